@@ -35,6 +35,7 @@ use sp_std::{result, cmp::min, prelude::*, collections::btree_map::BTreeMap};
 use sp_runtime::{RuntimeDebug, Perbill, traits::{Saturating, Zero}};
 use sp_inherents::{InherentIdentifier, InherentData, ProvideInherent, IsFatalError};
 use sp_consensus_pow::POW_ENGINE_ID;
+use kulupu_primitives::digest::{PreDigestFormat, split_pre_digest};
 #[cfg(feature = "std")]
 use sp_inherents::ProvideInherentData;
 use frame_support::{
@@ -89,6 +90,9 @@ pub trait Trait: frame_system::Trait {
 	type DonationDestination: Get<Self::AccountId>;
 	/// Generate reward locks.
 	type GenerateRewardLocks: GenerateRewardLocks<Self>;
+	/// Layout of the author pre-digest. Must match the one the node verifies
+	/// seals against, which follows the algorithm identifier of the runtime.
+	type PreDigestFormat: Get<PreDigestFormat>;
 	/// Weights for this pallet.
 	type WeightInfo: WeightInfo;
 }
@@ -157,8 +161,9 @@ decl_module! {
 				.logs
 				.iter()
 				.filter_map(|s| s.as_pre_runtime())
-				.filter_map(|(id, mut data)| if id == POW_ENGINE_ID {
-					T::AccountId::decode(&mut data).ok()
+				.filter_map(|(id, data)| if id == POW_ENGINE_ID {
+					let (mut author, _) = split_pre_digest(T::PreDigestFormat::get(), data)?;
+					T::AccountId::decode(&mut author).ok()
 				} else {
 					None
 				})
@@ -23,13 +23,15 @@ use crate::{Module, Trait, GenesisConfig};
 use sp_core::H256;
 use codec::Encode;
 use frame_support::{
-	impl_outer_origin, impl_outer_event, parameter_types, weights::Weight, traits::OnInitialize
+	impl_outer_origin, impl_outer_event, parameter_types, weights::Weight, traits::{Get, OnInitialize},
 };
 use sp_runtime::{
 	traits::{BlakeTwo256, IdentityLookup}, testing::{Digest, DigestItem, Header}, Perbill,
 };
 use frame_system::{self as system, InitKind};
 use sp_std::collections::btree_map::BTreeMap;
+use kulupu_primitives::digest::PreDigestFormat;
+use std::cell::Cell;
 
 impl_outer_origin! {
 	pub enum Origin for Test {}
@@ -142,11 +144,31 @@ parameter_types! {
 	pub DonationDestination: u64 = 255;
 }
 
+thread_local! {
+	static PRE_DIGEST_FORMAT: Cell<PreDigestFormat> = Cell::new(PreDigestFormat::Raw);
+}
+
+/// Pre-digest format of the mock runtime, raw unless set otherwise.
+pub struct MockPreDigestFormat;
+
+impl MockPreDigestFormat {
+	pub fn set(format: PreDigestFormat) {
+		PRE_DIGEST_FORMAT.with(|f| f.set(format));
+	}
+}
+
+impl Get<PreDigestFormat> for MockPreDigestFormat {
+	fn get() -> PreDigestFormat {
+		PRE_DIGEST_FORMAT.with(|f| f.get())
+	}
+}
+
 impl Trait for Test {
 	type Event = Event;
 	type Currency = Balances;
 	type DonationDestination = DonationDestination;
 	type GenerateRewardLocks = GenerateRewardLocks;
+	type PreDigestFormat = MockPreDigestFormat;
 	type WeightInfo = ();
 }
 
@@ -98,6 +98,46 @@ fn set_author_works() {
 	});
 }
 
+/// Start the next block with `pre_digest` as the author pre-digest.
+fn initialize_with_pre_digest(pre_digest: Vec<u8>) {
+	let current_block = System::block_number() + 1;
+	let parent_hash = System::parent_hash();
+	System::initialize(
+		&current_block,
+		&parent_hash,
+		&Default::default(),
+		&Digest { logs: vec![DigestItem::PreRuntime(sp_consensus_pow::POW_ENGINE_ID, pre_digest)] },
+		InitKind::Full
+	);
+	System::set_block_number(current_block);
+	Rewards::on_initialize(System::block_number());
+}
+
+#[test]
+fn author_follows_pre_digest_format() {
+	use kulupu_primitives::digest::{PreDigestFormat, AUTHOR_DIGEST_V1, AUTHOR_DIGEST_V2};
+
+	new_test_ext(1).execute_with(|| {
+		let mut tagged = vec![AUTHOR_DIGEST_V1];
+		2u64.encode_to(&mut tagged);
+		let mut committed = vec![AUTHOR_DIGEST_V2];
+		3u64.encode_to(&mut committed);
+		committed.extend_from_slice(&[9; 32]);
+
+		// Raw pre-digests are decoded as they are, whatever they start with.
+		initialize_with_pre_digest(tagged.clone());
+		assert_eq!(Author::<Test>::get(), Some(u64::from_le_bytes([1, 2, 0, 0, 0, 0, 0, 0])));
+
+		MockPreDigestFormat::set(PreDigestFormat::Versioned);
+		initialize_with_pre_digest(tagged);
+		assert_eq!(Author::<Test>::get(), Some(2));
+		initialize_with_pre_digest(committed);
+		assert_eq!(Author::<Test>::get(), Some(3));
+
+		MockPreDigestFormat::set(PreDigestFormat::Raw);
+	});
+}
+
 #[test]
 fn reward_payment_works() {
 	new_test_ext(1).execute_with(|| {
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2019-2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Verification cost of light and full machines over the same inputs, and the
+//! memory each one holds.
+
+use std::sync::Arc;
+use std::time::Duration;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+use randomx::{Config, FullCache, LightCache, FullVM, LightVM};
+
+const KEY: &[u8] = &[0x42; 32];
+
+pub fn light_vs_full(c: &mut Criterion) {
+	let config = Config::new();
+	let inputs = (0..8u8).map(|i| [i; 96].to_vec()).collect::<Vec<_>>();
+
+	let light_cache = Arc::new(LightCache::new(KEY, &config));
+	let full_cache = Arc::new(FullCache::new(KEY, &config));
+	println!(
+		"Memory: light {} MiB, full {} MiB",
+		light_cache.memory_bytes() / (1024 * 1024),
+		full_cache.memory_bytes() / (1024 * 1024),
+	);
+
+	let mut light_vm = LightVM::new(light_cache, &config);
+	let mut full_vm = FullVM::new(full_cache, &config);
+
+	let expected = inputs.iter().map(|input| light_vm.calculate(input)).collect::<Vec<_>>();
+
+	let mut group = c.benchmark_group("verify");
+	group.bench_function(BenchmarkId::new("light", inputs.len()), |b| b.iter(|| {
+		for (input, hash) in inputs.iter().zip(&expected) {
+			assert_eq!(light_vm.calculate(input), *hash);
+		}
+	}));
+	group.bench_function(BenchmarkId::new("full", inputs.len()), |b| b.iter(|| {
+		for (input, hash) in inputs.iter().zip(&expected) {
+			assert_eq!(full_vm.calculate(input), *hash);
+		}
+	}));
+	group.finish();
+}
+
+criterion_group! {
+	name = benches;
+	// Few short samples, so that the benchmark also fits in CI.
+	config = Criterion::default()
+		.sample_size(10)
+		.warm_up_time(Duration::from_millis(500))
+		.measurement_time(Duration::from_secs(2));
+	targets = light_vs_full
+}
+criterion_main!(benches);
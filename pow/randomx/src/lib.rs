@@ -19,11 +19,76 @@
 use std::sync::Arc;
 use std::marker::PhantomData;
 
+#[cfg(unix)]
+pub mod server;
+
 pub const HASH_SIZE: usize = sys::RANDOMX_HASH_SIZE as usize;
 
+/// Size of the RandomX cache used in light mode, in bytes. This is
+/// `RANDOMX_ARGON_MEMORY` KiB of the bundled configuration.
+pub const CACHE_SIZE_BYTES: usize = 262144 * 1024;
+
+/// Size of the RandomX dataset used in full mode, in bytes. This is
+/// `RANDOMX_DATASET_BASE_SIZE + RANDOMX_DATASET_EXTRA_SIZE` of the bundled
+/// configuration.
+pub const DATASET_SIZE_BYTES: usize = 2147483648 + 33554368;
+
+/// Size of a dataset item, in bytes. This is `RANDOMX_DATASET_ITEM_SIZE`.
+pub const DATASET_ITEM_SIZE_BYTES: usize = 64;
+
+/// Key of the pinned reference hash of the bundled configuration.
+pub const REFERENCE_KEY: &[u8] = b"RandomX example key";
+/// Input of the pinned reference hash of the bundled configuration.
+pub const REFERENCE_INPUT: &[u8] = b"RandomX example input";
+/// Hash of [`REFERENCE_INPUT`] under [`REFERENCE_KEY`], without network salt.
+/// A library computing another hash is miscompiled or misconfigured.
+pub const REFERENCE_HASH: [u8; HASH_SIZE] = [
+	69, 167, 169, 170, 66, 104, 77, 15, 73, 13, 233, 6, 227, 92, 143, 244,
+	95, 153, 4, 251, 223, 169, 78, 126, 236, 216, 174, 147, 1, 213, 223, 59,
+];
+
+/// Number of items of the dataset.
+pub fn dataset_item_count() -> u64 {
+	unsafe { sys::randomx_dataset_item_count() as u64 }
+}
+
+/// Configuration of the linked RandomX library.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LibraryInfo {
+	/// Number of items in the dataset.
+	pub dataset_item_count: usize,
+	/// Size of a single dataset item, in bytes.
+	pub dataset_item_size: usize,
+	/// Size of the dataset, in bytes.
+	pub dataset_size_bytes: usize,
+	/// Size of the cache, in bytes.
+	pub cache_size_bytes: usize,
+}
+
+/// Query the configuration of the linked RandomX library.
+pub fn library_info() -> LibraryInfo {
+	let dataset_item_count = unsafe { sys::randomx_dataset_item_count() } as usize;
+	let dataset_item_size = sys::RANDOMX_DATASET_ITEM_SIZE as usize;
+
+	LibraryInfo {
+		dataset_item_count,
+		dataset_item_size,
+		dataset_size_bytes: dataset_item_count * dataset_item_size,
+		cache_size_bytes: sys::RANDOMX_ARGON_MEMORY as usize * 1024,
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Config {
 	pub large_pages: bool,
 	pub secure: bool,
+	/// Salt separating the work of a network from the one of other networks.
+	///
+	/// The bundled RandomX fixes its argon2 salt at compile time, so this salt
+	/// is mixed into the cache key instead, which changes every dataset and
+	/// work hash just the same. This is consensus critical, and must be the
+	/// same on all nodes of a network. Kulupu uses none.
+	pub network_salt: Option<&'static [u8]>,
 }
 
 impl Config {
@@ -31,6 +96,20 @@ impl Config {
 		Config {
 			large_pages: false,
 			secure: false,
+			network_salt: None,
+		}
+	}
+
+	/// Key the RandomX cache is initialized with for `key`.
+	pub fn cache_key(&self, key: &[u8]) -> Vec<u8> {
+		match self.network_salt {
+			Some(salt) => {
+				let mut salted = (salt.len() as u64).to_le_bytes().to_vec();
+				salted.extend_from_slice(salt);
+				salted.extend_from_slice(key);
+				salted
+			},
+			None => key.to_vec(),
 		}
 	}
 }
@@ -102,6 +181,7 @@ impl<M: WithCacheMode> Cache<M> {
 	pub fn new(key: &[u8], config: &Config) -> Self {
 		let flags = M::randomx_flags(config);
 
+		let key = config.cache_key(key);
 		let cache_ptr = unsafe {
 			let ptr = sys::randomx_alloc_cache(flags);
 			sys::randomx_init_cache(
@@ -126,6 +206,144 @@ impl<M: WithCacheMode> Cache<M> {
 
 		Self { cache_ptr, dataset_ptr, _marker: PhantomData }
 	}
+
+	/// Hash of `input` with a new light machine on this cache, not using any
+	/// dataset. Shares no state with existing machines, nor the dataset, so
+	/// that they can be checked against it.
+	pub fn reference_hash(&self, input: &[u8], config: &Config) -> [u8; HASH_SIZE] {
+		let flags = WithLightCacheMode::randomx_flags(config);
+		let ret = [0u8; HASH_SIZE];
+
+		unsafe {
+			let vm = sys::randomx_create_vm(flags, self.cache_ptr, std::ptr::null_mut());
+			sys::randomx_calculate_hash(
+				vm,
+				input.as_ptr() as *const std::ffi::c_void,
+				input.len() as u64,
+				ret.as_ptr() as *mut std::ffi::c_void,
+			);
+			sys::randomx_destroy_vm(vm);
+		}
+
+		ret
+	}
+
+	/// Memory allocated by this cache and its dataset, in bytes.
+	pub fn memory_bytes(&self) -> usize {
+		if M::has_dataset() {
+			CACHE_SIZE_BYTES + DATASET_SIZE_BYTES
+		} else {
+			CACHE_SIZE_BYTES
+		}
+	}
+}
+
+impl Cache<WithFullCacheMode> {
+	/// Concatenation of `count` pages of `page_size` bytes, evenly spaced over
+	/// the dataset. Cheap to compare, unlike the whole dataset.
+	pub fn dataset_samples(&self, count: usize, page_size: usize) -> Vec<u8> {
+		let dataset = self.dataset_memory();
+		let page_size = page_size.min(dataset.len());
+		let stride = if count > 1 { (dataset.len() - page_size) / (count - 1) } else { 0 };
+
+		let mut samples = Vec::with_capacity(count * page_size);
+		for i in 0..count {
+			let start = i * stride;
+			samples.extend_from_slice(&dataset[start..start + page_size]);
+		}
+		samples
+	}
+
+	/// Allocate a full cache for the key, leaving the dataset uninitialized.
+	///
+	/// # Safety
+	///
+	/// All items of the dataset must be initialized, with
+	/// [`Self::init_dataset_items`] or a [`DatasetWriter`], before the cache is
+	/// used by a machine or its dataset is read.
+	pub unsafe fn new_uninit(key: &[u8], config: &Config) -> Self {
+		let flags = WithFullCacheMode::randomx_flags(config);
+
+		let key = config.cache_key(key);
+		let cache_ptr = sys::randomx_alloc_cache(flags);
+		sys::randomx_init_cache(
+			cache_ptr,
+			key.as_ptr() as *const std::ffi::c_void,
+			key.len() as u64
+		);
+		let dataset_ptr = sys::randomx_alloc_dataset(flags);
+
+		Self { cache_ptr, dataset_ptr: Some(dataset_ptr), _marker: PhantomData }
+	}
+
+	/// Initialize `count` dataset items from `start`.
+	pub fn init_dataset_items(&mut self, start: u64, count: u64) {
+		unsafe {
+			sys::randomx_init_dataset(
+				self.dataset_ptr.expect("Full cache always has a dataset; qed"),
+				self.cache_ptr,
+				start,
+				count,
+			);
+		}
+	}
+
+	/// Writer initializing disjoint ranges of the dataset from several threads.
+	///
+	/// # Safety
+	///
+	/// The writer must not be used after the cache is dropped, and no machine
+	/// may use the dataset, nor may it be read, while ranges are initialized
+	/// through the writer.
+	pub unsafe fn dataset_writer(&mut self) -> DatasetWriter {
+		DatasetWriter {
+			cache_ptr: self.cache_ptr,
+			dataset_ptr: self.dataset_ptr.expect("Full cache always has a dataset; qed"),
+		}
+	}
+
+	fn dataset_memory(&self) -> &[u8] {
+		unsafe {
+			std::slice::from_raw_parts(
+				sys::randomx_get_dataset_memory(
+					self.dataset_ptr.expect("Full cache always has a dataset; qed")
+				) as *const u8,
+				DATASET_SIZE_BYTES,
+			)
+		}
+	}
+
+	fn dataset_memory_mut(&mut self) -> &mut [u8] {
+		unsafe {
+			std::slice::from_raw_parts_mut(
+				sys::randomx_get_dataset_memory(
+					self.dataset_ptr.expect("Full cache always has a dataset; qed")
+				) as *mut u8,
+				DATASET_SIZE_BYTES,
+			)
+		}
+	}
+}
+
+/// Initializes the dataset of a full cache from several threads. See
+/// [`FullCache::dataset_writer`].
+pub struct DatasetWriter {
+	cache_ptr: *mut sys::randomx_cache,
+	dataset_ptr: *mut sys::randomx_dataset,
+}
+
+unsafe impl Send for DatasetWriter { }
+unsafe impl Sync for DatasetWriter { }
+
+impl DatasetWriter {
+	/// Initialize `count` dataset items from `start`.
+	///
+	/// # Safety
+	///
+	/// Ranges initialized concurrently must not overlap.
+	pub unsafe fn init_dataset_items(&self, start: u64, count: u64) {
+		sys::randomx_init_dataset(self.dataset_ptr, self.cache_ptr, start, count);
+	}
 }
 
 impl<M: WithCacheMode> Drop for Cache<M> {
@@ -241,20 +459,45 @@ impl<'a, M: WithCacheMode> Next<'a, M> {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn size_constants_match_library() {
+		let info = library_info();
+		assert_eq!(DATASET_SIZE_BYTES, info.dataset_size_bytes);
+		assert_eq!(DATASET_ITEM_SIZE_BYTES, info.dataset_item_size);
+		assert_eq!(CACHE_SIZE_BYTES, info.cache_size_bytes);
+	}
+
 	#[test]
 	fn should_create_light_vm() {
 		let cache = Arc::new(LightCache::new(&b"RandomX example key"[..], &Default::default()));
+		assert_eq!(cache.reference_hash(REFERENCE_INPUT, &Default::default()), REFERENCE_HASH);
 		let mut vm = LightVM::new(cache, &Default::default());
 		let hash = vm.calculate(&b"RandomX example input"[..]);
 		assert_eq!(hash, [69, 167, 169, 170, 66, 104, 77, 15, 73, 13, 233, 6, 227, 92, 143, 244, 95, 153, 4, 251, 223, 169, 78, 126, 236, 216, 174, 147, 1, 213, 223, 59]);
 	}
 
+	#[test]
+	fn network_salt_changes_work() {
+		let hash = |network_salt: Option<&'static [u8]>| {
+			let config = Config { network_salt, ..Config::new() };
+			let cache = Arc::new(LightCache::new(&b"RandomX example key"[..], &config));
+			LightVM::new(cache, &config).calculate(&b"RandomX example input"[..])
+		};
+
+		let unsalted = hash(None);
+		let salted = hash(Some(&b"forked network"[..]));
+		assert_ne!(salted, unsalted);
+		assert_ne!(hash(Some(&b"other network"[..])), salted);
+		assert_eq!(hash(Some(&b"forked network"[..])), salted);
+	}
+
 	#[test]
 	fn should_work_with_full_vm() {
 		let light_cache = Arc::new(LightCache::new(&b"RandomX example key"[..], &Default::default()));
 		let mut light_vm = LightVM::new(light_cache, &Default::default());
 		let hash = light_vm.calculate(&b"RandomX example input"[..]);
 		let full_cache = Arc::new(FullCache::new(&b"RandomX example key"[..], &Default::default()));
+		assert_eq!(full_cache.reference_hash(REFERENCE_INPUT, &Default::default()), REFERENCE_HASH);
 		let mut full_vm = FullVM::new(full_cache, &Default::default());
 		let full_hash = full_vm.calculate(&b"RandomX example input"[..]);
 		assert_eq!(hash, full_hash);
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2019-2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Serving full datasets to co-located processes over a Unix socket, so that
+//! only one process pays the generation cost.
+//!
+//! A client sends the key length as a little-endian `u64` followed by the key,
+//! and the server replies with the raw dataset. Clients check samples of the
+//! dataset against the key before using it.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{Config, FullCache, DATASET_ITEM_SIZE_BYTES};
+
+/// Largest key a client may request.
+const MAX_KEY_LEN: u64 = 256;
+
+/// Clients served at once. Further clients are turned away.
+pub const MAX_CONNECTIONS: usize = 8;
+
+/// Timeout of every read and write of the server, so that a stalled client
+/// does not hold a connection forever.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Timeout of every read of a client, long enough for the server to generate
+/// the dataset on first request.
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Dataset items a client recomputes to check a fetched dataset, about 3% of
+/// them. This takes seconds, against minutes to generate the whole dataset. A
+/// dataset with more than one item in 100000 wrong passes with a probability
+/// below e^-10.
+pub const VERIFIED_ITEMS: usize = 1 << 20;
+
+/// Datasets kept by the server, each holding over 2 GB. Requesting another key
+/// evicts the least recently requested one.
+pub const MAX_DATASETS: usize = 2;
+
+/// Dataset of a key, generated on first request.
+type DatasetSlot = Arc<Mutex<Option<Arc<FullCache>>>>;
+
+/// Server holding full datasets, generated on first request.
+pub struct DatasetServer {
+	listener: UnixListener,
+	config: Config,
+	/// Most recently requested first.
+	datasets: Mutex<VecDeque<(Vec<u8>, DatasetSlot)>>,
+	connections: AtomicUsize,
+}
+
+impl DatasetServer {
+	/// Listen on the socket at `path`, replacing a stale one.
+	pub fn bind<P: AsRef<Path>>(path: P, config: Config) -> io::Result<Self> {
+		let _ = fs::remove_file(&path);
+
+		Ok(Self {
+			listener: UnixListener::bind(path)?,
+			config,
+			datasets: Mutex::new(VecDeque::new()),
+			connections: AtomicUsize::new(0),
+		})
+	}
+
+	/// Dataset for `key`, generating it if it is not kept. Only requests for
+	/// the same key wait for the generation. Clients being served an evicted
+	/// dataset hold it until they are done.
+	pub fn dataset(&self, key: &[u8]) -> Arc<FullCache> {
+		let slot = touch(&mut self.datasets.lock().expect("Mutex poisioned"), key, MAX_DATASETS);
+
+		let mut dataset = slot.lock().expect("Mutex poisioned");
+		dataset.get_or_insert_with(|| Arc::new(FullCache::new(key, &self.config))).clone()
+	}
+
+	/// Serve clients until accepting a connection fails, each on its own
+	/// thread. Failures of single clients are ignored.
+	pub fn serve(self: Arc<Self>) -> io::Result<()> {
+		for stream in self.listener.incoming() {
+			let stream = stream?;
+
+			if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+				self.connections.fetch_sub(1, Ordering::SeqCst);
+				continue
+			}
+
+			let server = self.clone();
+			std::thread::spawn(move || {
+				let _ = server.handle(stream);
+				server.connections.fetch_sub(1, Ordering::SeqCst);
+			});
+		}
+
+		Ok(())
+	}
+
+	/// Accept and serve a single client.
+	pub fn serve_one(&self) -> io::Result<()> {
+		let (stream, _) = self.listener.accept()?;
+		self.handle(stream)
+	}
+
+	fn handle(&self, mut stream: UnixStream) -> io::Result<()> {
+		stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+		stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+		let mut len = [0u8; 8];
+		stream.read_exact(&mut len)?;
+		let len = u64::from_le_bytes(len);
+		if len > MAX_KEY_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Key too long"))
+		}
+
+		let mut key = vec![0u8; len as usize];
+		stream.read_exact(&mut key)?;
+
+		let dataset = self.dataset(&key);
+		stream.write_all(dataset.dataset_memory())
+	}
+}
+
+/// Entry of `key`, created if missing, moved to the front of `entries`. Entries
+/// beyond `capacity` are evicted from the back.
+fn touch<T: Clone + Default>(entries: &mut VecDeque<(Vec<u8>, T)>, key: &[u8], capacity: usize) -> T {
+	let entry = match entries.iter().position(|(entry_key, _)| &entry_key[..] == key) {
+		Some(index) => entries.remove(index).expect("Index is in bounds; qed").1,
+		None => T::default(),
+	};
+
+	entries.push_front((key.to_vec(), entry.clone()));
+	entries.truncate(capacity);
+	entry
+}
+
+/// Fetch the full dataset for `key` from the server listening at `path`.
+pub fn fetch_dataset<P: AsRef<Path>>(path: P, key: &[u8], config: &Config) -> io::Result<FullCache> {
+	let mut stream = UnixStream::connect(path)?;
+	stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+	stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+	stream.write_all(&(key.len() as u64).to_le_bytes())?;
+	stream.write_all(key)?;
+
+	// The dataset is received whole before the cache is returned.
+	let mut cache = unsafe { FullCache::new_uninit(key, config) };
+	stream.read_exact(cache.dataset_memory_mut())?;
+
+	if !verify_items(&mut cache, VERIFIED_ITEMS) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "Fetched dataset does not match the key"))
+	}
+
+	Ok(cache)
+}
+
+/// Recompute `count` items of the dataset at unpredictable positions, and
+/// compare them with the received ones. See [`VERIFIED_ITEMS`] for the items
+/// this catches.
+fn verify_items(cache: &mut FullCache, count: usize) -> bool {
+	let items = crate::dataset_item_count();
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+		.map(|elapsed| elapsed.as_nanos() as u64)
+		.unwrap_or(0);
+	let mut state = (nanos ^ ((std::process::id() as u64) << 32)) | 1;
+
+	(0..count).all(|_| {
+		// Xorshift, seeded from the clock, so that a server cannot predict
+		// the checked items.
+		state ^= state << 13;
+		state ^= state >> 7;
+		state ^= state << 17;
+
+		let index = state % items;
+		let offset = index as usize * DATASET_ITEM_SIZE_BYTES;
+		let received = cache.dataset_memory()[offset..offset + DATASET_ITEM_SIZE_BYTES].to_vec();
+
+		cache.init_dataset_items(index, 1);
+		cache.dataset_memory()[offset..offset + DATASET_ITEM_SIZE_BYTES] == received[..]
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[cfg(feature = "dataset-server-tests")]
+	use crate::{FullVM, LightCache, LightVM};
+
+	#[test]
+	fn least_recently_requested_dataset_is_evicted() {
+		let mut entries = VecDeque::new();
+		let first = touch::<Arc<u8>>(&mut entries, b"first", 2);
+		touch::<Arc<u8>>(&mut entries, b"second", 2);
+
+		// Requesting the first key again keeps its entry, and makes the second
+		// key the least recently requested.
+		assert!(Arc::ptr_eq(&touch(&mut entries, b"first", 2), &first));
+		touch::<Arc<u8>>(&mut entries, b"third", 2);
+
+		let keys = entries.iter().map(|(key, _)| &key[..]).collect::<Vec<_>>();
+		assert_eq!(keys, vec![&b"third"[..], &b"first"[..]]);
+	}
+
+	// Tests below serve full datasets, each holding over 2 GB.
+
+	#[cfg(feature = "dataset-server-tests")]
+	#[test]
+	fn client_receives_served_dataset() {
+		let path = std::env::temp_dir().join(format!("kulupu-randomx-server-{}", std::process::id()));
+		let key = &b"RandomX example key"[..];
+
+		let server = Arc::new(DatasetServer::bind(&path, Config::new()).unwrap());
+		let serving = {
+			let server = server.clone();
+			std::thread::spawn(move || server.serve_one())
+		};
+
+		let fetched = Arc::new(fetch_dataset(&path, key, &Config::new()).unwrap());
+		serving.join().unwrap().unwrap();
+
+		let mut full_vm = FullVM::new(fetched, &Config::new());
+		let mut light_vm = LightVM::new(Arc::new(LightCache::new(key, &Config::new())), &Config::new());
+		assert_eq!(
+			full_vm.calculate(&b"RandomX example input"[..]),
+			light_vm.calculate(&b"RandomX example input"[..]),
+		);
+
+		let _ = fs::remove_file(&path);
+	}
+
+	#[cfg(feature = "dataset-server-tests")]
+	#[test]
+	fn stalled_client_does_not_block_others() {
+		let path = std::env::temp_dir().join(format!("kulupu-randomx-stalled-{}", std::process::id()));
+		let key = &b"RandomX example key"[..];
+
+		let server = Arc::new(DatasetServer::bind(&path, Config::new()).unwrap());
+		std::thread::spawn({
+			let server = server.clone();
+			move || server.serve()
+		});
+
+		// Connects, and never sends its request.
+		let _stalled = UnixStream::connect(&path).unwrap();
+		assert!(fetch_dataset(&path, key, &Config::new()).is_ok());
+
+		let _ = fs::remove_file(&path);
+	}
+
+	#[cfg(feature = "dataset-server-tests")]
+	#[test]
+	fn dataset_not_matching_the_key_is_refused() {
+		let path = std::env::temp_dir().join(format!("kulupu-randomx-forged-{}", std::process::id()));
+		let _ = fs::remove_file(&path);
+		let listener = UnixListener::bind(&path).unwrap();
+
+		let forging = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = [0u8; 8 + 19];
+			stream.read_exact(&mut request).unwrap();
+
+			let zeros = vec![0u8; 1 << 20];
+			let mut remaining = crate::DATASET_SIZE_BYTES;
+			while remaining > 0 {
+				let len = remaining.min(zeros.len());
+				stream.write_all(&zeros[..len]).unwrap();
+				remaining -= len;
+			}
+		});
+
+		let fetched = fetch_dataset(&path, &b"RandomX example key"[..], &Config::new());
+		forging.join().unwrap();
+		assert_eq!(fetched.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+		let _ = fs::remove_file(&path);
+	}
+}
@@ -37,6 +37,8 @@ fn main() {
 		// The input header we would like to generate
 		// bindings for.
 		.header("randomx/src/randomx.h")
+		// The configuration the library is compiled with, for its sizes.
+		.header("randomx/src/configuration.h")
 		// Workaround for https://github.com/servo/rust-bindgen/issues/550
 		.blacklist_type("max_align_t")
 		// Finish the builder and generate the bindings.
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Pinning of mining workers to CPU cores.
+
+use std::str::FromStr;
+use log::*;
+
+/// Cores mining workers are pinned to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WorkerAffinity {
+	/// Leave scheduling of workers to the operating system.
+	None,
+	/// Pin workers to one logical core per physical core.
+	Auto,
+	/// Pin worker `i` to the `i`-th core of the list, wrapping around.
+	Cores(Vec<usize>),
+}
+
+impl Default for WorkerAffinity {
+	fn default() -> Self {
+		WorkerAffinity::None
+	}
+}
+
+impl FromStr for WorkerAffinity {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"none" => Ok(WorkerAffinity::None),
+			"auto" => Ok(WorkerAffinity::Auto),
+			_ => s.split(',')
+				.map(|core| core.trim().parse::<usize>()
+					.map_err(|_| format!("Invalid core index: {}", core)))
+				.collect::<Result<Vec<_>, _>>()
+				.map(WorkerAffinity::Cores),
+		}
+	}
+}
+
+impl WorkerAffinity {
+	/// Core of each of `threads` workers, or `None` for workers left unpinned.
+	pub fn assign(&self, threads: usize) -> Vec<Option<usize>> {
+		let cores = match self {
+			WorkerAffinity::None => Vec::new(),
+			WorkerAffinity::Auto => physical_cores(),
+			WorkerAffinity::Cores(cores) => cores.clone(),
+		};
+
+		(0..threads)
+			.map(|i| if cores.is_empty() { None } else { Some(cores[i % cores.len()]) })
+			.collect()
+	}
+}
+
+/// One logical core per physical core. Hyperthread siblings are usually
+/// numbered after all the physical cores, so this takes the first ones.
+fn physical_cores() -> Vec<usize> {
+	let logical = match core_affinity::get_core_ids() {
+		Some(logical) => logical,
+		None => return Vec::new(),
+	};
+
+	logical.into_iter()
+		.map(|core| core.id)
+		.take(num_cpus::get_physical())
+		.collect()
+}
+
+/// Pin the calling thread to `core`. Returns `false`, after a warning, if the
+/// platform does not support it or the core does not exist.
+pub fn pin_current_thread(core: usize) -> bool {
+	let cores = match core_affinity::get_core_ids() {
+		Some(cores) => cores,
+		None => {
+			warn!(target: crate::LOG_TARGET, "Thread affinity is not supported on this platform");
+			return false
+		},
+	};
+
+	match cores.into_iter().find(|c| c.id == core) {
+		Some(core) => {
+			core_affinity::set_for_current(core);
+			true
+		},
+		None => {
+			warn!(target: crate::LOG_TARGET, "Core {} not found, mining worker left unpinned", core);
+			false
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn affinity_parses_and_assigns_cores() {
+		assert_eq!("none".parse::<WorkerAffinity>(), Ok(WorkerAffinity::None));
+		assert_eq!("auto".parse::<WorkerAffinity>(), Ok(WorkerAffinity::Auto));
+		assert_eq!("0, 2".parse::<WorkerAffinity>(), Ok(WorkerAffinity::Cores(vec![0, 2])));
+		assert!("0,x".parse::<WorkerAffinity>().is_err());
+
+		assert_eq!(WorkerAffinity::None.assign(2), vec![None, None]);
+		assert_eq!(
+			WorkerAffinity::Cores(vec![0, 2]).assign(3),
+			vec![Some(0), Some(2), Some(0)],
+		);
+	}
+
+	#[test]
+	#[cfg(any(target_os = "linux", target_os = "windows"))]
+	fn workers_are_pinned_to_requested_cores() {
+		let cores = WorkerAffinity::Auto.assign(2);
+		assert!(cores.iter().all(|core| core.is_some()));
+
+		let handles = cores.into_iter().map(|core| {
+			std::thread::spawn(move || pin_current_thread(core.expect("Checked above; qed")))
+		}).collect::<Vec<_>>();
+
+		for handle in handles {
+			assert!(handle.join().unwrap());
+		}
+	}
+}
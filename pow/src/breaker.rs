@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Circuit breaker suspending mining after repeated compute failures.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use log::*;
+
+/// Default number of consecutive failures tripping the breaker.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+/// Default window within which consecutive failures are counted.
+pub const DEFAULT_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Default time the breaker stays open before compute is attempted again.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Result of a call through the breaker.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BreakerError<E> {
+	/// The breaker is open, and the call was not attempted.
+	Open,
+	/// The call was attempted and failed.
+	Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for BreakerError<E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BreakerError::Open =>
+				write!(f, "Mining suspended after repeated compute failures"),
+			BreakerError::Failed(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+struct State {
+	failures: u32,
+	first_failure: Option<Instant>,
+	open_until: Option<Instant>,
+}
+
+/// Stops calls after `threshold` consecutive failures within `window`, until
+/// `cooldown` elapsed.
+pub struct CircuitBreaker {
+	threshold: u32,
+	window: Duration,
+	cooldown: Duration,
+	state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+	pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+		Self {
+			threshold: threshold.max(1),
+			window,
+			cooldown,
+			state: Mutex::new(State { failures: 0, first_failure: None, open_until: None }),
+		}
+	}
+
+	/// Whether calls are currently refused.
+	pub fn is_open(&self, now: Instant) -> bool {
+		self.state.lock().open_until.map(|until| now < until).unwrap_or(false)
+	}
+
+	/// Run `f` unless the breaker is open, accounting for its result.
+	pub fn call<T, E, F: FnOnce() -> Result<T, E>>(
+		&self,
+		now: Instant,
+		f: F,
+	) -> Result<T, BreakerError<E>> {
+		{
+			let mut state = self.state.lock();
+			match state.open_until {
+				Some(until) if now < until => return Err(BreakerError::Open),
+				Some(_) => {
+					info!(target: crate::LOG_TARGET, "Compute cooldown elapsed, resuming mining");
+					state.open_until = None;
+					state.failures = 0;
+					state.first_failure = None;
+				},
+				None => (),
+			}
+		}
+
+		match f() {
+			Ok(value) => {
+				let mut state = self.state.lock();
+				state.failures = 0;
+				state.first_failure = None;
+				Ok(value)
+			},
+			Err(e) => {
+				self.record_failure(now);
+				Err(BreakerError::Failed(e))
+			},
+		}
+	}
+
+	fn record_failure(&self, now: Instant) {
+		let mut state = self.state.lock();
+
+		let in_window = state.first_failure
+			.map(|first| now.saturating_duration_since(first) <= self.window)
+			.unwrap_or(false);
+		if in_window {
+			state.failures += 1;
+		} else {
+			state.failures = 1;
+			state.first_failure = Some(now);
+		}
+
+		if state.failures >= self.threshold {
+			warn!(
+				target: crate::LOG_TARGET,
+				"{} consecutive compute failures, suspending mining for {:?}",
+				state.failures,
+				self.cooldown,
+			);
+			state.open_until = Some(now + self.cooldown);
+		}
+	}
+}
+
+impl Default for CircuitBreaker {
+	fn default() -> Self {
+		Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_FAILURE_WINDOW, DEFAULT_COOLDOWN)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn trips_after_threshold_and_recovers_after_cooldown() {
+		let breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(10));
+		let start = Instant::now();
+		let fail = || Err::<(), _>("Allocation failed");
+		let at = |secs: u64| start + Duration::from_secs(secs);
+
+		for secs in 0..3 {
+			assert_eq!(breaker.call(at(secs), fail), Err(BreakerError::Failed("Allocation failed")));
+		}
+		assert!(breaker.is_open(at(3)));
+
+		let mut attempted = false;
+		assert_eq!(breaker.call(at(5), || { attempted = true; Ok::<_, &str>(()) }), Err(BreakerError::Open));
+		assert!(!attempted);
+
+		assert_eq!(breaker.call(at(13), || Ok::<_, &str>(1)), Ok(1));
+		assert!(!breaker.is_open(at(13)));
+	}
+
+	#[test]
+	fn failures_outside_window_do_not_trip() {
+		let breaker = CircuitBreaker::new(2, Duration::from_secs(5), Duration::from_secs(10));
+		let start = Instant::now();
+		let fail = || Err::<(), _>(());
+
+		assert!(breaker.call(start, fail).is_err());
+		assert!(breaker.call(start + Duration::from_secs(6), fail).is_err());
+		assert!(!breaker.is_open(start + Duration::from_secs(7)));
+
+		assert!(breaker.call(start + Duration::from_secs(8), fail).is_err());
+		assert!(breaker.is_open(start + Duration::from_secs(9)));
+	}
+}
@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use sp_core::H256;
+use lru_cache::LruCache;
+
+/// Storage of the shared RandomX caches, by key hash. The eviction policy is
+/// up to the implementation.
+pub trait DatasetCache<V>: Send {
+	fn get_mut(&mut self, key_hash: &H256) -> Option<&mut V>;
+	fn insert(&mut self, key_hash: H256, value: V);
+	/// Evict the entry the policy would drop first.
+	fn remove_lru(&mut self) -> Option<(H256, V)>;
+	fn len(&self) -> usize;
+	fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	fn clear(&mut self);
+	fn set_capacity(&mut self, capacity: usize);
+	/// Entries, from the first to be evicted to the last.
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a H256, &'a V)> + 'a>;
+}
+
+/// Count based eviction of the least recently used caches. The default.
+impl<V: Send> DatasetCache<V> for LruCache<H256, V> {
+	fn get_mut(&mut self, key_hash: &H256) -> Option<&mut V> {
+		LruCache::get_mut(self, key_hash)
+	}
+
+	fn insert(&mut self, key_hash: H256, value: V) {
+		LruCache::insert(self, key_hash, value);
+	}
+
+	fn remove_lru(&mut self) -> Option<(H256, V)> {
+		LruCache::remove_lru(self)
+	}
+
+	fn len(&self) -> usize {
+		LruCache::len(self)
+	}
+
+	fn clear(&mut self) {
+		LruCache::clear(self)
+	}
+
+	fn set_capacity(&mut self, capacity: usize) {
+		LruCache::set_capacity(self, capacity)
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a H256, &'a V)> + 'a> {
+		Box::new(LruCache::iter(self))
+	}
+}
+
+/// Time based eviction of caches older than a maximum age, for nodes that
+/// would rather drop old epochs than bound their number. The capacity is still
+/// enforced as an upper bound, evicting the oldest caches first.
+pub struct TtlCache<V> {
+	ttl: Duration,
+	capacity: usize,
+	entries: VecDeque<(H256, Instant, V)>,
+}
+
+impl<V> TtlCache<V> {
+	pub fn new(ttl: Duration, capacity: usize) -> Self {
+		Self { ttl, capacity, entries: VecDeque::new() }
+	}
+
+	/// Drop entries inserted more than the maximum age before `now`.
+	pub fn purge_expired_at(&mut self, now: Instant) {
+		let ttl = self.ttl;
+		self.entries.retain(|(_, inserted, _)| now.saturating_duration_since(*inserted) < ttl);
+	}
+
+	fn purge_expired(&mut self) {
+		self.purge_expired_at(Instant::now())
+	}
+
+	fn enforce_capacity(&mut self) {
+		while self.entries.len() > self.capacity {
+			self.entries.pop_front();
+		}
+	}
+}
+
+impl<V: Send> DatasetCache<V> for TtlCache<V> {
+	fn get_mut(&mut self, key_hash: &H256) -> Option<&mut V> {
+		self.purge_expired();
+		self.entries.iter_mut()
+			.find(|(key, _, _)| key == key_hash)
+			.map(|(_, _, value)| value)
+	}
+
+	fn insert(&mut self, key_hash: H256, value: V) {
+		self.purge_expired();
+		self.entries.retain(|(key, _, _)| *key != key_hash);
+		self.entries.push_back((key_hash, Instant::now(), value));
+		self.enforce_capacity();
+	}
+
+	fn remove_lru(&mut self) -> Option<(H256, V)> {
+		self.entries.pop_front().map(|(key_hash, _, value)| (key_hash, value))
+	}
+
+	fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	fn clear(&mut self) {
+		self.entries.clear()
+	}
+
+	fn set_capacity(&mut self, capacity: usize) {
+		self.capacity = capacity;
+		self.enforce_capacity();
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a H256, &'a V)> + 'a> {
+		Box::new(self.entries.iter().map(|(key_hash, _, value)| (key_hash, value)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lru_cache_evicts_by_count() {
+		let mut cache: Box<dyn DatasetCache<u8>> = Box::new(LruCache::new(2));
+		for i in 0..3 {
+			cache.insert(H256::repeat_byte(i), i);
+		}
+
+		assert_eq!(cache.len(), 2);
+		assert!(cache.get_mut(&H256::repeat_byte(0)).is_none());
+		assert_eq!(cache.get_mut(&H256::repeat_byte(2)), Some(&mut 2));
+	}
+
+	#[test]
+	fn ttl_cache_expires_old_entries() {
+		let ttl = Duration::from_secs(3600);
+		let mut cache = TtlCache::new(ttl, 8);
+		let start = Instant::now();
+		cache.insert(H256::repeat_byte(1), 1u8);
+
+		cache.purge_expired_at(start);
+		assert_eq!(cache.get_mut(&H256::repeat_byte(1)), Some(&mut 1));
+
+		cache.purge_expired_at(start + ttl * 2);
+		assert_eq!(cache.len(), 0);
+
+		cache.set_capacity(1);
+		cache.insert(H256::repeat_byte(2), 2);
+		cache.insert(H256::repeat_byte(3), 3);
+		assert_eq!(cache.iter().map(|(key_hash, _)| *key_hash).collect::<Vec<_>>(), vec![H256::repeat_byte(3)]);
+	}
+}
@@ -16,34 +16,593 @@
 // You should have received a copy of the GNU General Public License
 // along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
 
+mod cache;
 mod v1;
 mod v2;
 
+pub use self::cache::{DatasetCache, TtlCache};
+
 pub use self::v1::{ComputeV1, SealV1};
-pub use self::v2::{ComputeV2, SealV2};
+pub use self::v2::{
+	ComputeV2, SealV2, ComputeInputDump, InvalidSignatureLength, SIGNATURE_LENGTH, SIGNING_DOMAIN_V2, SigningDomain,
+	parse_signature, randomx_input,
+};
 pub use randomx::Config;
+pub use kulupu_primitives::seal::Calculation;
 
-use log::info;
+use std::fmt;
+use log::{log, info, warn, error, Level};
 use codec::{Encode, Decode};
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
-use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
 use sp_core::H256;
 use lazy_static::lazy_static;
 use lru_cache::LruCache;
 use once_cell::sync::OnceCell;
 use kulupu_randomx as randomx;
-use kulupu_primitives::Difficulty;
+
+/// Key hash of the canonical RandomX reference computations, used by the
+/// collision and regression tests.
+pub const TEST_KEY_HASH: [u8; 32] = [
+	210, 164, 216, 149, 3, 68, 116, 1, 239, 110, 111, 48, 180, 102, 53, 180,
+	91, 84, 242, 90, 101, 12, 71, 70, 75, 83, 17, 249, 214, 253, 71, 89,
+];
+
+/// Log target of RandomX machine and cache logs.
+pub const LOG_TARGET: &str = "kulupu-randomx";
+
+/// Level of the log emitted whenever a cache is generated for a new key hash.
+static GENERATION_LOG_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Set the level of the log emitted whenever a cache is generated, so that it
+/// can be silenced where it is noisy. Defaults to `Info`.
+pub fn set_generation_log_level(level: Level) {
+	GENERATION_LOG_LEVEL.store(level as usize, Ordering::SeqCst);
+}
+
+fn generation_log_level() -> Level {
+	match GENERATION_LOG_LEVEL.load(Ordering::SeqCst) {
+		1 => Level::Error,
+		2 => Level::Warn,
+		3 => Level::Info,
+		4 => Level::Debug,
+		_ => Level::Trace,
+	}
+}
+
+/// Default number of full datasets kept in memory.
+pub const DEFAULT_FULL_CACHE_CAPACITY: usize = 2;
+/// Default number of light caches kept in memory.
+pub const DEFAULT_LIGHT_CACHE_CAPACITY: usize = 3;
+
+type SharedCaches<M> = Arc<Mutex<Box<dyn DatasetCache<Arc<randomx::Cache<M>>>>>>;
+
+lazy_static! {
+	static ref FULL_SHARED_CACHES: SharedCaches<randomx::WithFullCacheMode> =
+		Arc::new(Mutex::new(Box::new(LruCache::new(DEFAULT_FULL_CACHE_CAPACITY))));
+	static ref LIGHT_SHARED_CACHES: SharedCaches<randomx::WithLightCacheMode> =
+		Arc::new(Mutex::new(Box::new(LruCache::new(DEFAULT_LIGHT_CACHE_CAPACITY))));
+}
+
+/// Replace the storage of the shared caches, for example with a [`TtlCache`]
+/// to evict by age. Caches held by the previous storage are dropped. Defaults
+/// to least recently used eviction.
+pub fn set_dataset_caches(
+	full: Box<dyn DatasetCache<Arc<randomx::FullCache>>>,
+	light: Box<dyn DatasetCache<Arc<randomx::LightCache>>>,
+) {
+	*FULL_SHARED_CACHES.lock().expect("Mutex poisioned") = full;
+	*LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned") = light;
+}
+
+/// Set the number of full and light caches kept in memory, evicting the least
+/// recently used ones above the new capacity.
+pub(crate) fn set_cache_capacity(full: usize, light: usize) {
+	FULL_SHARED_CACHES.lock().expect("Mutex poisioned").set_capacity(full.max(1));
+	LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned").set_capacity(light.max(1));
+}
+
+/// Whether mining computes with light machines instead of full datasets.
+static LIGHT_MINING: AtomicBool = AtomicBool::new(false);
+
+/// Mine with light machines, which need no dataset but hash several times
+/// slower. Disabled by default.
+fn set_light_mining(enabled: bool) {
+	LIGHT_MINING.store(enabled, Ordering::SeqCst);
+}
+
+/// Whether mining computes with light machines, as selected by the memory
+/// budget of the [`CacheSettings`].
+pub fn light_mining() -> bool {
+	LIGHT_MINING.load(Ordering::SeqCst)
+}
+
+fn effective_mode(mode: ComputeMode) -> ComputeMode {
+	match mode {
+		ComputeMode::Mining if light_mining() => ComputeMode::Sync,
+		mode => mode,
+	}
+}
+
+/// Mode fitting `full_capacity` caches in a memory budget: full datasets if
+/// they all fit, light machines otherwise.
+pub fn select_mining_mode(max_memory_bytes: u64, full_capacity: usize) -> ComputeMode {
+	let full_bytes = expected_cache_memory(full_capacity.max(1), ComputeMode::Mining) as u64;
+
+	if max_memory_bytes >= full_bytes {
+		ComputeMode::Mining
+	} else {
+		ComputeMode::Sync
+	}
+}
+
+/// Source of the memory available on the system.
+pub trait MemoryReporter: Send + Sync {
+	/// Available memory in bytes, or `None` if unknown.
+	fn available_bytes(&self) -> Option<u64>;
+}
+
+/// Reports `MemAvailable` of `/proc/meminfo`. Unknown on other systems.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemMemory;
+
+impl MemoryReporter for SystemMemory {
+	fn available_bytes(&self) -> Option<u64> {
+		let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+		let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+		let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+		Some(kib.saturating_mul(1024))
+	}
+}
+
+struct MemoryPressure {
+	reporter: Box<dyn MemoryReporter>,
+	threshold_bytes: u64,
+}
+
+impl MemoryPressure {
+	/// Bytes to free for `needed` more bytes to fit above the threshold. Zero
+	/// if available memory is unknown.
+	fn shortfall(&self, needed: u64) -> u64 {
+		self.reporter.available_bytes()
+			.map(|available| self.threshold_bytes.saturating_add(needed).saturating_sub(available))
+			.unwrap_or(0)
+	}
+}
 
 lazy_static! {
-	static ref FULL_SHARED_CACHES: Arc<Mutex<LruCache<H256, Arc<randomx::FullCache>>>> =
-		Arc::new(Mutex::new(LruCache::new(2)));
-	static ref LIGHT_SHARED_CACHES: Arc<Mutex<LruCache<H256, Arc<randomx::LightCache>>>> =
-		Arc::new(Mutex::new(LruCache::new(3)));
+	static ref MEMORY_PRESSURE: Mutex<Option<MemoryPressure>> = Mutex::new(None);
+}
+
+/// Evict the least recently used caches, full datasets first, whenever a new
+/// cache would leave less than `threshold_bytes` of available memory, as
+/// reported by `reporter`.
+fn enable_memory_pressure_eviction<R: MemoryReporter + 'static>(
+	reporter: R,
+	threshold_bytes: u64,
+) {
+	*MEMORY_PRESSURE.lock().expect("Mutex poisioned") = Some(MemoryPressure {
+		reporter: Box::new(reporter),
+		threshold_bytes,
+	});
+}
+
+fn disable_memory_pressure_eviction() {
+	*MEMORY_PRESSURE.lock().expect("Mutex poisioned") = None;
+}
+
+/// Evict least recently used entries of `cache` until the memory they held,
+/// as given by `size`, covers `shortfall` bytes. Returns the bytes freed.
+fn evict_lru_until<V>(
+	cache: &mut dyn DatasetCache<V>,
+	shortfall: u64,
+	size: impl Fn(&V) -> u64,
+) -> u64 {
+	let mut freed = 0;
+	while freed < shortfall {
+		match cache.remove_lru() {
+			Some((_, value)) => freed += size(&value),
+			None => break,
+		}
+	}
+	freed
+}
+
+/// Make room for a new cache of `needed` bytes, evicting shared caches, full
+/// datasets first, while it would leave less available memory than the
+/// configured threshold. Nothing is evicted if evicting all of them would not
+/// be enough. Machines hold their cache, so the machines of all threads are
+/// invalidated along with any eviction. Returns whether the new cache fits,
+/// which it always does unless enabled.
+///
+/// Must be called without holding the shared caches.
+fn relieve_memory_pressure(needed: u64) -> bool {
+	let pressure = MEMORY_PRESSURE.lock().expect("Mutex poisioned");
+	let pressure = match pressure.as_ref() {
+		Some(pressure) => pressure,
+		None => return true,
+	};
+
+	// Memory is only read once: evicted caches are released as the machines
+	// holding them are, which a second reading would not see yet.
+	let shortfall = pressure.shortfall(needed);
+	if shortfall == 0 {
+		return true
+	}
+	// Caches are only dropped if that makes room.
+	if (cache_memory_bytes() as u64) < shortfall {
+		return false
+	}
+
+	let full_freed = evict_lru_until(
+		&mut **FULL_SHARED_CACHES.lock().expect("Mutex poisioned"),
+		shortfall,
+		|cache| cache.memory_bytes() as u64,
+	);
+	let freed = full_freed + evict_lru_until(
+		&mut **LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned"),
+		shortfall.saturating_sub(full_freed),
+		|cache| cache.memory_bytes() as u64,
+	);
+
+	if freed > 0 {
+		CACHE_GENERATION.fetch_add(1, Ordering::SeqCst);
+		warn!(
+			target: LOG_TARGET,
+			"Available memory below {} bytes, evicted {} bytes of RandomX caches",
+			pressure.threshold_bytes,
+			freed,
+		);
+	}
+
+	freed >= shortfall
+}
+
+/// Mode and generation of the caches of a machine flavour.
+trait CacheModeOf: randomx::WithCacheMode + Sized {
+	const MODE: ComputeMode;
+
+	fn generate(key_hash: &H256) -> Arc<randomx::Cache<Self>>;
+}
+
+impl CacheModeOf for randomx::WithFullCacheMode {
+	const MODE: ComputeMode = ComputeMode::Mining;
+
+	/// Full datasets are generated in parallel, on all cores.
+	fn generate(key_hash: &H256) -> Arc<randomx::FullCache> {
+		generate_full_cache(key_hash, num_cpus::get())
+	}
+}
+
+impl CacheModeOf for randomx::WithLightCacheMode {
+	const MODE: ComputeMode = ComputeMode::Sync;
+
+	fn generate(key_hash: &H256) -> Arc<randomx::LightCache> {
+		Arc::new(randomx::Cache::new(&key_hash[..], global_config()))
+	}
+}
+
+/// Generation of the shared caches. Bumped on every flush, so that thread-local
+/// machines built before the flush are discarded on their next use.
+static CACHE_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of hashes between two sanity checks of a machine. Zero disables them.
+static SANITY_CHECK_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Input re-hashed by the sanity check.
+const SANITY_CHECK_INPUT: &[u8] = b"kulupu-randomx sanity check";
+
+/// Enable periodic sanity checks of the RandomX machines, every `interval`
+/// hashes. A machine whose result for a fixed input differs from the reference
+/// is discarded and rebuilt. Zero disables the checks.
+///
+/// The reference is computed by a new light machine, which is only trusted if
+/// the library reproduces [`randomx::REFERENCE_HASH`].
+fn set_sanity_check_interval(interval: usize) {
+	if interval > 0 && !library_matches_reference() {
+		error!(
+			target: LOG_TARGET,
+			"RandomX library does not reproduce its pinned reference hash, sanity checks are unreliable",
+		);
+	}
+	SANITY_CHECK_INTERVAL.store(interval, Ordering::SeqCst);
+}
+
+/// Whether the linked RandomX library reproduces the pinned hash of its
+/// bundled configuration, [`randomx::REFERENCE_HASH`].
+pub fn library_matches_reference() -> bool {
+	let config = Config { network_salt: None, ..*global_config() };
+	let cache = randomx::LightCache::new(randomx::REFERENCE_KEY, &config);
+
+	cache.reference_hash(randomx::REFERENCE_INPUT, &config) == randomx::REFERENCE_HASH
+}
+
+/// Detects machines left in a bad state by native faults, by comparing the
+/// hash of a fixed input against a reference computed independently of the
+/// machine, and of its dataset.
+struct SanityCheck {
+	expected: Option<H256>,
+	since_last: usize,
+}
+
+impl SanityCheck {
+	fn new() -> Self {
+		Self { expected: None, since_last: 0 }
+	}
+
+	/// Account for `hashes` about to be calculated, re-hashing the sentinel
+	/// input once `interval` of them passed, and comparing it to `reference`,
+	/// computed on first use. Returns `false` if the machine must be rebuilt.
+	fn check<R: FnOnce() -> H256, F: FnMut(&[u8]) -> H256>(
+		&mut self,
+		interval: usize,
+		hashes: usize,
+		reference: R,
+		mut calculate: F,
+	) -> bool {
+		let expected = *self.expected.get_or_insert_with(reference);
+
+		self.since_last = self.since_last.saturating_add(hashes);
+		if self.since_last < interval {
+			return true
+		}
+
+		self.since_last = 0;
+		calculate(SANITY_CHECK_INPUT) == expected
+	}
+}
+
+struct LocalMachine<M: randomx::WithCacheMode> {
+	key_hash: H256,
+	generation: usize,
+	cache: Arc<randomx::Cache<M>>,
+	vm: randomx::VM<M>,
+	sanity: SanityCheck,
 }
 
 thread_local! {
-	static FULL_MACHINE: RefCell<Option<(H256, randomx::FullVM)>> = RefCell::new(None);
-	static LIGHT_MACHINE: RefCell<Option<(H256, randomx::LightVM)>> = RefCell::new(None);
+	static FULL_MACHINE: RefCell<Option<LocalMachine<randomx::WithFullCacheMode>>> =
+		RefCell::new(None);
+	static LIGHT_MACHINE: RefCell<Option<LocalMachine<randomx::WithLightCacheMode>>> =
+		RefCell::new(None);
+	static LOCAL_VM_BUILDS: Cell<usize> = Cell::new(0);
+	static LOCAL_VM_KEY_HASH: Cell<Option<H256>> = Cell::new(None);
+}
+
+/// Number of machines built on the calling thread.
+pub fn local_vm_builds() -> usize {
+	LOCAL_VM_BUILDS.with(|builds| builds.get())
+}
+
+/// Key hash of the machine the calling thread last computed with, if any.
+/// Differing from the expected epoch key hints at unexpected machine rebuilds.
+pub fn current_vm_key_hash() -> Option<H256> {
+	LOCAL_VM_KEY_HASH.with(|key_hash| key_hash.get())
+}
+
+/// Make sure the calling thread has a machine for `key_hash` ready, building
+/// its cache if needed, without calculating any hash.
+pub fn warm(key_hash: &H256, mode: ComputeMode) {
+	loop_raw(
+		key_hash,
+		mode,
+		|| (Vec::new(), ()),
+		|_, ()| Loop::<Option<()>>::Break(None),
+		0,
+	);
+}
+
+/// Like [`warm`], but returns an error instead of panicking if no machine can
+/// be prepared.
+pub fn try_warm(key_hash: &H256, mode: ComputeMode) -> Result<(), ComputeError> {
+	try_loop_raw(
+		key_hash,
+		mode,
+		|| (Vec::new(), ()),
+		|_, ()| Loop::<Option<()>>::Break(None),
+		0,
+	).map(|_| ())
+}
+
+/// Dataset generations remembered by [`generation_history`].
+pub const GENERATION_HISTORY_LEN: usize = 16;
+
+lazy_static! {
+	static ref GENERATION_HISTORY: Mutex<VecDeque<GenerationRecord>> =
+		Mutex::new(VecDeque::with_capacity(GENERATION_HISTORY_LEN));
+}
+
+/// Dataset generation, split in chunks generated in parallel.
+pub trait ChunkedGenerator: Send + Sync {
+	fn chunk_count(&self) -> usize;
+	/// Generate a chunk, returning the number of bytes written.
+	fn generate_chunk(&self, index: usize) -> u64;
+}
+
+/// Completed dataset generation.
+#[derive(Clone, PartialEq, Debug)]
+pub struct GenerationRecord {
+	pub key_hash: H256,
+	pub bytes: u64,
+	pub duration: Duration,
+	/// Achieved memory bandwidth, in gigabytes per second. Dataset generation
+	/// is bandwidth bound, so a drop hints at degraded or throttled memory.
+	pub bandwidth_gbps: f64,
+}
+
+impl GenerationRecord {
+	pub fn new(key_hash: H256, bytes: u64, duration: Duration) -> Self {
+		let seconds = duration.as_secs_f64();
+		let bandwidth_gbps = if seconds > 0.0 { bytes as f64 / seconds / 1e9 } else { 0.0 };
+
+		Self { key_hash, bytes, duration, bandwidth_gbps }
+	}
+}
+
+/// Source of the temperature dataset generation is limited by.
+pub trait TemperatureSource: Send + Sync {
+	/// Current temperature, in degrees Celsius, or `None` if unknown.
+	fn celsius(&self) -> Option<f64>;
+}
+
+/// Pauses dataset generation while a temperature is above a threshold, for
+/// thermally constrained machines, trading a slower startup for safety.
+pub struct ThermalLimit {
+	source: Box<dyn TemperatureSource>,
+	max_celsius: f64,
+	pause: Duration,
+	pauses: AtomicUsize,
+}
+
+impl ThermalLimit {
+	pub fn new<S: TemperatureSource + 'static>(source: S, max_celsius: f64, pause: Duration) -> Self {
+		Self { source: Box::new(source), max_celsius, pause, pauses: AtomicUsize::new(0) }
+	}
+
+	pub fn max_celsius(&self) -> f64 {
+		self.max_celsius
+	}
+
+	/// Number of pauses taken so far.
+	pub fn pauses(&self) -> usize {
+		self.pauses.load(Ordering::SeqCst)
+	}
+
+	/// Pause until the temperature is at most the threshold. Unknown
+	/// temperatures do not pause.
+	pub fn wait_until_cool(&self) {
+		while let Some(celsius) = self.source.celsius().filter(|celsius| *celsius > self.max_celsius) {
+			if self.pauses.fetch_add(1, Ordering::SeqCst) == 0 {
+				warn!(
+					target: LOG_TARGET,
+					"Temperature of {}°C above {}°C, pausing dataset generation",
+					celsius,
+					self.max_celsius,
+				);
+			}
+			std::thread::sleep(self.pause);
+		}
+	}
+}
+
+lazy_static! {
+	static ref THERMAL_LIMIT: Mutex<Option<Arc<ThermalLimit>>> = Mutex::new(None);
+}
+
+/// Limit full dataset generation by temperature. Not limited if `None`.
+fn set_thermal_limit(limit: Option<Arc<ThermalLimit>>) {
+	*THERMAL_LIMIT.lock().expect("Mutex poisioned") = limit;
+}
+
+/// Latest dataset generations, oldest first.
+pub fn generation_history() -> Vec<GenerationRecord> {
+	GENERATION_HISTORY.lock().expect("Mutex poisioned").iter().cloned().collect()
+}
+
+/// Run all chunks of `generator` on `threads` threads, and record the
+/// generation in the history. Chunks wait for the thermal limit of the
+/// [`CacheSettings`], if any.
+pub fn generate_chunked<G: ChunkedGenerator + 'static>(
+	key_hash: H256,
+	generator: Arc<G>,
+	threads: usize,
+) -> GenerationRecord {
+	let thermal = THERMAL_LIMIT.lock().expect("Mutex poisioned").clone();
+	generate_chunked_with(key_hash, generator, threads, thermal)
+}
+
+/// Like [`generate_chunked`], with the given thermal limit.
+pub fn generate_chunked_with<G: ChunkedGenerator + 'static>(
+	key_hash: H256,
+	generator: Arc<G>,
+	threads: usize,
+	thermal: Option<Arc<ThermalLimit>>,
+) -> GenerationRecord {
+	let next_chunk = Arc::new(AtomicUsize::new(0));
+	let started = Instant::now();
+
+	let handles = (0..threads.max(1)).map(|_| {
+		let generator = generator.clone();
+		let next_chunk = next_chunk.clone();
+		let thermal = thermal.clone();
+
+		std::thread::spawn(move || {
+			let mut bytes = 0;
+			loop {
+				if let Some(thermal) = &thermal {
+					thermal.wait_until_cool();
+				}
+
+				let index = next_chunk.fetch_add(1, Ordering::SeqCst);
+				if index >= generator.chunk_count() {
+					return bytes
+				}
+				bytes += generator.generate_chunk(index);
+			}
+		})
+	}).collect::<Vec<_>>();
+
+	let bytes = handles.into_iter()
+		.map(|handle| handle.join().expect("Dataset generation thread panicked"))
+		.sum();
+	let record = GenerationRecord::new(key_hash, bytes, started.elapsed());
+
+	let mut history = GENERATION_HISTORY.lock().expect("Mutex poisioned");
+	if history.len() == GENERATION_HISTORY_LEN {
+		history.pop_front();
+	}
+	history.push_back(record.clone());
+
+	record
+}
+
+/// Dataset of a full cache, generated in contiguous ranges of items.
+struct DatasetChunks {
+	// Keeps the cache alive for the writer.
+	_cache: Arc<randomx::FullCache>,
+	writer: randomx::DatasetWriter,
+	chunks: usize,
+}
+
+impl DatasetChunks {
+	fn range(&self, index: usize) -> (u64, u64) {
+		let items = randomx::dataset_item_count();
+		let start = items * index as u64 / self.chunks as u64;
+		let end = items * (index as u64 + 1) / self.chunks as u64;
+		(start, end - start)
+	}
+}
+
+impl ChunkedGenerator for DatasetChunks {
+	fn chunk_count(&self) -> usize {
+		self.chunks
+	}
+
+	fn generate_chunk(&self, index: usize) -> u64 {
+		let (start, count) = self.range(index);
+		// Chunks are disjoint, and the cache is only shared once initialized.
+		unsafe { self.writer.init_dataset_items(start, count) };
+		count * randomx::DATASET_ITEM_SIZE_BYTES as u64
+	}
+}
+
+/// Generate the full dataset for `key_hash` on `threads` threads, waiting for
+/// the thermal limit. The generation is recorded in the history.
+fn generate_full_cache(key_hash: &H256, threads: usize) -> Arc<randomx::FullCache> {
+	// The cache is only returned once all chunks are generated, and the chunks
+	// keep it alive for the writer.
+	let mut cache = unsafe { randomx::FullCache::new_uninit(&key_hash[..], global_config()) };
+	let writer = unsafe { cache.dataset_writer() };
+	let cache = Arc::new(cache);
+	let chunks = Arc::new(DatasetChunks { _cache: cache.clone(), writer, chunks: threads.max(1) * 4 });
+	generate_chunked(*key_hash, chunks, threads);
+
+	cache
 }
 
 static GLOBAL_CONFIG: OnceCell<Config> = OnceCell::new();
@@ -57,73 +616,405 @@ pub fn set_global_config(config: Config) -> Result<(), Config> {
 	GLOBAL_CONFIG.set(config)
 }
 
+/// Process-wide settings of the shared RandomX caches and of the machines of
+/// all threads, applied once at startup with [`configure`].
+#[derive(Clone)]
+pub struct CacheSettings {
+	/// Number of full datasets, used for mining, kept in memory.
+	pub full_cache_capacity: usize,
+	/// Number of light caches, used for verification, kept in memory.
+	pub light_cache_capacity: usize,
+	/// Hashes between two machine sanity checks. Zero disables them.
+	pub sanity_check_interval: usize,
+	/// Evict cached datasets while the system has less than this many bytes
+	/// of memory available. Disabled if not set.
+	pub memory_pressure_threshold: Option<u64>,
+	/// Memory budget of the caches. Mining uses full datasets if all of them
+	/// fit, and light machines otherwise. Full datasets if not set.
+	pub max_memory_bytes: Option<u64>,
+	/// Pause full dataset generation while too hot. Not limited if not set.
+	pub thermal_limit: Option<Arc<ThermalLimit>>,
+}
+
+impl Default for CacheSettings {
+	fn default() -> Self {
+		Self {
+			full_cache_capacity: DEFAULT_FULL_CACHE_CAPACITY,
+			light_cache_capacity: DEFAULT_LIGHT_CACHE_CAPACITY,
+			sanity_check_interval: 0,
+			memory_pressure_threshold: None,
+			max_memory_bytes: None,
+			thermal_limit: None,
+		}
+	}
+}
+
+static CACHE_SETTINGS: OnceCell<CacheSettings> = OnceCell::new();
+
+/// Apply `settings` to the shared caches. Only the first call takes effect, so
+/// that the process is configured once, before any algorithm is built. Later
+/// calls hand their settings back.
+pub fn configure(settings: CacheSettings) -> Result<(), CacheSettings> {
+	CACHE_SETTINGS.set(settings)?;
+	apply_settings(CACHE_SETTINGS.get().expect("Settings were just set; qed"));
+	Ok(())
+}
+
+/// Settings applied by [`configure`], or the defaults if it was not called.
+pub fn cache_settings() -> CacheSettings {
+	CACHE_SETTINGS.get().cloned().unwrap_or_default()
+}
+
+fn apply_settings(settings: &CacheSettings) {
+	set_cache_capacity(settings.full_cache_capacity, settings.light_cache_capacity);
+	set_sanity_check_interval(settings.sanity_check_interval);
+	match settings.memory_pressure_threshold {
+		Some(threshold) => enable_memory_pressure_eviction(SystemMemory, threshold),
+		None => disable_memory_pressure_eviction(),
+	}
+	set_thermal_limit(settings.thermal_limit.clone());
+
+	let mining_mode = match settings.max_memory_bytes {
+		Some(budget) => {
+			let mode = select_mining_mode(budget, settings.full_cache_capacity);
+			info!(
+				target: LOG_TARGET,
+				"Memory budget of {} bytes, mining with RandomX {} mode",
+				budget,
+				if mode == ComputeMode::Mining { "full" } else { "light" },
+			);
+			mode
+		},
+		None => ComputeMode::Mining,
+	};
+	set_light_mining(mining_mode == ComputeMode::Sync);
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, Debug)]
 pub enum ComputeMode {
 	Sync,
 	Mining,
 }
 
+/// Largest input fed to a RandomX machine, in bytes. V2 inputs are 160 bytes,
+/// leaving room for extensions of the calculation, while rejecting
+/// pathologically large inputs before they reach the machine.
+pub const MAX_INPUT_SIZE: usize = 512;
+
+/// Failure preparing a RandomX machine, or refusing its input.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ComputeError {
+	/// A thread panicked while holding the shared caches.
+	CachePoisoned,
+	/// No machine is available after preparing it. This is a bug.
+	MachineUnavailable,
+	/// The input is larger than [`MAX_INPUT_SIZE`].
+	InputTooLarge { size: usize },
+	/// A new cache would not fit above the memory pressure threshold, even
+	/// after evicting all shared caches.
+	MemoryPressure,
+}
+
+impl fmt::Display for ComputeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ComputeError::CachePoisoned => write!(f, "Shared RandomX cache mutex poisoned"),
+			ComputeError::MachineUnavailable => write!(f, "RandomX machine unavailable"),
+			ComputeError::InputTooLarge { size } =>
+				write!(f, "RandomX input of {} bytes exceeds {} bytes", size, MAX_INPUT_SIZE),
+			ComputeError::MemoryPressure =>
+				write!(f, "Not enough memory available for a new RandomX cache"),
+		}
+	}
+}
+
+impl std::error::Error for ComputeError { }
+
 #[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, Debug)]
 pub enum Loop<R> {
 	Continue,
 	Break(R),
 }
 
-#[derive(Clone, PartialEq, Eq, Encode, Decode, Debug)]
-pub struct Calculation {
-	pub pre_hash: H256,
-	pub difficulty: Difficulty,
-	pub nonce: H256,
+/// Seal of any algorithm version. V3 seals have the V2 layout.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Seal {
+	V1(SealV1),
+	V2(SealV2),
+}
+
+/// Encoded length of a V1 seal: difficulty and nonce.
+pub const SEAL_V1_ENCODED_LEN: usize = 32 + 32;
+/// Encoded length of a V2 seal: difficulty, nonce and signature.
+pub const SEAL_V2_ENCODED_LEN: usize = 32 + 32 + SIGNATURE_LENGTH;
+
+/// Length of the SCALE encoding of the seal, without encoding it.
+pub fn seal_encoded_len(seal: &Seal) -> usize {
+	match seal {
+		Seal::V1(_) => SEAL_V1_ENCODED_LEN,
+		Seal::V2(_) => SEAL_V2_ENCODED_LEN,
+	}
+}
+
+/// Layout version of encoded seal bytes, `1` or `2`, or `None` if they are no
+/// valid seal. Seals carry no version byte, so layouts are told apart by their
+/// exact length. Algorithm V3 seals share the V2 layout and are reported as `2`.
+pub fn detect_seal_version(seal: &[u8]) -> Option<u8> {
+	match seal.len() {
+		SEAL_V1_ENCODED_LEN => SealV1::decode(&mut &seal[..]).ok().map(|_| 1),
+		SEAL_V2_ENCODED_LEN => SealV2::decode(&mut &seal[..]).ok().map(|_| 2),
+		_ => None,
+	}
+}
+
+impl Encode for Seal {
+	fn size_hint(&self) -> usize {
+		seal_encoded_len(self)
+	}
+
+	fn encode_to<T: codec::Output>(&self, dest: &mut T) {
+		match self {
+			Seal::V1(seal) => seal.encode_to(dest),
+			Seal::V2(seal) => seal.encode_to(dest),
+		}
+	}
+}
+
+/// Drop all shared caches and invalidate the thread-local machines.
+///
+/// The machine of the calling thread is released immediately. Machines of other
+/// threads are released the next time those threads compute.
+pub fn flush_caches() {
+	CACHE_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+	FULL_SHARED_CACHES.lock().expect("Mutex poisioned").clear();
+	LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned").clear();
+
+	release_local_machines();
+}
+
+/// Drop the machines of the calling thread, and with them its hold on their
+/// caches.
+pub fn release_local_machines() {
+	FULL_MACHINE.with(|machine| *machine.borrow_mut() = None);
+	LIGHT_MACHINE.with(|machine| *machine.borrow_mut() = None);
+}
+
+/// Whether the calling thread holds a machine.
+pub fn holds_machines() -> bool {
+	FULL_MACHINE.with(|machine| machine.borrow().is_some()) ||
+		LIGHT_MACHINE.with(|machine| machine.borrow().is_some())
+}
+
+/// Total memory held by the shared caches, in bytes.
+pub fn cache_memory_bytes() -> usize {
+	fn memory_bytes<M: randomx::WithCacheMode>(
+		shared_caches: &SharedCaches<M>,
+	) -> usize {
+		shared_caches.lock().expect("Mutex poisioned")
+			.iter()
+			.map(|(_, cache)| cache.memory_bytes())
+			.sum()
+	}
+
+	memory_bytes(&FULL_SHARED_CACHES) + memory_bytes(&LIGHT_SHARED_CACHES)
+}
+
+/// Memory used by `capacity` caches of the given mode, in bytes: a full dataset
+/// and its cache when mining, a light cache otherwise. Machine scratchpads are
+/// not included.
+pub fn expected_cache_memory(capacity: usize, mode: ComputeMode) -> usize {
+	let per_cache = match mode {
+		ComputeMode::Mining => randomx::CACHE_SIZE_BYTES + randomx::DATASET_SIZE_BYTES,
+		ComputeMode::Sync => randomx::CACHE_SIZE_BYTES,
+	};
+
+	capacity.saturating_mul(per_cache)
+}
+
+/// Pages of the dataset sampled by a cache fingerprint.
+const FINGERPRINT_PAGES: usize = 16;
+/// Size of a page sampled by a cache fingerprint, in bytes.
+const FINGERPRINT_PAGE_SIZE: usize = 4096;
+
+/// Cheap fingerprint of a dataset, to confirm that two nodes built the same
+/// dataset for an epoch.
+pub trait CacheFingerprint {
+	fn fingerprint(&self) -> H256;
+}
+
+impl CacheFingerprint for randomx::FullCache {
+	/// Hash of the dataset size and of evenly spaced pages of the dataset.
+	fn fingerprint(&self) -> H256 {
+		let samples = self.dataset_samples(FINGERPRINT_PAGES, FINGERPRINT_PAGE_SIZE);
+		H256::from(sp_core::blake2_256(&(randomx::DATASET_SIZE_BYTES as u64, samples).encode()[..]))
+	}
+}
+
+/// Fingerprint of the shared full dataset for `key_hash`, if it is held.
+pub fn cache_fingerprint(key_hash: &H256) -> Option<H256> {
+	FULL_SHARED_CACHES.lock().expect("Mutex poisioned")
+		.get_mut(key_hash)
+		.map(|cache| cache.fingerprint())
+}
+
+/// Number of caches currently held in the shared caches.
+pub fn cache_count() -> usize {
+	FULL_SHARED_CACHES.lock().expect("Mutex poisioned").len() +
+		LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned").len()
+}
+
+/// Key hashes of the caches currently held in the shared caches, full ones
+/// first, each from least to most recently used.
+pub fn cached_key_hashes() -> Vec<H256> {
+	let mut key_hashes: Vec<H256> = FULL_SHARED_CACHES.lock().expect("Mutex poisioned")
+		.iter()
+		.map(|(key_hash, _)| *key_hash)
+		.collect();
+
+	for (key_hash, _) in LIGHT_SHARED_CACHES.lock().expect("Mutex poisioned").iter() {
+		if !key_hashes.contains(key_hash) {
+			key_hashes.push(*key_hash);
+		}
+	}
+
+	key_hashes
 }
 
 fn need_new_vm<M: randomx::WithCacheMode>(
 	key_hash: &H256,
-	machine: &RefCell<Option<(H256, randomx::VM<M>)>>,
+	machine: &RefCell<Option<LocalMachine<M>>>,
 ) -> bool {
 	let ms = machine.borrow();
+	let generation = CACHE_GENERATION.load(Ordering::SeqCst);
 
-	let need_new_vm = ms.as_ref().map(|(mkey_hash, _)| {
-		mkey_hash != key_hash
+	let need_new_vm = ms.as_ref().map(|m| {
+		&m.key_hash != key_hash || m.generation != generation
 	}).unwrap_or(true);
 
 	need_new_vm
 }
 
-fn loop_raw_with_cache<M: randomx::WithCacheMode, FPre, I, FValidate, R>(
+fn check_machine_sanity<M: randomx::WithCacheMode>(
+	key_hash: &H256,
+	machine: &RefCell<Option<LocalMachine<M>>>,
+	hashes: usize,
+) {
+	let interval = SANITY_CHECK_INTERVAL.load(Ordering::SeqCst);
+	if interval == 0 {
+		return
+	}
+
+	let mut ms = machine.borrow_mut();
+
+	let healthy = match ms.as_mut() {
+		Some(LocalMachine { key_hash: mkey_hash, cache, vm, sanity, .. }) if mkey_hash == key_hash =>
+			sanity.check(
+				interval,
+				hashes,
+				|| H256::from(cache.reference_hash(SANITY_CHECK_INPUT, global_config())),
+				|input| H256::from(vm.calculate(input)),
+			),
+		_ => true,
+	};
+
+	if !healthy {
+		error!(
+			target: LOG_TARGET,
+			"RandomX {} machine with key hash {} failed the sanity check, rebuilding ...",
+			M::description(),
+			key_hash,
+		);
+		*ms = None;
+	}
+}
+
+fn loop_raw_with_cache<M: CacheModeOf, FPre, I, FValidate, R>(
 	key_hash: &H256,
-	machine: &RefCell<Option<(H256, randomx::VM<M>)>>,
-	shared_caches: &Arc<Mutex<LruCache<H256, Arc<randomx::Cache<M>>>>>,
+	machine: &RefCell<Option<LocalMachine<M>>>,
+	shared_caches: &SharedCaches<M>,
 	mut f_pre: FPre,
 	f_validate: FValidate,
 	round: usize,
-) -> Option<R> where
+) -> Result<Option<R>, ComputeError> where
 	FPre: FnMut() -> (Vec<u8>, I),
 	FValidate: Fn(H256, I) -> Loop<Option<R>>,
 {
-	if need_new_vm(key_hash, machine) {
+	#[cfg(feature = "tracing")]
+	let span = tracing::debug_span!(
+		"compute",
+		key_hash = ?key_hash,
+		mode = M::description(),
+		vm_rebuilt = tracing::field::Empty,
+	);
+	#[cfg(feature = "tracing")]
+	let _entered = span.enter();
+
+	check_machine_sanity(key_hash, machine, round);
+
+	let rebuild = need_new_vm(key_hash, machine);
+	#[cfg(feature = "tracing")]
+	span.record("vm_rebuilt", &rebuild);
+
+	if rebuild {
 		let mut ms = machine.borrow_mut();
+		// Release the previous cache of this thread before making room.
+		*ms = None;
+
+		let held = shared_caches.lock().map_err(|_| ComputeError::CachePoisoned)?
+			.get_mut(key_hash)
+			.is_some();
+		if !held && !relieve_memory_pressure(expected_cache_memory(1, M::MODE) as u64) {
+			return Err(ComputeError::MemoryPressure)
+		}
 
-		let mut shared_caches = shared_caches.lock().expect("Mutex poisioned");
+		let generation = CACHE_GENERATION.load(Ordering::SeqCst);
+		let mut shared_caches = shared_caches.lock().map_err(|_| ComputeError::CachePoisoned)?;
+		LOCAL_VM_BUILDS.with(|builds| builds.set(builds.get() + 1));
 
 		if let Some(cache) = shared_caches.get_mut(key_hash) {
-			*ms = Some((*key_hash, randomx::VM::new(cache.clone(), global_config())));
+			*ms = Some(LocalMachine {
+				key_hash: *key_hash,
+				generation,
+				cache: cache.clone(),
+				vm: randomx::VM::new(cache.clone(), global_config()),
+				sanity: SanityCheck::new(),
+			});
 		} else {
-			info!(
-				target: "kulupu-randomx",
+			log!(
+				target: LOG_TARGET,
+				generation_log_level(),
 				"At block boundary, generating new RandomX {} cache with key hash {} ...",
 				M::description(),
 				key_hash,
 			);
-			let cache = Arc::new(randomx::Cache::new(&key_hash[..], global_config()));
+			let cache = M::generate(key_hash);
 			shared_caches.insert(*key_hash, cache.clone());
-			*ms = Some((*key_hash, randomx::VM::new(cache, global_config())));
+			*ms = Some(LocalMachine {
+				key_hash: *key_hash,
+				generation,
+				cache: cache.clone(),
+				vm: randomx::VM::new(cache, global_config()),
+				sanity: SanityCheck::new(),
+			});
 		}
 	}
 
 	let mut ms = machine.borrow_mut();
+	LOCAL_VM_KEY_HASH.with(|current| current.set(ms.as_ref().map(|m| m.key_hash)));
 
-	let ret = ms.as_mut()
-		.map(|(mkey_hash, vm)| {
+	// Inputs are checked as they are produced, so that no oversized input
+	// reaches the machine.
+	let mut next_input = || {
+		let (pre, int) = f_pre();
+		if pre.len() > MAX_INPUT_SIZE {
+			return Err(ComputeError::InputTooLarge { size: pre.len() })
+		}
+		Ok((pre, int))
+	};
+
+	let ret = ms.as_mut()
+		.map(|LocalMachine { key_hash: mkey_hash, vm, .. }| {
 			assert_eq!(mkey_hash, key_hash,
 					   "Condition failed checking cached key_hash. This is a bug");
 
@@ -132,7 +1023,7 @@ fn loop_raw_with_cache<M: randomx::WithCacheMode, FPre, I, FValidate, R>(
 			match round {
 				0 => (),
 				1 => {
-					let (pre, int) = f_pre();
+					let (pre, int) = next_input()?;
 					let hash = H256::from(vm.calculate(&pre[..]));
 					let validate = f_validate(hash, int);
 
@@ -144,11 +1035,11 @@ fn loop_raw_with_cache<M: randomx::WithCacheMode, FPre, I, FValidate, R>(
 					}
 				},
 				_ => {
-					let (prev_pre, mut prev_int) = f_pre();
+					let (prev_pre, mut prev_int) = next_input()?;
 					let mut vmn = vm.begin(&prev_pre[..]);
 
 					for _ in 1..round {
-						let (pre, int) = f_pre();
+						let (pre, int) = next_input()?;
 						let prev_hash = H256::from(vmn.next(&pre[..]));
 						let prev_validate = f_validate(prev_hash, prev_int);
 
@@ -175,11 +1066,11 @@ fn loop_raw_with_cache<M: randomx::WithCacheMode, FPre, I, FValidate, R>(
 				}
 			}
 
-			ret
+			Ok(ret)
 		})
-		.expect("Local MACHINES always set to Some above; qed");
+		.ok_or(ComputeError::MachineUnavailable)??;
 
-	ret
+	Ok(ret)
 }
 
 pub fn loop_raw<FPre, I, FValidate, R>(
@@ -192,18 +1083,57 @@ pub fn loop_raw<FPre, I, FValidate, R>(
 	FPre: FnMut() -> (Vec<u8>, I),
 	FValidate: Fn(H256, I) -> Loop<Option<R>>,
 {
-	match mode {
-		ComputeMode::Mining =>
-			FULL_MACHINE.with(|machine| {
+	try_loop_raw(key_hash, mode, f_pre, f_validate, round)
+		.unwrap_or_else(|e| panic!("RandomX computation failed: {}", e))
+}
+
+/// Like [`loop_raw`], but returns an error instead of panicking when no machine
+/// can be prepared, or an input is larger than [`MAX_INPUT_SIZE`].
+pub fn try_loop_raw<FPre, I, FValidate, R>(
+	key_hash: &H256,
+	mode: ComputeMode,
+	f_pre: FPre,
+	f_validate: FValidate,
+	round: usize,
+) -> Result<Option<R>, ComputeError> where
+	FPre: FnMut() -> (Vec<u8>, I),
+	FValidate: Fn(H256, I) -> Loop<Option<R>>,
+{
+	match effective_mode(mode) {
+		ComputeMode::Mining => {
+			let mut f_pre = f_pre;
+			let full_ret = FULL_MACHINE.with(|machine| {
 				loop_raw_with_cache::<randomx::WithFullCacheMode, _, _, _, _>(
 					key_hash,
 					machine,
 					&FULL_SHARED_CACHES,
-					f_pre,
-					f_validate,
+					&mut f_pre,
+					&f_validate,
 					round,
 				)
-			}),
+			});
+
+			match full_ret {
+				Err(ComputeError::MemoryPressure) => {
+					warn!(
+						target: LOG_TARGET,
+						"No memory for a RandomX dataset with key hash {}, mining in light mode",
+						key_hash,
+					);
+					LIGHT_MACHINE.with(|machine| {
+						loop_raw_with_cache::<randomx::WithLightCacheMode, _, _, _, _>(
+							key_hash,
+							machine,
+							&LIGHT_SHARED_CACHES,
+							f_pre,
+							f_validate,
+							round,
+						)
+					})
+				},
+				ret => ret,
+			}
+		},
 		ComputeMode::Sync => {
 			let full_ret = FULL_MACHINE.with(|machine| {
 				if !need_new_vm::<randomx::WithFullCacheMode>(key_hash, machine) {
@@ -239,6 +1169,190 @@ pub fn loop_raw<FPre, I, FValidate, R>(
 	}
 }
 
+/// Source of work hashes for a given key hash.
+pub trait WorkBackend {
+	/// Calculate the work hash of the raw input under `key_hash`.
+	fn work(&self, key_hash: &H256, input: &[u8], mode: ComputeMode) -> H256;
+
+	/// Calculate the work of up to `round` inputs produced by `f_pre`, until
+	/// `f_validate` breaks the loop.
+	fn work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		mut f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Option<R> where
+		Self: Sized,
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		for _ in 0..round {
+			let (pre, int) = f_pre();
+			let hash = self.work(key_hash, &pre[..], mode);
+
+			match f_validate(hash, int) {
+				Loop::Continue => (),
+				Loop::Break(b) => return b,
+			}
+		}
+
+		None
+	}
+
+	/// Like [`WorkBackend::work_loop`], returning an error instead of
+	/// panicking when no work can be computed.
+	fn try_work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Result<Option<R>, ComputeError> where
+		Self: Sized,
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		Ok(self.work_loop(key_hash, mode, f_pre, f_validate, round))
+	}
+}
+
+/// Work backend using RandomX machines built from the shared caches.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RandomXBackend;
+
+/// Compute the work of a single input, refusing inputs larger than
+/// [`MAX_INPUT_SIZE`] before any machine is prepared.
+pub fn try_work(key_hash: &H256, input: &[u8], mode: ComputeMode) -> Result<H256, ComputeError> {
+	if input.len() > MAX_INPUT_SIZE {
+		return Err(ComputeError::InputTooLarge { size: input.len() })
+	}
+
+	try_loop_raw(
+		key_hash,
+		mode,
+		|| (input.to_vec(), ()),
+		|hash, ()| Loop::Break(Some(hash)),
+		1,
+	).map(|work| work.expect("Loop break always returns Some; qed"))
+}
+
+impl WorkBackend for RandomXBackend {
+	fn work(&self, key_hash: &H256, input: &[u8], mode: ComputeMode) -> H256 {
+		loop_raw(
+			key_hash,
+			mode,
+			|| (input.to_vec(), ()),
+			|hash, ()| Loop::Break(Some(hash)),
+			1,
+		).expect("Loop break always returns Some; qed")
+	}
+
+	fn work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Option<R> where
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		loop_raw(key_hash, mode, f_pre, f_validate, round)
+	}
+
+	fn try_work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Result<Option<R>, ComputeError> where
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		try_loop_raw(key_hash, mode, f_pre, f_validate, round)
+	}
+}
+
+/// Work backend hashing the key hash and input with blake2, without any
+/// dataset. Only meant for test networks.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Blake2TestBackend;
+
+impl WorkBackend for Blake2TestBackend {
+	fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+		H256::from(sp_core::blake2_256(&(key_hash, input).encode()[..]))
+	}
+}
+
+/// Work function selected at construction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum WorkAlgo {
+	/// RandomX, as on mainnet.
+	RandomX,
+	/// Blake2, bypassing the RandomX dataset. Only accepted by runtimes using
+	/// the blake2 test identifier.
+	Blake2Test,
+}
+
+impl Default for WorkAlgo {
+	fn default() -> Self {
+		WorkAlgo::RandomX
+	}
+}
+
+impl WorkBackend for WorkAlgo {
+	fn work(&self, key_hash: &H256, input: &[u8], mode: ComputeMode) -> H256 {
+		match self {
+			WorkAlgo::RandomX => RandomXBackend.work(key_hash, input, mode),
+			WorkAlgo::Blake2Test => Blake2TestBackend.work(key_hash, input, mode),
+		}
+	}
+
+	fn work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Option<R> where
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		match self {
+			WorkAlgo::RandomX =>
+				RandomXBackend.work_loop(key_hash, mode, f_pre, f_validate, round),
+			WorkAlgo::Blake2Test =>
+				Blake2TestBackend.work_loop(key_hash, mode, f_pre, f_validate, round),
+		}
+	}
+
+	fn try_work_loop<FPre, I, FValidate, R>(
+		&self,
+		key_hash: &H256,
+		mode: ComputeMode,
+		f_pre: FPre,
+		f_validate: FValidate,
+		round: usize,
+	) -> Result<Option<R>, ComputeError> where
+		FPre: FnMut() -> (Vec<u8>, I),
+		FValidate: Fn(H256, I) -> Loop<Option<R>>,
+	{
+		match self {
+			WorkAlgo::RandomX =>
+				RandomXBackend.try_work_loop(key_hash, mode, f_pre, f_validate, round),
+			WorkAlgo::Blake2Test =>
+				Blake2TestBackend.try_work_loop(key_hash, mode, f_pre, f_validate, round),
+		}
+	}
+}
+
 pub fn compute<T: Encode>(key_hash: &H256, input: &T, mode: ComputeMode) -> H256 {
 	loop_raw(
 		key_hash,
@@ -249,11 +1363,80 @@ pub fn compute<T: Encode>(key_hash: &H256, input: &T, mode: ComputeMode) -> H256
 	).expect("Loop break always returns Some; qed")
 }
 
+/// Measure the aggregate hashrate of `threads` threads mining throwaway work
+/// for `duration`, in hashes per second.
+pub fn benchmark_hashrate(key_hash: H256, duration: Duration, threads: usize) -> f64 {
+	benchmark_hashrate_with(Arc::new(RandomXBackend), key_hash, ComputeMode::Mining, duration, threads)
+}
+
+pub fn benchmark_hashrate_with<W>(
+	backend: Arc<W>,
+	key_hash: H256,
+	mode: ComputeMode,
+	duration: Duration,
+	threads: usize,
+) -> f64 where
+	W: WorkBackend + Send + Sync + 'static,
+{
+	const BATCH: usize = 64;
+
+	// Build the shared cache once, so that threads only build their machine.
+	backend.work(&key_hash, &[], mode);
+
+	let handles = (0..threads.max(1) as u64).map(|thread| {
+		let backend = backend.clone();
+
+		std::thread::spawn(move || {
+			let mut nonce = thread << 48;
+			backend.work(&key_hash, &nonce.encode()[..], mode);
+
+			let start = Instant::now();
+			let mut hashes = 0;
+			while start.elapsed() < duration {
+				backend.work_loop(
+					&key_hash,
+					mode,
+					|| {
+						nonce += 1;
+						(nonce.encode(), ())
+					},
+					|_, ()| Loop::<Option<()>>::Continue,
+					BATCH,
+				);
+				hashes += BATCH;
+			}
+
+			hashes as f64 / start.elapsed().as_secs_f64()
+		})
+	}).collect::<Vec<_>>();
+
+	handles.into_iter()
+		.map(|handle| handle.join().expect("Benchmark thread panicked"))
+		.sum()
+}
+
+/// Like [`compute`], but returns an error instead of panicking.
+pub fn try_compute<T: Encode>(
+	key_hash: &H256,
+	input: &T,
+	mode: ComputeMode,
+) -> Result<H256, ComputeError> {
+	try_loop_raw(
+		key_hash,
+		mode,
+		|| (input.encode(), ()),
+		|hash, ()| Loop::Break(Some(hash)),
+		1,
+	).map(|hash| hash.expect("Loop break always returns Some; qed"))
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use sp_core::{H256, U256};
 
+	use crate::mock::lock_shared_caches;
+
 	#[test]
 	fn randomx_len() {
 		assert_eq!(randomx::HASH_SIZE, 32);
@@ -261,8 +1444,9 @@ mod tests {
 
 	#[test]
 	fn randomx_collision() {
+		let _guard = lock_shared_caches();
 		let mut compute = ComputeV1 {
-			key_hash: H256::from([210, 164, 216, 149, 3, 68, 116, 1, 239, 110, 111, 48, 180, 102, 53, 180, 91, 84, 242, 90, 101, 12, 71, 70, 75, 83, 17, 249, 214, 253, 71, 89]),
+			key_hash: H256::from(TEST_KEY_HASH),
 			pre_hash: H256::default(),
 			difficulty: U256::default(),
 			nonce: H256::default(),
@@ -273,7 +1457,7 @@ mod tests {
 		assert!(hash1.1 != hash2.1);
 
 		let mut compute2 = ComputeV2 {
-			key_hash: H256::from([210, 164, 216, 149, 3, 68, 116, 1, 239, 110, 111, 48, 180, 102, 53, 180, 91, 84, 242, 90, 101, 12, 71, 70, 75, 83, 17, 249, 214, 253, 71, 89]),
+			key_hash: H256::from(TEST_KEY_HASH),
 			pre_hash: H256::default(),
 			difficulty: U256::default(),
 			nonce: H256::default(),
@@ -285,4 +1469,596 @@ mod tests {
 		assert!(hash1.1 != hash3.1);
 		assert!(hash2.1 != hash4.1);
 	}
+
+	#[test]
+	#[cfg(feature = "randomx-regression")]
+	fn randomx_example_hash_is_stable() {
+		let _guard = lock_shared_caches();
+
+		// The example pair of the bundled configuration, in both modes. Work
+		// of the canonical inputs under `TEST_KEY_HASH` is not pinned yet, and
+		// needs expected bytes from a build of the Kulupu configuration.
+		const EXPECTED: [u8; 32] = [
+			69, 167, 169, 170, 66, 104, 77, 15, 73, 13, 233, 6, 227, 92, 143, 244,
+			95, 153, 4, 251, 223, 169, 78, 126, 236, 216, 174, 147, 1, 213, 223, 59,
+		];
+		let config = Config { network_salt: None, ..*global_config() };
+		let light = Arc::new(randomx::LightCache::new(b"RandomX example key", &config));
+		assert_eq!(randomx::LightVM::new(light, &config).calculate(b"RandomX example input"), EXPECTED);
+		let full = Arc::new(randomx::FullCache::new(b"RandomX example key", &config));
+		assert_eq!(randomx::FullVM::new(full, &config).calculate(b"RandomX example input"), EXPECTED);
+		assert!(library_matches_reference());
+	}
+
+	#[test]
+	fn collision_with_fake_vm() {
+		let backend = crate::mock::CountingBackend::default();
+		let key_hash = H256::repeat_byte(1);
+
+		let mut compute = ComputeV1 {
+			key_hash,
+			pre_hash: H256::default(),
+			difficulty: U256::default(),
+			nonce: H256::default(),
+		};
+		let (_, work1) = compute.seal_and_work_with(&backend, ComputeMode::Sync);
+		U256::one().to_big_endian(&mut compute.nonce[..]);
+		let (_, work2) = compute.seal_and_work_with(&backend, ComputeMode::Sync);
+		assert!(work1 != work2);
+
+		let mut compute2 = ComputeV2 {
+			key_hash,
+			pre_hash: H256::default(),
+			difficulty: U256::default(),
+			nonce: H256::default(),
+		};
+		let (_, work3) = compute2.seal_and_work_with(&backend, Default::default(), ComputeMode::Sync);
+		U256::one().to_big_endian(&mut compute2.nonce[..]);
+		let (_, work4) = compute2.seal_and_work_with(&backend, Default::default(), ComputeMode::Sync);
+		assert!(work3 != work4);
+		assert!(work1 != work3);
+		assert!(work2 != work4);
+
+		assert_eq!(backend.calls(), 4);
+	}
+
+	#[test]
+	fn warmed_machine_is_reused() {
+		let _guard = lock_shared_caches();
+		let key_hash = H256::repeat_byte(0x43);
+
+		let builds = local_vm_builds();
+		warm(&key_hash, ComputeMode::Sync);
+		assert_eq!(local_vm_builds(), builds + 1);
+
+		compute(&key_hash, &0u64, ComputeMode::Sync);
+		assert_eq!(local_vm_builds(), builds + 1);
+	}
+
+	#[test]
+	fn current_vm_key_hash_follows_computation() {
+		let _guard = lock_shared_caches();
+		let first = H256::repeat_byte(0x44);
+		let second = H256::repeat_byte(0x45);
+
+		let (current, before) = std::thread::spawn(move || {
+			let before = current_vm_key_hash();
+			compute(&first, &0u64, ComputeMode::Sync);
+			(current_vm_key_hash(), before)
+		}).join().unwrap();
+		assert_eq!(before, None);
+		assert_eq!(current, Some(first));
+
+		compute(&second, &0u64, ComputeMode::Sync);
+		assert_eq!(current_vm_key_hash(), Some(second));
+	}
+
+	#[test]
+	fn flush_caches_releases_memory() {
+		let _guard = lock_shared_caches();
+		let key_hash = H256::repeat_byte(0x42);
+
+		let hash1 = compute(&key_hash, &0u64, ComputeMode::Sync);
+		assert!(cache_memory_bytes() > 0);
+
+		flush_caches();
+		assert_eq!(cache_memory_bytes(), 0);
+
+		let hash2 = compute(&key_hash, &0u64, ComputeMode::Sync);
+		assert!(cache_memory_bytes() > 0);
+		assert_eq!(hash1, hash2);
+	}
+
+	#[test]
+	fn benchmark_reports_positive_hashrate() {
+		let backend = Arc::new(crate::mock::CountingBackend::default());
+
+		let hashrate = benchmark_hashrate_with(
+			backend.clone(),
+			H256::repeat_byte(1),
+			ComputeMode::Sync,
+			Duration::from_millis(20),
+			2,
+		);
+
+		assert!(hashrate > 0.0);
+		assert!(backend.calls() > 3);
+	}
+
+	#[test]
+	fn poisoned_cache_is_reported_as_error() {
+		let shared_caches: SharedCaches<randomx::WithLightCacheMode> =
+			Arc::new(Mutex::new(Box::new(LruCache::new(1))));
+		let poisoner = shared_caches.clone();
+		let _ = std::thread::spawn(move || {
+			let _lock = poisoner.lock().unwrap();
+			panic!("Poisoning the shared caches");
+		}).join();
+
+		let machine = RefCell::new(None);
+		let ret = loop_raw_with_cache::<randomx::WithLightCacheMode, _, _, _, _>(
+			&H256::repeat_byte(1),
+			&machine,
+			&shared_caches,
+			|| (Vec::new(), ()),
+			|hash, ()| Loop::Break(Some(hash)),
+			1,
+		);
+
+		assert_eq!(ret, Err(ComputeError::CachePoisoned));
+		assert_eq!(ComputeError::CachePoisoned.to_string(), "Shared RandomX cache mutex poisoned");
+		assert!(machine.borrow().is_none());
+		assert_eq!(local_vm_builds(), 0);
+	}
+
+	#[test]
+	fn sanity_check_triggers_rebuild_on_corrupt_result() {
+		fn healthy(input: &[u8]) -> H256 {
+			H256::from(sp_core::blake2_256(input))
+		}
+
+		fn corrupt(_input: &[u8]) -> H256 {
+			H256::repeat_byte(0xff)
+		}
+
+		let reference = || healthy(SANITY_CHECK_INPUT);
+
+		let mut sanity = SanityCheck::new();
+		assert!(sanity.check(4, 1, reference, healthy));
+		assert!(sanity.check(4, 2, reference, corrupt));
+		assert!(sanity.check(4, 2, reference, healthy));
+		assert!(sanity.check(4, 3, reference, corrupt));
+		assert!(!sanity.check(4, 1, reference, corrupt));
+
+		// A machine corrupt from the start is not its own reference.
+		let mut sanity = SanityCheck::new();
+		assert!(!sanity.check(1, 1, reference, corrupt));
+	}
+
+	#[test]
+	fn machines_match_the_pinned_reference() {
+		let _guard = lock_shared_caches();
+		assert!(library_matches_reference());
+
+		let key_hash = H256::repeat_byte(9);
+		let cache = randomx::LightCache::new(&key_hash[..], global_config());
+		assert_eq!(
+			H256::from(cache.reference_hash(SANITY_CHECK_INPUT, global_config())),
+			RandomXBackend.work(&key_hash, SANITY_CHECK_INPUT, ComputeMode::Sync),
+		);
+	}
+
+	#[test]
+	fn seal_encoded_len_matches_encoding() {
+		let v1 = Seal::V1(SealV1 {
+			difficulty: U256::max_value(),
+			nonce: H256::repeat_byte(1),
+		});
+		let v2 = Seal::V2(SealV2 {
+			difficulty: U256::one(),
+			nonce: H256::repeat_byte(1),
+			signature: parse_signature(&[2u8; 64][..]).unwrap(),
+		});
+
+		for seal in &[v1, v2] {
+			assert_eq!(seal_encoded_len(seal), seal.encode().len());
+		}
+	}
+
+	#[test]
+	fn cache_generation_is_logged_under_randomx_target() {
+		let _guard = lock_shared_caches();
+		let logs = crate::mock::capture_logs();
+		let key_hash = H256::repeat_byte(0x61);
+
+		flush_caches();
+		compute(&key_hash, &0u64, ComputeMode::Sync);
+
+		let generation = logs.records().into_iter()
+			.find(|(_, _, message)| {
+				message.starts_with("At block boundary") && message.contains(&format!("{}", key_hash))
+			})
+			.expect("Generating a cache is logged");
+		assert_eq!(generation.0, LOG_TARGET);
+		assert_eq!(generation.1, Level::Info);
+	}
+
+	#[test]
+	fn fingerprint_identifies_dataset() {
+		// Only the pages sampled by the fingerprint are generated, not the
+		// whole datasets.
+		let fingerprint = |key: &[u8]| {
+			let item = randomx::DATASET_ITEM_SIZE_BYTES;
+			let stride = (randomx::DATASET_SIZE_BYTES - FINGERPRINT_PAGE_SIZE) / (FINGERPRINT_PAGES - 1);
+			let mut cache = unsafe { randomx::FullCache::new_uninit(key, global_config()) };
+
+			for page in 0..FINGERPRINT_PAGES {
+				let start = page * stride / item;
+				let end = (page * stride + FINGERPRINT_PAGE_SIZE + item - 1) / item;
+				cache.init_dataset_items(start as u64, (end - start) as u64);
+			}
+			cache.fingerprint()
+		};
+
+		let first = fingerprint(&[1u8; 32][..]);
+		assert_eq!(fingerprint(&[1u8; 32][..]), first);
+		assert_ne!(fingerprint(&[2u8; 32][..]), first);
+	}
+
+	#[test]
+	fn memory_budget_selects_mining_mode() {
+		let _guard = lock_shared_caches();
+		let dataset = randomx::DATASET_SIZE_BYTES as u64;
+
+		assert_eq!(select_mining_mode(0, 1), ComputeMode::Sync);
+		assert_eq!(select_mining_mode(dataset - 1, 1), ComputeMode::Sync);
+		assert_eq!(select_mining_mode(64 * dataset, 2), ComputeMode::Mining);
+		assert_eq!(select_mining_mode(3 * dataset, 4), ComputeMode::Sync);
+
+		apply_settings(&CacheSettings { max_memory_bytes: Some(dataset - 1), ..Default::default() });
+		assert!(light_mining());
+		assert_eq!(effective_mode(ComputeMode::Mining), ComputeMode::Sync);
+		apply_settings(&CacheSettings::default());
+		assert!(!light_mining());
+		assert_eq!(effective_mode(ComputeMode::Mining), ComputeMode::Mining);
+	}
+
+	#[test]
+	fn settings_apply_to_shared_caches() {
+		let _guard = lock_shared_caches();
+		flush_caches();
+
+		apply_settings(&CacheSettings { light_cache_capacity: 1, ..Default::default() });
+		compute(&H256::repeat_byte(1), &0u64, ComputeMode::Sync);
+		compute(&H256::repeat_byte(2), &0u64, ComputeMode::Sync);
+		assert_eq!(cache_count(), 1);
+		assert_eq!(cache_settings().light_cache_capacity, DEFAULT_LIGHT_CACHE_CAPACITY);
+
+		apply_settings(&CacheSettings::default());
+		flush_caches();
+	}
+
+	#[test]
+	fn expected_cache_memory_scales_with_capacity() {
+		for mode in &[ComputeMode::Sync, ComputeMode::Mining] {
+			let one = expected_cache_memory(1, *mode);
+			assert_eq!(expected_cache_memory(0, *mode), 0);
+			assert_eq!(expected_cache_memory(3, *mode), 3 * one);
+		}
+
+		assert_eq!(expected_cache_memory(1, ComputeMode::Sync), randomx::CACHE_SIZE_BYTES);
+		assert_eq!(
+			expected_cache_memory(1, ComputeMode::Mining) - expected_cache_memory(1, ComputeMode::Sync),
+			randomx::DATASET_SIZE_BYTES,
+		);
+	}
+
+	#[test]
+	fn caches_are_evicted_to_cover_shortfall() {
+		let filled = || {
+			let mut cache = LruCache::<H256, u64>::new(3);
+			for i in 0..3 {
+				cache.insert(H256::repeat_byte(i), 10);
+			}
+			cache
+		};
+
+		let mut cache = filled();
+		assert_eq!(evict_lru_until::<u64>(&mut cache, 0, |size| *size), 0);
+		assert_eq!(cache.len(), 3);
+
+		let mut cache = filled();
+		assert_eq!(evict_lru_until::<u64>(&mut cache, 15, |size| *size), 20);
+		assert_eq!(cache.len(), 1);
+		assert!(cache.contains_key(&H256::repeat_byte(2)));
+
+		let mut cache = filled();
+		assert_eq!(evict_lru_until::<u64>(&mut cache, 100, |size| *size), 30);
+		assert!(cache.is_empty());
+	}
+
+	#[test]
+	fn persistent_memory_pressure_refuses_new_caches() {
+		struct FixedMemory(u64);
+
+		impl MemoryReporter for FixedMemory {
+			fn available_bytes(&self) -> Option<u64> {
+				Some(self.0)
+			}
+		}
+
+		let _guard = lock_shared_caches();
+		flush_caches();
+		let light = randomx::CACHE_SIZE_BYTES as u64;
+		let hash = |key_hash: &H256, mode| try_loop_raw(
+			key_hash,
+			mode,
+			|| (Vec::new(), ()),
+			|hash, ()| Loop::Break(Some(hash)),
+			1,
+		);
+
+		hash(&H256::repeat_byte(1), ComputeMode::Sync).unwrap();
+		let builds = local_vm_builds();
+
+		// One eviction covers the shortfall, and releases the machine holding it.
+		enable_memory_pressure_eviction(FixedMemory(light), 1);
+		hash(&H256::repeat_byte(2), ComputeMode::Sync).unwrap();
+		assert_eq!(cached_key_hashes(), vec![H256::repeat_byte(2)]);
+		assert_eq!(local_vm_builds(), builds + 1);
+
+		// Without room for a dataset, mining falls back to a light cache.
+		enable_memory_pressure_eviction(FixedMemory(2 * light), light);
+		hash(&H256::repeat_byte(3), ComputeMode::Mining).unwrap();
+		assert_eq!(FULL_SHARED_CACHES.lock().unwrap().len(), 0);
+		assert_eq!(cached_key_hashes(), vec![H256::repeat_byte(2), H256::repeat_byte(3)]);
+
+		// Evicting everything is not enough: the new cache is refused, and the
+		// held ones are kept.
+		enable_memory_pressure_eviction(FixedMemory(0), u64::max_value() / 2);
+		assert_eq!(hash(&H256::repeat_byte(4), ComputeMode::Sync), Err(ComputeError::MemoryPressure));
+		assert_eq!(cache_count(), 2);
+
+		disable_memory_pressure_eviction();
+		flush_caches();
+	}
+
+	#[test]
+	fn generation_bandwidth_is_recorded() {
+		struct SleepyChunks;
+
+		impl ChunkedGenerator for SleepyChunks {
+			fn chunk_count(&self) -> usize {
+				4
+			}
+
+			fn generate_chunk(&self, _index: usize) -> u64 {
+				std::thread::sleep(Duration::from_millis(10));
+				1_000_000
+			}
+		}
+
+		assert_eq!(GenerationRecord::new(H256::zero(), 2_000_000_000, Duration::from_secs(1)).bandwidth_gbps, 2.0);
+		assert_eq!(GenerationRecord::new(H256::zero(), 1, Duration::from_secs(0)).bandwidth_gbps, 0.0);
+
+		let record = generate_chunked(H256::repeat_byte(1), Arc::new(SleepyChunks), 1);
+		assert_eq!(record.bytes, 4_000_000);
+		assert!(record.duration >= Duration::from_millis(40));
+		assert_eq!(record.bandwidth_gbps, 4_000_000.0 / record.duration.as_secs_f64() / 1e9);
+		assert!(record.bandwidth_gbps <= 0.1);
+		assert!(generation_history().contains(&record));
+	}
+
+	#[test]
+	fn generation_pauses_while_too_hot() {
+		struct Cooling(Arc<AtomicUsize>);
+
+		impl TemperatureSource for Cooling {
+			fn celsius(&self) -> Option<f64> {
+				let reads = self.0.fetch_add(1, Ordering::SeqCst);
+				Some(if reads < 2 { 95.0 } else { 40.0 })
+			}
+		}
+
+		struct Chunks(Arc<AtomicUsize>);
+
+		impl ChunkedGenerator for Chunks {
+			fn chunk_count(&self) -> usize {
+				2
+			}
+
+			fn generate_chunk(&self, _index: usize) -> u64 {
+				assert!(self.0.load(Ordering::SeqCst) >= 3, "Generated before cooling down");
+				1_000
+			}
+		}
+
+		let reads = Arc::new(AtomicUsize::new(0));
+		let thermal = Arc::new(ThermalLimit::new(Cooling(reads.clone()), 80.0, Duration::from_millis(5)));
+
+		let record = generate_chunked_with(
+			H256::repeat_byte(2), Arc::new(Chunks(reads.clone())), 1, Some(thermal.clone()),
+		);
+
+		assert_eq!(thermal.pauses(), 2);
+		assert_eq!(record.bytes, 2_000);
+		assert!(record.duration >= Duration::from_millis(10));
+	}
+
+	#[test]
+	fn thermal_limit_pauses_full_dataset_generation() {
+		struct Hot(AtomicUsize);
+
+		impl TemperatureSource for Hot {
+			fn celsius(&self) -> Option<f64> {
+				Some(if self.0.fetch_add(1, Ordering::SeqCst) == 0 { 95.0 } else { 40.0 })
+			}
+		}
+
+		let _guard = lock_shared_caches();
+		flush_caches();
+		let key_hash = H256::repeat_byte(7);
+		let thermal = Arc::new(ThermalLimit::new(Hot(AtomicUsize::new(0)), 80.0, Duration::from_millis(1)));
+		apply_settings(&CacheSettings { thermal_limit: Some(thermal.clone()), ..Default::default() });
+
+		warm(&key_hash, ComputeMode::Mining);
+
+		assert_eq!(thermal.pauses(), 1);
+		let record = generation_history().pop().unwrap();
+		assert_eq!(record.key_hash, key_hash);
+		assert_eq!(record.bytes, randomx::DATASET_SIZE_BYTES as u64);
+		// A fresh thread has no full machine, and computes in light mode.
+		let light = std::thread::spawn(move || compute(&key_hash, &0u64, ComputeMode::Sync))
+			.join()
+			.unwrap();
+		assert_eq!(compute(&key_hash, &0u64, ComputeMode::Mining), light);
+
+		apply_settings(&CacheSettings::default());
+		flush_caches();
+	}
+
+	#[test]
+	fn seal_version_is_detected() {
+		let v1 = SealV1 {
+			difficulty: U256::max_value(),
+			nonce: H256::repeat_byte(1),
+		}.encode();
+		let v2 = SealV2 {
+			difficulty: U256::one(),
+			nonce: H256::repeat_byte(1),
+			signature: parse_signature(&[2u8; 64][..]).unwrap(),
+		}.encode();
+
+		assert_eq!(detect_seal_version(&v1[..]), Some(1));
+		assert_eq!(detect_seal_version(&v2[..]), Some(2));
+		assert_eq!(detect_seal_version(&v2[..100]), None);
+		assert_eq!(detect_seal_version(&[][..]), None);
+	}
+
+	#[test]
+	fn calculation_difficulty_encoding_is_pinned() {
+		fn encoded_difficulty(difficulty: U256) -> Vec<u8> {
+			let encoded = Calculation {
+				pre_hash: H256::repeat_byte(0x01),
+				difficulty,
+				nonce: H256::repeat_byte(0x02),
+			}.encode();
+
+			assert_eq!(encoded.len(), 96);
+			assert_eq!(&encoded[..32], &[0x01; 32][..]);
+			assert_eq!(&encoded[64..], &[0x02; 32][..]);
+
+			encoded[32..64].to_vec()
+		}
+
+		let mut one = [0u8; 32];
+		one[0] = 0x01;
+		assert_eq!(encoded_difficulty(U256::one()), one.to_vec());
+
+		let mut two_fifty_six = [0u8; 32];
+		two_fifty_six[1] = 0x01;
+		assert_eq!(encoded_difficulty(U256::from(256)), two_fifty_six.to_vec());
+
+		let mut high_limb = [0u8; 32];
+		high_limb[31] = 0x80;
+		assert_eq!(encoded_difficulty(U256::one() << 255), high_limb.to_vec());
+
+		assert_eq!(encoded_difficulty(U256::max_value()), vec![0xff; 32]);
+	}
+
+	#[cfg(feature = "tracing")]
+	#[test]
+	fn compute_emits_span() {
+		use tracing::{Event, Metadata, field::{Field, Visit}, span};
+
+		type Spans = Arc<Mutex<Vec<(&'static str, Vec<&'static str>)>>>;
+
+		struct FieldNames<'a>(&'a mut Vec<&'static str>);
+
+		impl<'a> Visit for FieldNames<'a> {
+			fn record_debug(&mut self, field: &Field, _value: &dyn fmt::Debug) {
+				self.0.push(field.name());
+			}
+		}
+
+		struct Recorder(Spans);
+
+		impl tracing::Subscriber for Recorder {
+			fn enabled(&self, _metadata: &Metadata) -> bool {
+				true
+			}
+
+			fn new_span(&self, attrs: &span::Attributes) -> span::Id {
+				let mut spans = self.0.lock().unwrap();
+				let mut fields = Vec::new();
+				attrs.record(&mut FieldNames(&mut fields));
+				spans.push((attrs.metadata().name(), fields));
+				span::Id::from_u64(spans.len() as u64)
+			}
+
+			fn record(&self, id: &span::Id, values: &span::Record) {
+				let mut spans = self.0.lock().unwrap();
+				values.record(&mut FieldNames(&mut spans[id.into_u64() as usize - 1].1));
+			}
+
+			fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) { }
+			fn event(&self, _event: &Event) { }
+			fn enter(&self, _span: &span::Id) { }
+			fn exit(&self, _span: &span::Id) { }
+		}
+
+		let _guard = lock_shared_caches();
+		let spans = Spans::default();
+		let key_hash = H256::repeat_byte(0x11);
+
+		tracing::subscriber::with_default(Recorder(spans.clone()), || {
+			RandomXBackend.work(&key_hash, &[1, 2, 3][..], ComputeMode::Sync);
+		});
+
+		let spans = spans.lock().unwrap();
+		let (_, fields) = spans.iter().find(|(name, _)| *name == "compute").expect("Compute span is emitted");
+		for field in &["key_hash", "mode", "vm_rebuilt"] {
+			assert!(fields.contains(field), "Missing field {}", field);
+		}
+	}
+
+	#[test]
+	fn oversized_input_is_refused_before_compute() {
+		let _guard = lock_shared_caches();
+		let builds = local_vm_builds();
+		let mut input = vec![0u8; 160];
+		// Extra data appended by a misused extension.
+		input.extend_from_slice(&[0xee; MAX_INPUT_SIZE]);
+
+		assert_eq!(
+			try_work(&H256::repeat_byte(0x21), &input[..], ComputeMode::Sync),
+			Err(ComputeError::InputTooLarge { size: 160 + MAX_INPUT_SIZE }),
+		);
+		assert_eq!(local_vm_builds(), builds);
+	}
+
+	#[test]
+	fn oversized_input_in_round_is_refused() {
+		let _guard = lock_shared_caches();
+		let mut inputs = vec![
+			vec![0u8; 160],
+			vec![0u8; MAX_INPUT_SIZE + 1],
+			vec![0u8; 160],
+		].into_iter();
+		let hashed = Cell::new(0);
+
+		let ret = try_loop_raw(
+			&H256::repeat_byte(0x22),
+			ComputeMode::Sync,
+			|| (inputs.next().unwrap(), ()),
+			|_, ()| {
+				hashed.set(hashed.get() + 1);
+				Loop::<Option<()>>::Continue
+			},
+			3,
+		);
+
+		assert_eq!(ret, Err(ComputeError::InputTooLarge { size: MAX_INPUT_SIZE + 1 }));
+		assert_eq!(hashed.get(), 0);
+	}
 }
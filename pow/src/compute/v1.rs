@@ -47,13 +47,17 @@ impl ComputeV1 {
 	}
 
 	pub fn seal_and_work(&self, mode: super::ComputeMode) -> (SealV1, H256) {
+		self.seal_and_work_with(&super::RandomXBackend, mode)
+	}
+
+	pub fn seal_and_work_with<W: super::WorkBackend + ?Sized>(
+		&self,
+		backend: &W,
+		mode: super::ComputeMode,
+	) -> (SealV1, H256) {
 		let input = self.input();
 
-		let work = super::compute::<Calculation>(
-			&self.key_hash,
-			&input,
-			mode,
-		);
+		let work = backend.work(&self.key_hash, &input.encode()[..], mode);
 
 		(SealV1 {
 			nonce: self.nonce,
@@ -16,12 +16,77 @@
 // You should have received a copy of the GNU General Public License
 // along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
 
+use std::fmt;
 use codec::{Encode, Decode};
-use sp_core::{H256, crypto::Pair, hashing::blake2_256};
+use sp_core::{H256, crypto::Pair, hexdisplay::HexDisplay};
 use kulupu_primitives::Difficulty;
 use super::Calculation;
 use crate::app;
 
+/// Length of an sr25519 signature, in bytes.
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Externally supplied signature does not have the expected length.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvalidSignatureLength {
+	pub expected: usize,
+	pub actual: usize,
+}
+
+impl fmt::Display for InvalidSignatureLength {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"Invalid signature length: expected {} bytes, got {} bytes",
+			self.expected,
+			self.actual,
+		)
+	}
+}
+
+impl std::error::Error for InvalidSignatureLength { }
+
+/// Parse a raw signature submitted by an external miner.
+pub fn parse_signature(raw: &[u8]) -> Result<app::Signature, InvalidSignatureLength> {
+	if raw.len() != SIGNATURE_LENGTH {
+		return Err(InvalidSignatureLength {
+			expected: SIGNATURE_LENGTH,
+			actual: raw.len(),
+		})
+	}
+
+	Ok(app::Signature::decode(&mut &raw[..])
+		.expect("Signature is a fixed 64 byte array and length is checked; qed"))
+}
+
+pub use kulupu_primitives::seal::{SigningDomain, SIGNING_DOMAIN_V2};
+
+/// Input fed to RandomX for a V2 computation.
+///
+/// This is consensus critical. It is the SCALE encoding of the calculation
+/// followed by the signature: `pre_hash` (32 bytes), `difficulty` (32 bytes,
+/// little-endian), `nonce` (32 bytes) and the signature (64 bytes).
+///
+/// The difficulty is part of the input, so the work for a nonce changes with
+/// the difficulty it is mined at, even for the same `pre_hash` and signature.
+pub fn randomx_input(calculation: &Calculation, signature: &app::Signature) -> Vec<u8> {
+	(calculation, signature).encode()
+}
+
+/// Exact RandomX input of a V2 computation, for reproducing it against a
+/// reference implementation.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ComputeInputDump {
+	/// Key the RandomX machine is built with.
+	pub key_hash: H256,
+	/// Encoded calculation, the first part of the input.
+	pub calculation_bytes: Vec<u8>,
+	/// Encoded signature, the second part of the input.
+	pub signature_bytes: Vec<u8>,
+	/// Lowercase hex of the whole input, without prefix.
+	pub full_input_hex: String,
+}
+
 #[derive(Clone, PartialEq, Eq, Encode, Decode, Debug)]
 pub struct SealV2 {
 	pub difficulty: Difficulty,
@@ -48,14 +113,47 @@ impl ComputeV2 {
 		(calculation, signature)
 	}
 
+	/// Dump the input a computation with `signature` feeds to RandomX, without
+	/// running the machine.
+	pub fn debug_input(&self, signature: app::Signature) -> ComputeInputDump {
+		let (calculation, signature) = self.input(signature);
+		let input = randomx_input(&calculation, &signature);
+
+		ComputeInputDump {
+			key_hash: self.key_hash,
+			calculation_bytes: calculation.encode(),
+			signature_bytes: signature.encode(),
+			full_input_hex: format!("{}", HexDisplay::from(&input)),
+		}
+	}
+
 	pub fn seal_and_work(&self, signature: app::Signature, mode: super::ComputeMode) -> (SealV2, H256) {
-		let input = self.input(signature.clone());
+		self.seal_and_work_with(&super::RandomXBackend, signature, mode)
+	}
 
-		let work = super::compute::<(Calculation, app::Signature)>(
-			&self.key_hash,
-			&input,
-			mode,
-		);
+	/// Like [`seal_and_work`](Self::seal_and_work), but returns an error instead
+	/// of panicking if no machine can be prepared.
+	pub fn try_compute(
+		&self,
+		signature: app::Signature,
+		mode: super::ComputeMode,
+	) -> Result<(SealV2, H256), super::ComputeError> {
+		let (calculation, signature) = self.input(signature);
+
+		let work = super::try_work(&self.key_hash, &randomx_input(&calculation, &signature)[..], mode)?;
+
+		Ok((self.seal(signature), work))
+	}
+
+	pub fn seal_and_work_with<W: super::WorkBackend + ?Sized>(
+		&self,
+		backend: &W,
+		signature: app::Signature,
+		mode: super::ComputeMode,
+	) -> (SealV2, H256) {
+		let (calculation, signature) = self.input(signature);
+
+		let work = backend.work(&self.key_hash, &randomx_input(&calculation, &signature)[..], mode);
 
 		(SealV2 {
 			nonce: self.nonce,
@@ -64,6 +162,31 @@ impl ComputeV2 {
 		}, work)
 	}
 
+	/// Compute with a machine managed by the caller, instead of the thread-local
+	/// one. The machine must have been built for `key_hash`.
+	pub fn compute_with_vm<M: randomx::WithCacheMode>(
+		&self,
+		vm: &mut randomx::VM<M>,
+		signature: app::Signature,
+	) -> (SealV2, H256) {
+		let (calculation, signature) = self.input(signature);
+
+		let work = H256::from(vm.calculate(&randomx_input(&calculation, &signature)[..]));
+
+		(self.seal(signature), work)
+	}
+
+	/// Compute with a fresh machine built from a caller-owned cache, bypassing
+	/// the shared caches. The cache must be built for `key_hash`.
+	pub fn compute_with_cache<M: randomx::WithCacheMode>(
+		&self,
+		cache: std::sync::Arc<randomx::Cache<M>>,
+		signature: app::Signature,
+	) -> (SealV2, H256) {
+		let mut vm = randomx::VM::new(cache, super::global_config());
+		self.compute_with_vm(&mut vm, signature)
+	}
+
 	pub fn seal(&self, signature: app::Signature) -> SealV2 {
 		SealV2 {
 			nonce: self.nonce,
@@ -72,18 +195,25 @@ impl ComputeV2 {
 		}
 	}
 
-	fn signing_message(&self) -> [u8; 32] {
-		let calculation = Calculation {
+	fn calculation(&self) -> Calculation {
+		Calculation {
 			difficulty: self.difficulty,
 			pre_hash: self.pre_hash,
 			nonce: self.nonce,
-		};
+		}
+	}
 
-		blake2_256(&calculation.encode()[..])
+	/// Message signed by the author, for signers other than a local pair.
+	pub fn signing_message(&self, domain: SigningDomain) -> [u8; 32] {
+		kulupu_primitives::seal::signing_message(&self.calculation(), domain)
 	}
 
 	pub fn sign(&self, pair: &app::Pair) -> app::Signature {
-		let hash = self.signing_message();
+		self.sign_in(pair, SigningDomain::Legacy)
+	}
+
+	pub fn sign_in(&self, pair: &app::Pair, domain: SigningDomain) -> app::Signature {
+		let hash = self.signing_message(domain);
 		pair.sign(&hash[..])
 	}
 
@@ -92,11 +222,177 @@ impl ComputeV2 {
 		signature: &app::Signature,
 		public: &app::Public,
 	) -> bool {
-		let hash = self.signing_message();
-		app::Pair::verify(
-			signature,
-			&hash[..],
-			public,
+		self.verify_in(signature, public, SigningDomain::Legacy)
+	}
+
+	pub fn verify_in(
+		&self,
+		signature: &app::Signature,
+		public: &app::Public,
+		domain: SigningDomain,
+	) -> bool {
+		// The same check as in the runtime.
+		kulupu_primitives::seal::verify_signature(
+			&self.calculation(),
+			signature.as_ref(),
+			public.as_ref(),
+			domain,
 		)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn randomx_input_is_pinned() {
+		let calculation = Calculation {
+			pre_hash: H256::repeat_byte(0x01),
+			difficulty: Difficulty::from(0x0203),
+			nonce: H256::repeat_byte(0x04),
+		};
+		let signature = parse_signature(&[0x05; 64][..]).unwrap();
+
+		let mut expected = Vec::new();
+		expected.extend_from_slice(&[0x01; 32]);
+		expected.extend_from_slice(&[0x03, 0x02]);
+		expected.extend_from_slice(&[0x00; 30]);
+		expected.extend_from_slice(&[0x04; 32]);
+		expected.extend_from_slice(&[0x05; 64]);
+
+		assert_eq!(randomx_input(&calculation, &signature), expected);
+	}
+
+	#[test]
+	fn signing_domains_are_separated() {
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 {
+			key_hash: H256::repeat_byte(0x01),
+			pre_hash: H256::repeat_byte(0x02),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(0x03),
+		};
+
+		let legacy = compute.sign_in(&pair, SigningDomain::Legacy);
+		let separated = compute.sign_in(&pair, SigningDomain::V2);
+
+		assert!(compute.verify_in(&legacy, &pair.public(), SigningDomain::Legacy));
+		assert!(compute.verify_in(&separated, &pair.public(), SigningDomain::V2));
+		assert!(!compute.verify_in(&legacy, &pair.public(), SigningDomain::V2));
+		assert!(!compute.verify_in(&separated, &pair.public(), SigningDomain::Legacy));
+	}
+
+	#[test]
+	fn work_depends_on_difficulty() {
+		use crate::compute::ComputeMode;
+
+		let _guard = crate::mock::lock_shared_caches();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 {
+			key_hash: H256::repeat_byte(0x01),
+			pre_hash: H256::repeat_byte(0x02),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(0x03),
+		};
+		let harder = ComputeV2 { difficulty: Difficulty::from(2), ..compute.clone() };
+
+		// Keep the signature fixed, so that only the calculation differs.
+		let signature = compute.sign(&pair);
+		let (_, work) = compute.seal_and_work(signature.clone(), ComputeMode::Sync);
+		let (_, harder_work) = harder.seal_and_work(signature, ComputeMode::Sync);
+
+		assert_ne!(work, harder_work);
+	}
+
+	#[test]
+	fn compute_with_vm_matches_thread_local_machine() {
+		use crate::compute::{ComputeMode, global_config};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 {
+			key_hash: H256::repeat_byte(0x01),
+			pre_hash: H256::repeat_byte(0x02),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(0x03),
+		};
+		let signature = compute.sign(&pair);
+
+		let cache = std::sync::Arc::new(
+			randomx::LightCache::new(&compute.key_hash[..], global_config())
+		);
+		let mut vm = randomx::LightVM::new(cache, global_config());
+
+		assert_eq!(
+			compute.compute_with_vm(&mut vm, signature.clone()),
+			compute.seal_and_work(signature, ComputeMode::Sync),
+		);
+	}
+
+	#[test]
+	fn compute_with_cache_matches_shared_caches() {
+		use crate::compute::{ComputeMode, global_config};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 {
+			key_hash: H256::repeat_byte(0x01),
+			pre_hash: H256::repeat_byte(0x02),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(0x03),
+		};
+		let signature = compute.sign(&pair);
+		let caches = crate::compute::cache_count();
+
+		let cache = std::sync::Arc::new(
+			randomx::LightCache::new(&compute.key_hash[..], global_config())
+		);
+		let computed = compute.compute_with_cache(cache, signature.clone());
+		assert_eq!(crate::compute::cache_count(), caches);
+
+		assert_eq!(computed, compute.seal_and_work(signature, ComputeMode::Sync));
+	}
+
+	#[test]
+	fn debug_input_matches_machine_input() {
+		use crate::compute::ComputeMode;
+		use crate::mock::RecordingBackend;
+
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 {
+			key_hash: H256::repeat_byte(0x01),
+			pre_hash: H256::repeat_byte(0x02),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(0x03),
+		};
+		let signature = compute.sign(&pair);
+
+		let backend = RecordingBackend::default();
+		compute.seal_and_work_with(&backend, signature.clone(), ComputeMode::Sync);
+
+		let dump = compute.debug_input(signature);
+		let decoded = (0..dump.full_input_hex.len()).step_by(2)
+			.map(|i| u8::from_str_radix(&dump.full_input_hex[i..i + 2], 16).unwrap())
+			.collect::<Vec<_>>();
+
+		assert_eq!(backend.inputs(), vec![(compute.key_hash, decoded.clone())]);
+		assert_eq!(dump.key_hash, compute.key_hash);
+		assert_eq!([&dump.calculation_bytes[..], &dump.signature_bytes[..]].concat(), decoded);
+		assert_eq!(dump.signature_bytes.len(), SIGNATURE_LENGTH);
+	}
+
+	#[test]
+	fn parse_signature_checks_length() {
+		assert!(parse_signature(&[0u8; 64][..]).is_ok());
+
+		for len in &[63, 65] {
+			let err = parse_signature(&vec![0u8; *len][..]).unwrap_err();
+			assert_eq!(err, InvalidSignatureLength { expected: 64, actual: *len });
+			assert_eq!(
+				err.to_string(),
+				format!("Invalid signature length: expected 64 bytes, got {} bytes", len),
+			);
+		}
+	}
+}
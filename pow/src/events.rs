@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Machine-readable mining events, for consumption by pool software.
+
+use parking_lot::Mutex;
+use serde::{Serialize, Deserialize};
+use sp_core::H256;
+use kulupu_primitives::Difficulty;
+use crate::TargetChecker;
+
+/// Event emitted by the mining path.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum MiningEvent {
+	/// Mining started on a new job.
+	#[serde(rename_all = "camelCase")]
+	JobStarted {
+		pre_hash: H256,
+		key_hash: H256,
+		difficulty: Difficulty,
+	},
+	/// Work meeting the share difficulty, but not the network difficulty.
+	#[serde(rename_all = "camelCase")]
+	ShareFound {
+		pre_hash: H256,
+		nonce: H256,
+		work: H256,
+	},
+	/// Work meeting the network difficulty.
+	#[serde(rename_all = "camelCase")]
+	BlockFound {
+		pre_hash: H256,
+		nonce: H256,
+		work: H256,
+	},
+}
+
+/// Emits mining events to a sink. Can be shared between mining threads.
+pub struct MiningEvents {
+	sink: Box<dyn Fn(MiningEvent) + Send + Sync>,
+	share_difficulty: Option<Difficulty>,
+	last_job: Mutex<Option<(H256, H256, Difficulty)>>,
+}
+
+impl MiningEvents {
+	pub fn new<F: Fn(MiningEvent) + Send + Sync + 'static>(sink: F) -> Self {
+		Self {
+			sink: Box::new(sink),
+			share_difficulty: None,
+			last_job: Mutex::new(None),
+		}
+	}
+
+	/// Emit share events for work meeting `share_difficulty`.
+	pub fn with_share_difficulty(mut self, share_difficulty: Difficulty) -> Self {
+		self.share_difficulty = Some(share_difficulty);
+		self
+	}
+
+	/// Emit a job started event, if the job differs from the last one.
+	pub(crate) fn job(&self, pre_hash: &H256, key_hash: &H256, difficulty: Difficulty) {
+		let job = (*pre_hash, *key_hash, difficulty);
+
+		{
+			let mut last_job = self.last_job.lock();
+			if last_job.as_ref() == Some(&job) {
+				return
+			}
+			*last_job = Some(job);
+		}
+
+		(self.sink)(MiningEvent::JobStarted {
+			pre_hash: *pre_hash,
+			key_hash: *key_hash,
+			difficulty,
+		});
+	}
+
+	/// Emit the event for a calculated work, if any, checking it against
+	/// targets with `target`.
+	pub(crate) fn work(
+		&self,
+		pre_hash: &H256,
+		nonce: &H256,
+		work: &H256,
+		difficulty: Difficulty,
+		target: &dyn TargetChecker,
+	) {
+		if target.is_valid(work, difficulty) {
+			(self.sink)(MiningEvent::BlockFound {
+				pre_hash: *pre_hash,
+				nonce: *nonce,
+				work: *work,
+			});
+		} else if self.share_difficulty.map(|d| target.is_valid(work, d)).unwrap_or(false) {
+			(self.sink)(MiningEvent::ShareFound {
+				pre_hash: *pre_hash,
+				nonce: *nonce,
+				work: *work,
+			});
+		}
+	}
+}
@@ -18,25 +18,60 @@
 
 pub mod compute;
 pub mod weak_sub;
+pub mod events;
+pub mod pool;
+pub mod affinity;
+pub mod breaker;
+pub mod luck;
+pub mod verify_pool;
+pub mod shutdown;
+#[cfg(feature = "test-vectors")]
+pub mod vectors;
+#[cfg(test)]
+mod mock;
 
-use std::{sync::Arc, time::{Duration, Instant}};
+use std::{
+	fmt,
+	sync::{Arc, atomic::{AtomicBool, Ordering}},
+	thread::{self, ThreadId},
+	time::{Duration, Instant},
+	cell::Cell,
+	collections::BTreeMap,
+};
 use parking_lot::Mutex;
 use codec::{Encode, Decode};
 use sp_core::{U256, H256, blake2_256};
-use sp_api::ProvideRuntimeApi;
+use sp_api::{ProvideRuntimeApi, ApiExt};
 use sp_runtime::generic::BlockId;
 use sp_runtime::traits::{
-	Block as BlockT, Header as HeaderT, UniqueSaturatedInto,
+	Block as BlockT, Header as HeaderT, UniqueSaturatedInto, Zero,
 };
 use sp_consensus_pow::{Seal as RawSeal, DifficultyApi};
 use sc_consensus_pow::PowAlgorithm;
 use sc_client_api::{blockchain::HeaderBackend, backend::AuxStore};
 use sc_keystore::LocalKeystore;
-use kulupu_primitives::{Difficulty, AlgorithmApi};
-use rand::{SeedableRng, thread_rng, rngs::SmallRng};
+use sp_keystore::SyncCryptoStore;
+use kulupu_primitives::{
+	Difficulty, AlgorithmApi, KeyHashApi, ExpectedKeyHashApi, KeyHashParams, DEFAULT_KEY_HASH_PARAMS,
+	digest::{PreDigestFormat, split_pre_digest},
+};
+use rand::{Rng, RngCore, SeedableRng, thread_rng, rngs::{SmallRng, StdRng}};
+use lru_cache::LruCache;
 use log::*;
+use serde::{Serialize, Deserialize};
+
+use crate::compute::{
+	ComputeV1, ComputeV2, SealV1, SealV2, ComputeMode, WorkBackend, WorkAlgo, SigningDomain,
+};
+use crate::events::MiningEvents;
+use crate::breaker::{CircuitBreaker, BreakerError};
+use crate::verify_pool::VerifyPool;
+use crate::luck::{NearMisses, TimeToBlockWarning};
+use crate::shutdown::Shutdown;
 
-use crate::compute::{ComputeV1, ComputeV2, SealV1, SealV2, ComputeMode};
+/// Log target of consensus and mining logs. RandomX machine and cache logs use
+/// [`compute::LOG_TARGET`].
+pub const LOG_TARGET: &str = "kulupu-pow";
 
 pub mod app {
 	use sp_application_crypto::{app_crypto, sr25519};
@@ -47,6 +82,9 @@ pub mod app {
 }
 
 /// Checks whether the given hash is above difficulty.
+///
+/// Difficulty one accepts every hash, as no 256-bit hash overflows when
+/// multiplied by one.
 pub fn is_valid_hash(hash: &H256, difficulty: Difficulty) -> bool {
 	let num_hash = U256::from(&hash[..]);
 	let (_, overflowed) = num_hash.overflowing_mul(difficulty);
@@ -54,90 +92,172 @@ pub fn is_valid_hash(hash: &H256, difficulty: Difficulty) -> bool {
 	!overflowed
 }
 
-pub fn key_hash<B, C>(
-	client: &C,
-	parent: &BlockId<B>
-) -> Result<H256, sc_consensus_pow::Error<B>> where
-	B: BlockT<Hash=H256>,
-	C: HeaderBackend<B>,
-{
-	const PERIOD: u64 = 4096; // ~2.8 days
-	const OFFSET: u64 = 128;  // 2 hours
+/// Check of work against the target of a difficulty, for networks mapping work
+/// to targets differently than [`is_valid_hash`].
+pub trait TargetChecker: Send + Sync {
+	fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool;
+}
 
-	let parent_header = client.header(*parent)
-		.map_err(|e| sc_consensus_pow::Error::Environment(
-			format!("Client execution error: {:?}", e)
-		))?
-		.ok_or(sc_consensus_pow::Error::Environment(
-			"Parent header not found".to_string()
-		))?;
-	let parent_number = UniqueSaturatedInto::<u64>::unique_saturated_into(*parent_header.number());
+/// Target check of Kulupu, [`is_valid_hash`].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DefaultTargetChecker;
 
-	let mut key_number = parent_number.saturating_sub(parent_number % PERIOD);
-	if parent_number.saturating_sub(key_number) < OFFSET {
-		key_number = key_number.saturating_sub(PERIOD);
+impl TargetChecker for DefaultTargetChecker {
+	fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool {
+		is_valid_hash(work, difficulty)
 	}
+}
 
-	let mut current = parent_header;
-	while UniqueSaturatedInto::<u64>::unique_saturated_into(*current.number()) != key_number {
-		current = client.header(BlockId::Hash(*current.parent_hash()))
-			.map_err(|e| sc_consensus_pow::Error::Environment(
-				format!("Client execution error: {:?}", e)
-			))?
-			.ok_or(sc_consensus_pow::Error::Environment(
-				format!("Block with hash {:?} not found", current.hash())
-			))?;
+/// Source of the difficulty to mine and verify children of a block at,
+/// overriding the runtime difficulty.
+pub trait DifficultySource: Send + Sync {
+	/// Difficulty for children of `parent`, or `None` to use the runtime one.
+	fn difficulty(&self, parent: &H256) -> Option<Difficulty>;
+}
+
+/// Difficulty of one, where any hash is valid, so that blocks are produced
+/// instantly. Only for integration tests and development networks.
+pub struct TestDifficulty;
+
+impl DifficultySource for TestDifficulty {
+	fn difficulty(&self, _parent: &H256) -> Option<Difficulty> {
+		Some(U256::one())
 	}
+}
 
-	Ok(current.hash())
+/// What fetching the runtime difficulty does when the runtime errors, such as
+/// when the state right after an import is not yet available.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum DifficultyFallback {
+	/// Fail with the runtime error.
+	Fail,
+	/// Retry up to `attempts` more times, waiting `delay` in between.
+	Retry { attempts: u32, delay: Duration },
+	/// Use the last difficulty fetched successfully, if any.
+	LastKnown,
 }
 
-pub enum RandomXAlgorithmVersion {
-	V1,
-	V2,
+impl Default for DifficultyFallback {
+	fn default() -> Self {
+		DifficultyFallback::Fail
+	}
 }
 
-pub struct RandomXAlgorithm<C> {
-	client: Arc<C>,
+/// Parents whose last fetched difficulty is remembered for
+/// [`DifficultyFallback::LastKnown`].
+const LAST_KNOWN_DIFFICULTIES: usize = 16;
+
+/// Fetches the difficulty for children of a parent according to a
+/// [`DifficultyFallback`], remembering the last difficulty fetched per parent.
+pub struct DifficultyFetcher {
+	fallback: DifficultyFallback,
+	last_known: Mutex<LruCache<H256, Difficulty>>,
 }
 
-impl<C> RandomXAlgorithm<C> {
-	pub fn new(client: Arc<C>) -> Self {
-		Self {
-			client,
+impl DifficultyFetcher {
+	pub fn new(fallback: DifficultyFallback) -> Self {
+		Self { fallback, last_known: Mutex::new(LruCache::new(LAST_KNOWN_DIFFICULTIES)) }
+	}
+
+	/// Fetch the difficulty for children of `parent` with `fetch`, applying
+	/// the fallback on errors.
+	pub fn fetch<E: fmt::Debug, F: FnMut() -> Result<Difficulty, E>>(
+		&self,
+		parent: &H256,
+		mut fetch: F,
+	) -> Result<Difficulty, E> {
+		let (retries, delay) = match self.fallback {
+			DifficultyFallback::Retry { attempts, delay } => (attempts, delay),
+			_ => (0, Duration::default()),
+		};
+		let mut attempt = 0;
+
+		loop {
+			let err = match fetch() {
+				Ok(difficulty) => {
+					self.last_known.lock().insert(*parent, difficulty);
+					return Ok(difficulty)
+				},
+				Err(err) => err,
+			};
+
+			if attempt < retries {
+				attempt += 1;
+				warn!(
+					target: LOG_TARGET,
+					"Fetching difficulty on top of {} failed, retrying ({}/{}): {:?}",
+					parent,
+					attempt,
+					retries,
+					err,
+				);
+				thread::sleep(delay);
+				continue
+			}
+
+			if self.fallback == DifficultyFallback::LastKnown {
+				if let Some(difficulty) = self.last_known.lock().get_mut(parent).cloned() {
+					warn!(
+						target: LOG_TARGET,
+						"Fetching difficulty on top of {} failed, using last known difficulty {}: {:?}",
+						parent,
+						difficulty,
+						err,
+					);
+					return Ok(difficulty)
+				}
+			}
+
+			return Err(err)
 		}
 	}
 }
 
-impl<C> Clone for RandomXAlgorithm<C> {
-	fn clone(&self) -> Self {
-		Self {
-			client: self.client.clone(),
-		}
+impl Default for DifficultyFetcher {
+	fn default() -> Self {
+		Self::new(DifficultyFallback::default())
 	}
 }
 
-impl<B: BlockT<Hash=H256>, C> PowAlgorithm<B> for RandomXAlgorithm<C> where
-	C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
-	C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B>,
-{
+/// Algorithm for the mining worker, applying a [`DifficultyFallback`] when
+/// fetching the difficulty of the next block fails, and otherwise the same as
+/// the wrapped one.
+///
+/// Block import must keep using the wrapped algorithm: the difficulty blocks
+/// are verified at is consensus, and must never come from a fallback.
+#[derive(Clone)]
+pub struct MiningDifficulty<A> {
+	inner: A,
+	fetcher: Arc<DifficultyFetcher>,
+}
+
+impl<A> MiningDifficulty<A> {
+	pub fn new(inner: A, fallback: DifficultyFallback) -> Self {
+		Self { inner, fetcher: Arc::new(DifficultyFetcher::new(fallback)) }
+	}
+
+	pub fn inner(&self) -> &A {
+		&self.inner
+	}
+}
+
+impl<B: BlockT<Hash=H256>, A: PowAlgorithm<B, Difficulty=Difficulty>> PowAlgorithm<B> for MiningDifficulty<A> {
 	type Difficulty = Difficulty;
 
 	fn difficulty(&self, parent: H256) -> Result<Difficulty, sc_consensus_pow::Error<B>> {
-		let difficulty = self.client.runtime_api().difficulty(&BlockId::Hash(parent))
-			.map_err(|e| sc_consensus_pow::Error::Environment(
-				format!("Fetching difficulty from runtime failed: {:?}", e)
-			));
-
-		difficulty
+		self.fetcher.fetch(&parent, || self.inner.difficulty(parent))
 	}
 
-	fn break_tie(
+	fn preliminary_verify(
 		&self,
-		own_seal: &RawSeal,
-		new_seal: &RawSeal,
-	) -> bool {
-		blake2_256(&own_seal[..]) > blake2_256(&new_seal[..])
+		pre_hash: &H256,
+		seal: &RawSeal,
+	) -> Result<Option<bool>, sc_consensus_pow::Error<B>> {
+		self.inner.preliminary_verify(pre_hash, seal)
+	}
+
+	fn break_tie(&self, own_seal: &RawSeal, new_seal: &RawSeal) -> bool {
+		self.inner.break_tie(own_seal, new_seal)
 	}
 
 	fn verify(
@@ -148,272 +268,5208 @@ impl<B: BlockT<Hash=H256>, C> PowAlgorithm<B> for RandomXAlgorithm<C> where
 		seal: &RawSeal,
 		difficulty: Difficulty,
 	) -> Result<bool, sc_consensus_pow::Error<B>> {
-		let version_raw = self.client.runtime_api().identifier(parent)
-			.map_err(|e| sc_consensus_pow::Error::Environment(
-				format!("Fetching identifier from runtime failed: {:?}", e))
-			)?;
-
-		let version = match version_raw {
-			kulupu_primitives::ALGORITHM_IDENTIFIER_V1 => RandomXAlgorithmVersion::V1,
-			kulupu_primitives::ALGORITHM_IDENTIFIER_V2 => RandomXAlgorithmVersion::V2,
-			_ => return Err(sc_consensus_pow::Error::<B>::Other(
-				"Unknown algorithm identifier".to_string(),
-			)),
-		};
-
-		let key_hash = key_hash(self.client.as_ref(), parent)?;
-
-		match version {
-			RandomXAlgorithmVersion::V1 => {
-				let seal = match SealV1::decode(&mut &seal[..]) {
-					Ok(seal) => seal,
-					Err(_) => return Ok(false),
-				};
+		self.inner.verify(parent, pre_hash, pre_digest, seal, difficulty)
+	}
+}
 
-				let compute = ComputeV1 {
-					key_hash,
-					difficulty,
-					pre_hash: *pre_hash,
-					nonce: seal.nonce,
-				};
+/// Cumulative work of a chain segment, with each block contributing its
+/// difficulty. Blocks whose seal was not verified as valid contribute nothing.
+/// Saturates instead of overflowing.
+pub fn segment_work(seals: &[(Difficulty, bool)]) -> U256 {
+	seals.iter()
+		.filter(|(_, valid)| *valid)
+		.fold(U256::zero(), |work, (difficulty, _)| work.saturating_add(*difficulty))
+}
 
-				// No pre-digest check is needed for V1 algorithm.
+/// Probability that a single hash is valid for `difficulty`, that is the
+/// target of [`is_valid_hash`] over `2^256`. Zero difficulty accepts any hash.
+pub fn solve_probability(difficulty: Difficulty) -> f64 {
+	if difficulty.is_zero() {
+		return 1.0
+	}
 
-				let (computed_seal, computed_work) = compute.seal_and_work(ComputeMode::Sync);
+	let difficulty = difficulty.0.iter().rev()
+		.fold(0.0, |acc, limb| acc * 18_446_744_073_709_551_616.0 + *limb as f64);
 
-				if computed_seal != seal {
-					return Ok(false)
-				}
+	1.0 / difficulty
+}
 
-				if !is_valid_hash(&computed_work, difficulty) {
-					return Ok(false)
-				}
+/// Number of the block whose hash is the RandomX key for children of
+/// `parent_number`.
+pub fn key_number(parent_number: u64) -> u64 {
+	key_number_with(&DEFAULT_KEY_HASH_PARAMS, parent_number)
+}
 
-				Ok(true)
-			},
-			RandomXAlgorithmVersion::V2 => {
-				let seal = match SealV2::decode(&mut &seal[..]) {
-					Ok(seal) => seal,
-					Err(_) => return Ok(false),
-				};
+/// Like [`key_number`], with a custom key block schedule. A zero period always
+/// selects the genesis block.
+///
+/// The key block is the latest multiple of the period at least `offset` blocks
+/// below the parent. Near genesis no such multiple exists, and the rollback by
+/// one period saturates at the genesis block. Every parent below
+/// `period + offset`, including the genesis block itself, is keyed by genesis.
+pub fn key_number_with(params: &KeyHashParams, parent_number: u64) -> u64 {
+	if params.period == 0 {
+		return 0
+	}
 
-				let compute = ComputeV2 {
-					key_hash,
-					difficulty,
-					pre_hash: *pre_hash,
-					nonce: seal.nonce,
-				};
-
-				let pre_digest = match pre_digest {
-					Some(pre_digest) => pre_digest,
-					None => return Ok(false),
-				};
-
-				let author = match app::Public::decode(&mut &pre_digest[..]) {
-					Ok(author) => author,
-					Err(_) => return Ok(false),
-				};
-
-				if !compute.verify(&seal.signature, &author) {
-					return Ok(false)
-				}
+	let mut key_number = parent_number.saturating_sub(parent_number % params.period);
+	if parent_number.saturating_sub(key_number) < params.offset {
+		key_number = key_number.saturating_sub(params.period);
+	}
 
-				let (computed_seal, computed_work) = compute.seal_and_work(
-					seal.signature.clone(),
-					ComputeMode::Sync,
-				);
+	key_number
+}
 
-				if computed_seal != seal {
-					return Ok(false)
-				}
+/// Key block schedule defined by the runtime at `at`. Runtimes predating
+/// [`KeyHashApi`] use the default schedule.
+pub fn key_hash_params<B, C>(
+	client: &C,
+	at: &BlockId<B>,
+) -> Result<KeyHashParams, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: ProvideRuntimeApi<B>,
+	C::Api: KeyHashApi<B>,
+{
+	let runtime_api = client.runtime_api();
 
-				if !is_valid_hash(&computed_work, difficulty) {
-					return Ok(false)
-				}
+	let has_api = runtime_api.has_api::<dyn KeyHashApi<B>>(at)
+		.map_err(|e| sc_consensus_pow::Error::Environment(
+			format!("Checking key hash API of runtime failed: {:?}", e)
+		))?;
 
-				Ok(true)
-			},
-		}
+	if !has_api {
+		return Ok(DEFAULT_KEY_HASH_PARAMS)
 	}
-}
 
-pub struct Stats {
-	last_clear: Instant,
-	last_display: Instant,
-	round: u32,
-}
-
-impl Stats {
-	pub fn new() -> Stats {
-		Self {
-			last_clear: Instant::now(),
-			last_display: Instant::now(),
-			round: 0,
-		}
-	}
+	runtime_api.key_hash_params(at)
+		.map_err(|e| sc_consensus_pow::Error::Environment(
+			format!("Fetching key hash params from runtime failed: {:?}", e)
+		))
 }
 
-pub fn mine<B, C>(
+/// Key hash recorded by the runtime at `at` for its children. Runtimes without
+/// [`ExpectedKeyHashApi`] record none.
+pub fn expected_key_hash<B, C>(
 	client: &C,
-	keystore: &LocalKeystore,
-	parent: &BlockId<B>,
-	pre_hash: &H256,
-	pre_digest: Option<&[u8]>,
-	difficulty: Difficulty,
-	round: u32,
-	stats: &Arc<Mutex<Stats>>,
-) -> Result<Option<RawSeal>, sc_consensus_pow::Error<B>> where
+	at: &BlockId<B>,
+) -> Result<Option<H256>, sc_consensus_pow::Error<B>> where
 	B: BlockT<Hash=H256>,
-	C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
-	C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B>,
+	C: ProvideRuntimeApi<B>,
+	C::Api: ExpectedKeyHashApi<B>,
 {
-	let version_raw = client.runtime_api().identifier(parent)
-		.map_err(|e| sc_consensus_pow::Error::Environment(
-			format!("Fetching identifier from runtime failed: {:?}", e))
-		)?;
-
-	let version = match version_raw {
-		kulupu_primitives::ALGORITHM_IDENTIFIER_V1 => RandomXAlgorithmVersion::V1,
-		kulupu_primitives::ALGORITHM_IDENTIFIER_V2 => RandomXAlgorithmVersion::V2,
-		_ => return Err(sc_consensus_pow::Error::<B>::Other(
-			"Unknown algorithm identifier".to_string()
-		)),
-	};
+	let runtime_api = client.runtime_api();
 
-	let mut rng = SmallRng::from_rng(&mut thread_rng())
+	let has_api = runtime_api.has_api::<dyn ExpectedKeyHashApi<B>>(at)
 		.map_err(|e| sc_consensus_pow::Error::Environment(
-			format!("Initialize RNG failed for mining: {:?}", e)
+			format!("Checking expected key hash API of runtime failed: {:?}", e)
 		))?;
-	let key_hash = key_hash(client, parent)?;
 
-	let pre_digest = pre_digest.ok_or(sc_consensus_pow::Error::<B>::Other(
-		"Unable to mine: pre-digest not set".to_string(),
-	))?;
+	if !has_api {
+		return Ok(None)
+	}
 
-	let author = app::Public::decode(&mut &pre_digest[..]).map_err(|_| {
-		sc_consensus_pow::Error::<B>::Other(
-			"Unable to mine: author pre-digest decoding failed".to_string(),
-		)
-	})?;
+	runtime_api.expected_key_hash(at)
+		.map_err(|e| sc_consensus_pow::Error::Environment(
+			format!("Fetching expected key hash from runtime failed: {:?}", e)
+		))
+}
 
-	let pair = keystore.key_pair::<app::Pair>(
-		&author,
-	).map_err(|_| sc_consensus_pow::Error::<B>::Other(
-		"Unable to mine: fetch pair from author failed".to_string(),
-	))?;
+/// Whether the key hash walked on the chain agrees with the one recorded by
+/// the runtime, if any.
+pub fn matches_expected_key_hash(walked: &H256, recorded: Option<H256>) -> bool {
+	recorded.map(|recorded| recorded == *walked).unwrap_or(true)
+}
 
-	let maybe_seal = match version {
-		RandomXAlgorithmVersion::V1 => {
-			compute::loop_raw(
-				&key_hash,
-				ComputeMode::Mining,
-				|| {
-					let nonce = H256::random_using(&mut rng);
+/// Key block selected for a parent. Logged on verification, so that nodes
+/// disagreeing on a key hash can be compared.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyHashSelection {
+	pub parent_hash: H256,
+	pub parent_number: u64,
+	pub key_number: u64,
+	pub key_hash: H256,
+}
 
-					let compute = ComputeV1 {
-						key_hash,
-						difficulty,
-						pre_hash: *pre_hash,
-						nonce,
-					};
+impl fmt::Display for KeyHashSelection {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"parent {} (#{}) selects key block #{} with key hash {}",
+			self.parent_hash,
+			self.parent_number,
+			self.key_number,
+			self.key_hash,
+		)
+	}
+}
 
-					(compute.input().encode(), compute)
-				},
-				|work, compute| {
-					if is_valid_hash(&work, compute.difficulty) {
-						let seal = compute.seal();
-						compute::Loop::Break(Some(seal.encode()))
-					} else {
-						compute::Loop::Continue
-					}
-				},
-				round as usize,
-			)
-		},
-		RandomXAlgorithmVersion::V2 => {
-			compute::loop_raw(
-				&key_hash,
-				ComputeMode::Mining,
-				|| {
-					let nonce = H256::random_using(&mut rng);
+pub fn key_hash<B, C>(
+	client: &C,
+	parent: &BlockId<B>
+) -> Result<H256, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	key_hash_selection(client, parent).map(|selection| selection.key_hash)
+}
 
-					let compute = ComputeV2 {
-						key_hash,
-						difficulty,
-						pre_hash: *pre_hash,
-						nonce,
-					};
+/// Key hash block `number` of the canonical chain is mined with, that is the
+/// key for children of its parent, under the schedule of the runtime at the
+/// parent. Meant for tools tagging every block with its epoch key. The genesis
+/// block is not mined, and has none.
+pub fn key_hash_for_number<B, C>(
+	client: &C,
+	number: u64,
+) -> Result<H256, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B> + ProvideRuntimeApi<B>,
+	C::Api: KeyHashApi<B>,
+{
+	let parent_number = number.checked_sub(1).ok_or_else(|| {
+		sc_consensus_pow::Error::<B>::Other("Genesis block has no key hash".to_string())
+	})?;
+	let parent = BlockId::<B>::Number(parent_number.unique_saturated_into());
+	let params = key_hash_params(client, &parent)?;
 
-					let signature = compute.sign(&pair);
+	key_hash_for_number_with(client, number, &params)
+}
 
-					(compute.input(signature.clone()).encode(), (compute, signature))
-				},
-				|work, (compute, signature)| {
-					if is_valid_hash(&work, difficulty) {
-						let seal = compute.seal(signature);
-						compute::Loop::Break(Some(seal.encode()))
-					} else {
-						compute::Loop::Continue
-					}
-				},
-				round as usize,
-			)
-		},
-	};
+pub fn key_hash_for_number_with<B, C>(
+	client: &C,
+	number: u64,
+	params: &KeyHashParams,
+) -> Result<H256, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	let parent_number = number.checked_sub(1).ok_or_else(|| {
+		sc_consensus_pow::Error::<B>::Other("Genesis block has no key hash".to_string())
+	})?;
+	let parent = BlockId::<B>::Number(parent_number.unique_saturated_into());
 
-	let now = Instant::now();
+	key_hash_selection_with(client, &parent, params).map(|selection| selection.key_hash)
+}
 
-	let maybe_display = {
-		let mut stats = stats.lock();
-		let since_last_clear = now.checked_duration_since(stats.last_clear);
-		let since_last_display = now.checked_duration_since(stats.last_display);
+/// Parent to key hash map maintained by the caller, consulted on verification
+/// instead of walking the chain.
+pub trait KeyHashCache {
+	/// Key hash for children of `parent_hash`, if cached.
+	fn get(&self, parent_hash: &H256) -> Option<H256>;
+	/// Remember a key hash walked on the chain.
+	fn insert(&self, parent_hash: H256, key_hash: H256);
+}
 
-		if let (Some(since_last_clear), Some(since_last_display)) =
-			(since_last_clear, since_last_display)
-		{
-			let mut ret = None;
+/// Key hash for children of `parent`, from `cache` if it has it, otherwise by
+/// calling `walk` and caching the result. Parents given by number are not cached.
+pub fn cached_key_hash<B, E, F>(
+	cache: Option<&dyn KeyHashCache>,
+	parent: &BlockId<B>,
+	walk: F,
+) -> Result<H256, E> where
+	B: BlockT<Hash=H256>,
+	F: FnOnce() -> Result<H256, E>,
+{
+	let (cache, parent_hash) = match (cache, parent) {
+		(Some(cache), BlockId::Hash(parent_hash)) => (cache, *parent_hash),
+		_ => return walk(),
+	};
 
-			stats.round += round;
-			let duration = since_last_clear;
+	if let Some(key_hash) = cache.get(&parent_hash) {
+		return Ok(key_hash)
+	}
 
-			let clear = duration >= Duration::new(600, 0);
-			let display = clear || since_last_display >= Duration::new(2, 0);
+	let key_hash = walk()?;
+	cache.insert(parent_hash, key_hash);
+	Ok(key_hash)
+}
 
-			if display {
-				stats.last_display = now;
-				ret = Some((duration, stats.round));
-			}
+/// Failure resolving the key block.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum KeyHashError {
+	/// The client failed to read a header.
+	Client(String),
+	/// The parent header is not known.
+	ParentNotFound,
+	/// The header with this hash, between the parent and the key block, is
+	/// pruned. It can be fetched from peers.
+	PrunedAncestor(H256),
+}
 
-			if clear {
-				stats.last_clear = now;
-				stats.round = 0;
-			}
+impl fmt::Display for KeyHashError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			KeyHashError::Client(e) => write!(f, "Client execution error: {}", e),
+			KeyHashError::ParentNotFound => write!(f, "Parent header not found"),
+			KeyHashError::PrunedAncestor(hash) =>
+				write!(f, "Block with hash {:?} not found, it may be pruned", hash),
+		}
+	}
+}
 
-			ret
-		} else {
-			warn!(
-				target: "kulupu-pow",
-				"Calculating duration failed, the system time may have changed and the hashrate calculation may be temporarily inaccurate."
-			);
+impl std::error::Error for KeyHashError { }
 
-			None
+impl<B: BlockT> From<KeyHashError> for sc_consensus_pow::Error<B> {
+	fn from(e: KeyHashError) -> Self {
+		match e {
+			KeyHashError::PrunedAncestor(_) => sc_consensus_pow::Error::Client(
+				sp_blockchain::Error::Application(Box::new(e)),
+			),
+			e => sc_consensus_pow::Error::Environment(e.to_string()),
 		}
-	};
+	}
+}
 
-	if let Some((duration, round)) = maybe_display {
-		let hashrate = round / duration.as_secs() as u32;
-		let network_hashrate = difficulty / U256::from(60);
-		let every: u32 = (network_hashrate / U256::from(hashrate)).unique_saturated_into();
-		let every_duration = Duration::new(60, 0) * every;
-		info!(
-			target: "kulupu-pow",
-			"Local hashrate: {} H/s, network hashrate: {} H/s, expected one block every {} ({} blocks)",
-			hashrate,
-			network_hashrate,
-			humantime::format_duration(every_duration).to_string(),
-			every,
-		);
+/// Hash of the pruned ancestor a consensus error is about, if it is about one,
+/// so that the node can fetch it from peers instead of failing.
+pub fn pruned_ancestor<B: BlockT>(e: &sc_consensus_pow::Error<B>) -> Option<H256> {
+	match e {
+		sc_consensus_pow::Error::Client(sp_blockchain::Error::Application(e)) =>
+			match e.downcast_ref::<KeyHashError>() {
+				Some(KeyHashError::PrunedAncestor(hash)) => Some(*hash),
+				_ => None,
+			},
+		_ => None,
 	}
+}
 
-	Ok(maybe_seal)
+/// Resolve the key block for children of `parent`.
+pub fn key_hash_selection<B, C>(
+	client: &C,
+	parent: &BlockId<B>
+) -> Result<KeyHashSelection, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	Ok(try_key_hash_selection(client, parent)?)
+}
+
+/// Like [`key_hash_selection`], with a custom key block schedule.
+pub fn key_hash_selection_with<B, C>(
+	client: &C,
+	parent: &BlockId<B>,
+	params: &KeyHashParams,
+) -> Result<KeyHashSelection, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	Ok(try_key_hash_selection_with(client, parent, params)?)
+}
+
+/// Like [`key_hash_selection`], with an error telling pruned ancestors apart
+/// from other failures.
+pub fn try_key_hash_selection<B, C>(
+	client: &C,
+	parent: &BlockId<B>
+) -> Result<KeyHashSelection, KeyHashError> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	try_key_hash_selection_with(client, parent, &DEFAULT_KEY_HASH_PARAMS)
+}
+
+pub fn try_key_hash_selection_with<B, C>(
+	client: &C,
+	parent: &BlockId<B>,
+	params: &KeyHashParams,
+) -> Result<KeyHashSelection, KeyHashError> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	let parent_header = client.header(*parent)
+		.map_err(|e| KeyHashError::Client(format!("{:?}", e)))?
+		.ok_or(KeyHashError::ParentNotFound)?;
+	let parent_hash = parent_header.hash();
+	let parent_number = UniqueSaturatedInto::<u64>::unique_saturated_into(*parent_header.number());
+	let key_number = key_number_with(params, parent_number);
+
+	let mut current = parent_header;
+	while UniqueSaturatedInto::<u64>::unique_saturated_into(*current.number()) != key_number {
+		current = client.header(BlockId::Hash(*current.parent_hash()))
+			.map_err(|e| KeyHashError::Client(format!("{:?}", e)))?
+			.ok_or(KeyHashError::PrunedAncestor(*current.parent_hash()))?;
+	}
+
+	Ok(KeyHashSelection {
+		parent_hash,
+		parent_number,
+		key_number,
+		key_hash: current.hash(),
+	})
+}
+
+/// Trusted `(block_number, hash)` pairs, for nodes syncing from a checkpoint
+/// rather than from genesis, whose chain may not reach back to a key block.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Checkpoints(BTreeMap<u64, H256>);
+
+impl Checkpoints {
+	pub fn new<I: IntoIterator<Item = (u64, H256)>>(checkpoints: I) -> Self {
+		Self(checkpoints.into_iter().collect())
+	}
+
+	/// Hash of the checkpoint at `number`, if any.
+	pub fn get(&self, number: u64) -> Option<H256> {
+		self.0.get(&number).cloned()
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
+/// Like [`try_key_hash_selection_with`], resolving the key block from
+/// `checkpoints` when a header between the parent and the key block is
+/// missing.
+pub fn try_key_hash_selection_with_checkpoints<B, C>(
+	client: &C,
+	parent: &BlockId<B>,
+	params: &KeyHashParams,
+	checkpoints: &Checkpoints,
+) -> Result<KeyHashSelection, KeyHashError> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B>,
+{
+	match try_key_hash_selection_with(client, parent, params) {
+		Err(KeyHashError::PrunedAncestor(missing)) => {
+			let parent_header = client.header(*parent)
+				.map_err(|e| KeyHashError::Client(format!("{:?}", e)))?
+				.ok_or(KeyHashError::ParentNotFound)?;
+			let parent_number = UniqueSaturatedInto::<u64>::unique_saturated_into(*parent_header.number());
+			let key_number = key_number_with(params, parent_number);
+
+			match checkpoints.get(key_number) {
+				Some(key_hash) => Ok(KeyHashSelection {
+					parent_hash: parent_header.hash(),
+					parent_number,
+					key_number,
+					key_hash,
+				}),
+				None => Err(KeyHashError::PrunedAncestor(missing)),
+			}
+		},
+		selection => selection,
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RandomXAlgorithmVersion {
+	V1,
+	V2,
+	/// V2 seals, with a domain separated signing payload.
+	V3,
+}
+
+impl RandomXAlgorithmVersion {
+	/// Version for a runtime algorithm identifier.
+	pub fn from_identifier(identifier: &[u8; 8]) -> Option<Self> {
+		match *identifier {
+			kulupu_primitives::ALGORITHM_IDENTIFIER_V1 => Some(RandomXAlgorithmVersion::V1),
+			kulupu_primitives::ALGORITHM_IDENTIFIER_V2 => Some(RandomXAlgorithmVersion::V2),
+			kulupu_primitives::ALGORITHM_IDENTIFIER_V3 => Some(RandomXAlgorithmVersion::V3),
+			_ => None,
+		}
+	}
+
+	/// Version for a runtime algorithm identifier, when computing work with
+	/// `work_algo`. The blake2 test work function only accepts the blake2 test
+	/// identifier, whose seals are V2 seals, so that it can never run on mainnet.
+	pub fn from_identifier_for(identifier: &[u8; 8], work_algo: WorkAlgo) -> Option<Self> {
+		match work_algo {
+			WorkAlgo::RandomX => Self::from_identifier(identifier),
+			WorkAlgo::Blake2Test =>
+				if *identifier == kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST {
+					Some(RandomXAlgorithmVersion::V2)
+				} else {
+					None
+				},
+		}
+	}
+
+	/// Signing domain of seals of this version. V1 seals are not signed.
+	pub fn signing_domain(&self) -> SigningDomain {
+		match self {
+			RandomXAlgorithmVersion::V1 | RandomXAlgorithmVersion::V2 => SigningDomain::Legacy,
+			RandomXAlgorithmVersion::V3 => SigningDomain::V2,
+		}
+	}
+
+	/// Layout of the author pre-digest of blocks of this version. Matches
+	/// [`PreDigestFormat::for_identifier`], used by the rewards pallet.
+	pub fn pre_digest_format(&self) -> PreDigestFormat {
+		match self {
+			RandomXAlgorithmVersion::V1 | RandomXAlgorithmVersion::V2 => PreDigestFormat::Raw,
+			RandomXAlgorithmVersion::V3 => PreDigestFormat::Versioned,
+		}
+	}
+}
+
+/// Algorithm version used for children of the given block.
+pub fn algorithm_version<B, C>(
+	client: &C,
+	at: &BlockId<B>,
+) -> Result<RandomXAlgorithmVersion, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: ProvideRuntimeApi<B>,
+	C::Api: AlgorithmApi<B>,
+{
+	algorithm_version_for(client, at, WorkAlgo::RandomX)
+}
+
+/// Like [`algorithm_version`], when computing work with `work_algo`.
+pub fn algorithm_version_for<B, C>(
+	client: &C,
+	at: &BlockId<B>,
+	work_algo: WorkAlgo,
+) -> Result<RandomXAlgorithmVersion, sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: ProvideRuntimeApi<B>,
+	C::Api: AlgorithmApi<B>,
+{
+	let version_raw = algorithm_identifier(client, at)?;
+
+	RandomXAlgorithmVersion::from_identifier_for(&version_raw, work_algo)
+		.ok_or(sc_consensus_pow::Error::<B>::Other(
+			format!("Unknown algorithm identifier for {:?} work", work_algo),
+		))
+}
+
+/// Algorithm identifier of the runtime at `at`.
+pub fn algorithm_identifier<B, C>(
+	client: &C,
+	at: &BlockId<B>,
+) -> Result<[u8; 8], sc_consensus_pow::Error<B>> where
+	B: BlockT<Hash=H256>,
+	C: ProvideRuntimeApi<B>,
+	C::Api: AlgorithmApi<B>,
+{
+	client.runtime_api().identifier(at)
+		.map_err(|e| sc_consensus_pow::Error::Environment(
+			format!("Fetching identifier from runtime failed: {:?}", e))
+		)
+}
+
+/// Reason a seal is invalid, as opposed to an error of the node verifying it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvalidReason {
+	/// The runtime algorithm is not supported with the configured work.
+	UnsupportedVersion,
+	/// A signed seal has no author pre-digest.
+	MissingDigest,
+	/// The author pre-digest cannot be decoded.
+	MalformedDigest,
+	/// The key hash committed in the pre-digest is not the epoch key, or the
+	/// epoch key is not the one recorded by the runtime.
+	KeyHashMismatch,
+	/// The seal difficulty is implausibly far from the parent difficulty.
+	ImplausibleDifficulty,
+	/// The seal difficulty is above the highest one the retarget can represent.
+	UnrepresentableDifficulty,
+	/// The pre-hash is a degenerate value. See [`is_sentinel_pre_hash`].
+	SentinelPreHash,
+	/// The seal cannot be decoded.
+	MalformedSeal,
+	/// The seal is not signed by the author.
+	BadSignature,
+	/// The seal differs from the one recomputed for its nonce, such as by its
+	/// difficulty.
+	SealMismatch,
+	/// The work does not meet the difficulty.
+	BelowDifficulty,
+}
+
+impl fmt::Display for InvalidReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InvalidReason::UnsupportedVersion => write!(f, "Unsupported algorithm version"),
+			InvalidReason::MissingDigest => write!(f, "Missing author pre-digest"),
+			InvalidReason::MalformedDigest => write!(f, "Malformed author pre-digest"),
+			InvalidReason::KeyHashMismatch => write!(f, "Committed key hash does not match the key block"),
+			InvalidReason::ImplausibleDifficulty => write!(f, "Implausible seal difficulty"),
+			InvalidReason::UnrepresentableDifficulty => write!(f, "Unrepresentable seal difficulty"),
+			InvalidReason::SentinelPreHash => write!(f, "Sentinel pre-hash"),
+			InvalidReason::MalformedSeal => write!(f, "Malformed seal"),
+			InvalidReason::BadSignature => write!(f, "Bad signature"),
+			InvalidReason::SealMismatch => write!(f, "Seal does not match its recomputation"),
+			InvalidReason::BelowDifficulty => write!(f, "Work below difficulty"),
+		}
+	}
+}
+
+/// Result of a seal verification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyOutcome {
+	Valid,
+	Invalid(InvalidReason),
+}
+
+impl VerifyOutcome {
+	pub fn is_valid(&self) -> bool {
+		*self == VerifyOutcome::Valid
+	}
+}
+
+impl From<Result<(), InvalidReason>> for VerifyOutcome {
+	fn from(result: Result<(), InvalidReason>) -> Self {
+		match result {
+			Ok(()) => VerifyOutcome::Valid,
+			Err(reason) => VerifyOutcome::Invalid(reason),
+		}
+	}
+}
+
+/// Version of seals under the runtime algorithm `identifier`.
+fn version_for_identifier(
+	identifier: &[u8; 8],
+	work_algo: WorkAlgo,
+) -> Result<RandomXAlgorithmVersion, InvalidReason> {
+	RandomXAlgorithmVersion::from_identifier_for(identifier, work_algo)
+		.ok_or(InvalidReason::UnsupportedVersion)
+}
+
+/// Author seals of `version` must be signed by. V1 seals are not signed.
+fn required_author(
+	version: RandomXAlgorithmVersion,
+	pre_digest: Option<&[u8]>,
+) -> Result<Option<app::Public>, InvalidReason> {
+	match version {
+		// No pre-digest check is needed for V1 algorithm.
+		RandomXAlgorithmVersion::V1 => Ok(None),
+		RandomXAlgorithmVersion::V2 | RandomXAlgorithmVersion::V3 => {
+			let pre_digest = pre_digest.ok_or(InvalidReason::MissingDigest)?;
+			decode_author(version.pre_digest_format(), pre_digest)
+				.map(Some)
+				.ok_or(InvalidReason::MalformedDigest)
+		},
+	}
+}
+
+/// Snapshot of the algorithm state, cheap enough to be served over RPC.
+#[derive(Clone, PartialEq, Debug)]
+pub struct AlgorithmStatus {
+	/// Whether this instance is configured for mining.
+	pub mining_enabled: bool,
+	/// Configured mining author.
+	pub author: Option<app::Public>,
+	/// Number of RandomX caches currently held in memory.
+	pub cache_count: usize,
+	/// Key hash of the last mining round.
+	pub active_key_hash: Option<H256>,
+	/// Last measured local hashrate, in hashes per second.
+	pub hashrate_hps: f64,
+}
+
+/// Effective settings of an algorithm, to be attached to bug reports so that
+/// issues can be reproduced.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlgorithmConfig {
+	pub full_cache_capacity: usize,
+	pub light_cache_capacity: usize,
+	/// Whether mining uses light machines, to stay within the memory budget.
+	pub light_mining: bool,
+	pub max_memory_bytes: Option<u64>,
+	pub memory_pressure_threshold: Option<u64>,
+	pub sanity_check_interval: usize,
+	pub cancel_check_interval: u32,
+	pub round_timeout: Option<Duration>,
+	pub verification_cache_size: usize,
+	pub verify_threads: Option<usize>,
+	pub nonce_rng: NonceRng,
+	pub work_algo: WorkAlgo,
+	pub check_retarget: bool,
+	pub trust_committed_key_hash: bool,
+	pub author_mismatch_policy: AuthorMismatchPolicy,
+	pub cpu_throttle: Option<f64>,
+	pub max_representable_difficulty: Option<Difficulty>,
+	pub near_miss_threshold: Option<Difficulty>,
+	pub time_to_block_warning: Option<Duration>,
+	/// Temperature above which dataset generation pauses, in degrees Celsius.
+	pub thermal_limit_celsius: Option<f64>,
+	/// Whether the runtime difficulty is overridden by a difficulty source.
+	pub difficulty_overridden: bool,
+	/// Whether work is checked against the target by a custom checker.
+	pub target_overridden: bool,
+	pub checkpoints: usize,
+	pub large_pages: bool,
+	pub secure: bool,
+	/// RandomX network salt, in hex.
+	pub network_salt: Option<String>,
+}
+
+pub struct RandomXAlgorithm<C> {
+	client: Arc<C>,
+	author: Option<app::Public>,
+	stats: Arc<Mutex<Stats>>,
+	cancellation: Cancellation,
+	round_timeout: Option<Duration>,
+	events: Option<Arc<MiningEvents>>,
+	verified_seals: Option<Arc<VerifiedSeals>>,
+	nonce_rng: NonceRng,
+	check_retarget: bool,
+	paused: Arc<AtomicBool>,
+	work_algo: WorkAlgo,
+	trust_committed_key_hash: bool,
+	author_mismatch: Arc<AuthorMismatch>,
+	cpu_throttle: Option<CpuUtilization>,
+	circuit_breaker: Arc<CircuitBreaker>,
+	max_representable_difficulty: Option<Difficulty>,
+	verify_pool: Option<Arc<VerifyPool>>,
+	near_misses: Option<Arc<NearMisses>>,
+	time_to_block_warning: Option<Arc<TimeToBlockWarning>>,
+	config: Arc<AlgorithmConfig>,
+	shutdown: Arc<Shutdown>,
+	difficulty_source: Option<Arc<dyn DifficultySource>>,
+	target_checker: Arc<dyn TargetChecker>,
+	checkpoints: Arc<Checkpoints>,
+}
+
+impl<C> RandomXAlgorithm<C> {
+	pub fn new(client: Arc<C>) -> Self {
+		Self::builder(client).build()
+	}
+
+	pub fn builder(client: Arc<C>) -> RandomXAlgorithmBuilder<C> {
+		RandomXAlgorithmBuilder::new(client)
+	}
+
+	/// Mining statistics, to be passed to [`mine`].
+	pub fn stats(&self) -> Arc<Mutex<Stats>> {
+		self.stats.clone()
+	}
+
+	/// State of each mining worker thread. See [`Stats::worker_states`].
+	pub fn worker_states(&self) -> Vec<WorkerState> {
+		self.stats.lock().worker_states()
+	}
+
+	/// Effective settings of this algorithm. See [`AlgorithmConfig`].
+	pub fn config_snapshot(&self) -> AlgorithmConfig {
+		self.config.as_ref().clone()
+	}
+
+	/// Number of near misses mined so far. Zero if no near-miss threshold is
+	/// configured.
+	pub fn near_miss_count(&self) -> u64 {
+		self.near_misses.as_ref().map(|near_misses| near_misses.count()).unwrap_or(0)
+	}
+
+	/// Local hashrate under each of the recent key hashes. See
+	/// [`Stats::hashrate_by_epoch`].
+	pub fn hashrate_by_epoch(&self) -> Vec<(H256, f64)> {
+		self.stats.lock().hashrate_by_epoch()
+	}
+
+	/// Metrics of the most recent mining call of this instance.
+	pub fn last_mining_metrics(&self) -> Option<MiningMetrics> {
+		self.stats.lock().last_metrics()
+	}
+
+	/// Cancellation flag of all mining rounds run by this instance.
+	pub fn cancellation(&self) -> &Cancellation {
+		&self.cancellation
+	}
+
+	/// Stop mining until [`resume`](Self::resume) is called. Cached datasets and
+	/// machines are kept, so that mining resumes without rebuilding them.
+	pub fn pause(&self) {
+		self.paused.store(true, Ordering::SeqCst);
+	}
+
+	/// Resume mining after [`pause`](Self::pause).
+	pub fn resume(&self) {
+		self.paused.store(false, Ordering::SeqCst);
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.paused.load(Ordering::SeqCst)
+	}
+
+	/// Run a mining round, unless mining is paused.
+	fn unless_paused<T, E, F>(&self, f: F) -> Result<Option<T>, E> where
+		F: FnOnce() -> Result<Option<T>, E>,
+	{
+		if self.is_paused() {
+			return Ok(None)
+		}
+
+		self.shutdown.run(f).unwrap_or(Ok(None))
+	}
+
+	/// Stop mining and verifying, and wait for the rounds and verifications in
+	/// flight on other threads to return. Threads drop their machines as their
+	/// calls return, or on their next refused call, and the verification
+	/// threads exit. Called from within a round, this happens once the round
+	/// returns. The shared caches may serve other algorithms, and are kept.
+	pub fn shutdown(&self) {
+		self.cancellation.cancel();
+		let verify_pool = self.verify_pool.clone();
+		self.shutdown.stop_with(move || if let Some(pool) = verify_pool {
+			pool.close();
+		});
+	}
+
+	pub fn status(&self) -> AlgorithmStatus {
+		let (active_key_hash, hashrate_hps) = {
+			let stats = self.stats.lock();
+			(stats.key_hash, stats.hashrate)
+		};
+
+		AlgorithmStatus {
+			mining_enabled: self.author.is_some(),
+			author: self.author.clone(),
+			cache_count: compute::cache_count(),
+			active_key_hash,
+			hashrate_hps,
+		}
+	}
+
+	/// Key hashes of the RandomX caches currently held, to confirm which epochs
+	/// are warm.
+	pub fn cached_key_hashes(&self) -> Vec<H256> {
+		compute::cached_key_hashes()
+	}
+
+	/// Drop all cached RandomX datasets and machines, freeing their memory.
+	pub fn flush_caches(&self) {
+		compute::flush_caches()
+	}
+
+	/// Prepare the dataset and machine of the calling thread for mining with a
+	/// predicted key hash, before the block template on top of `parent` is final.
+	pub fn speculative_warm(&self, parent: &H256, predicted_key_hash: &H256) {
+		debug!(
+			target: LOG_TARGET,
+			"Speculatively warming RandomX machine for key hash {} on top of {}",
+			predicted_key_hash,
+			parent,
+		);
+
+		compute::warm(predicted_key_hash, ComputeMode::Mining);
+	}
+
+	/// Run one mining round with the options of this instance. See [`mine`].
+	pub fn mine<B>(
+		&self,
+		keystore: &LocalKeystore,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		difficulty: Difficulty,
+		round: u32,
+	) -> Result<Option<RawSeal>, MineError<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B>,
+	{
+		self.mine_with_work(keystore, parent, pre_hash, pre_digest, difficulty, round)
+			.map(|mined| mined.map(|(seal, _)| seal))
+	}
+
+	/// Like [`mine`](Self::mine), also returning the work of the seal.
+	pub fn mine_with_work<B>(
+		&self,
+		keystore: &LocalKeystore,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		difficulty: Difficulty,
+		round: u32,
+	) -> Result<Option<(RawSeal, H256)>, MineError<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B>,
+	{
+		let options = MiningOptions {
+			cancellation: Some(&self.cancellation),
+			deadline: self.round_timeout.map(|timeout| Instant::now() + timeout),
+			events: self.events.as_ref().map(|events| events.as_ref()),
+			nonce_rng: self.nonce_rng,
+			work_algo: self.work_algo,
+			cpu_throttle: self.cpu_throttle,
+			circuit_breaker: Some(&self.circuit_breaker),
+			near_misses: self.near_misses.as_ref().map(|near_misses| near_misses.as_ref()),
+			time_to_block_warning: self.time_to_block_warning.as_ref().map(|warning| warning.as_ref()),
+			target_checker: Some(self.target_checker.as_ref()),
+			..MiningOptions::new(&self.stats, &self.author_mismatch)
+		};
+
+		self.unless_paused(|| mine_with_work(
+			self.client.as_ref(),
+			keystore,
+			parent,
+			pre_hash,
+			pre_digest,
+			difficulty,
+			round,
+			&options,
+		))
+	}
+
+	/// Difficulty the parent was sealed with, or `None` for the genesis block.
+	fn parent_difficulty<B>(
+		&self,
+		parent: &BlockId<B>,
+	) -> Result<Option<Difficulty>, sc_consensus_pow::Error<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty>,
+	{
+		let parent_header = self.client.header(*parent)
+			.map_err(|e| sc_consensus_pow::Error::Environment(
+				format!("Client execution error: {:?}", e)
+			))?
+			.ok_or(sc_consensus_pow::Error::Environment(
+				"Parent header not found".to_string()
+			))?;
+
+		if parent_header.number().is_zero() {
+			return Ok(None)
+		}
+
+		self.client.runtime_api().difficulty(&BlockId::Hash(*parent_header.parent_hash()))
+			.map(Some)
+			.map_err(|e| sc_consensus_pow::Error::Environment(
+				format!("Fetching difficulty from runtime failed: {:?}", e)
+			))
+	}
+
+	/// Verify a seal like [`PowAlgorithm::verify`], telling why an invalid seal
+	/// is rejected.
+	pub fn verify_detailed<B>(
+		&self,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		seal: &RawSeal,
+		difficulty: Difficulty,
+	) -> Result<VerifyOutcome, sc_consensus_pow::Error<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B> + ExpectedKeyHashApi<B>,
+	{
+		self.verify_with_key_hash_cache(parent, pre_hash, pre_digest, seal, difficulty, None)
+	}
+
+	/// Like [`RandomXAlgorithm::verify_detailed`], looking the key hash up in a
+	/// cache owned by the caller before walking the chain.
+	pub fn verify_with_key_hash_cache<B>(
+		&self,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		seal: &RawSeal,
+		difficulty: Difficulty,
+		key_hash_cache: Option<&dyn KeyHashCache>,
+	) -> Result<VerifyOutcome, sc_consensus_pow::Error<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B> + ExpectedKeyHashApi<B>,
+	{
+		#[cfg(feature = "tracing")]
+		let span = tracing::debug_span!("verify", pre_hash = ?pre_hash, outcome = tracing::field::Empty);
+		#[cfg(feature = "tracing")]
+		let _entered = span.enter();
+
+		let _in_flight = self.shutdown.enter().ok_or_else(|| {
+			sc_consensus_pow::Error::<B>::Other("Not verifying: shutting down".to_string())
+		})?;
+
+		let outcome = match &self.verified_seals {
+			Some(verified_seals) => {
+				// Seals remembered as valid are not verified again.
+				let mut outcome = VerifyOutcome::Valid;
+				verified_seals.verify_with(pre_hash, difficulty, &seal[..], || {
+					outcome = self.verify_uncached(parent, pre_hash, pre_digest, seal, difficulty, key_hash_cache)?;
+					Ok::<_, sc_consensus_pow::Error<B>>(outcome.is_valid())
+				})?;
+				Ok(outcome)
+			},
+			None => self.verify_uncached(parent, pre_hash, pre_digest, seal, difficulty, key_hash_cache),
+		};
+
+		#[cfg(feature = "tracing")]
+		if let Ok(outcome) = &outcome {
+			span.record("outcome", &tracing::field::debug(outcome));
+		}
+
+		outcome
+	}
+
+	fn verify_uncached<B>(
+		&self,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		seal: &RawSeal,
+		difficulty: Difficulty,
+		key_hash_cache: Option<&dyn KeyHashCache>,
+	) -> Result<VerifyOutcome, sc_consensus_pow::Error<B>> where
+		B: BlockT<Hash=H256>,
+		C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+		C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B> + ExpectedKeyHashApi<B>,
+	{
+		let identifier = algorithm_identifier(self.client.as_ref(), parent)?;
+		let version = match version_for_identifier(&identifier, self.work_algo) {
+			Ok(version) => version,
+			Err(reason) => return Ok(VerifyOutcome::Invalid(reason)),
+		};
+
+		if self.check_retarget {
+			if let Some(parent_difficulty) = self.parent_difficulty(parent)? {
+				if !is_plausible_seal_difficulty(parent_difficulty, &seal[..]) {
+					return Ok(VerifyOutcome::Invalid(InvalidReason::ImplausibleDifficulty))
+				}
+			}
+		}
+
+		if let Some(max) = self.max_representable_difficulty {
+			if !is_representable_seal_difficulty(max, &seal[..]) {
+				return Ok(VerifyOutcome::Invalid(InvalidReason::UnrepresentableDifficulty))
+			}
+		}
+
+		let committed = match version {
+			RandomXAlgorithmVersion::V1 => None,
+			RandomXAlgorithmVersion::V2 | RandomXAlgorithmVersion::V3 => pre_digest
+				.and_then(|pre_digest| decode_pre_digest(version.pre_digest_format(), pre_digest))
+				.and_then(|pre_digest| pre_digest.epoch_key_hash),
+		};
+
+		let key_hash = resolve_key_hash(committed, self.trust_committed_key_hash, || {
+			cached_key_hash(key_hash_cache, parent, || {
+				let params = key_hash_params(self.client.as_ref(), parent)?;
+				let selection = try_key_hash_selection_with_checkpoints(
+					self.client.as_ref(), parent, &params, &self.checkpoints,
+				)?;
+				debug!(target: LOG_TARGET, "Verifying seal: {}", selection);
+				Ok::<_, sc_consensus_pow::Error<B>>(selection.key_hash)
+			})
+		})?;
+		let key_hash = match key_hash {
+			Some(key_hash) => key_hash,
+			None => {
+				debug!(target: LOG_TARGET, "Committed key hash does not match the key block");
+				return Ok(VerifyOutcome::Invalid(InvalidReason::KeyHashMismatch))
+			},
+		};
+
+		let recorded = expected_key_hash(self.client.as_ref(), parent)?;
+		if !matches_expected_key_hash(&key_hash, recorded) {
+			error!(
+				target: LOG_TARGET,
+				"Key hash {} on top of {:?} disagrees with the runtime recorded key hash {:?}. \
+				 The node and the runtime diverged.",
+				key_hash,
+				parent,
+				recorded,
+			);
+			return Ok(VerifyOutcome::Invalid(InvalidReason::KeyHashMismatch))
+		}
+
+		let author = match required_author(version, pre_digest) {
+			Ok(author) => author,
+			Err(reason) => return Ok(VerifyOutcome::Invalid(reason)),
+		};
+
+		let seal = SealToVerify {
+			key_hash,
+			pre_hash: *pre_hash,
+			difficulty,
+			author,
+			domain: version.signing_domain(),
+			seal: seal.clone(),
+		};
+		let work_algo = self.work_algo;
+		let target = self.target_checker.clone();
+
+		let checked = match &self.verify_pool {
+			Some(pool) => pool.run(move || seal.check_with_target(&work_algo, target.as_ref()))
+				.map_err(|e| sc_consensus_pow::Error::<B>::Other(e.to_string()))?,
+			None => seal.check_with_target(&work_algo, target.as_ref()),
+		};
+
+		Ok(VerifyOutcome::from(checked))
+	}
+}
+
+impl<C> Clone for RandomXAlgorithm<C> {
+	fn clone(&self) -> Self {
+		Self {
+			client: self.client.clone(),
+			author: self.author.clone(),
+			stats: self.stats.clone(),
+			cancellation: self.cancellation.clone(),
+			round_timeout: self.round_timeout,
+			events: self.events.clone(),
+			verified_seals: self.verified_seals.clone(),
+			nonce_rng: self.nonce_rng,
+			check_retarget: self.check_retarget,
+			paused: self.paused.clone(),
+			work_algo: self.work_algo,
+			trust_committed_key_hash: self.trust_committed_key_hash,
+			author_mismatch: self.author_mismatch.clone(),
+			cpu_throttle: self.cpu_throttle,
+			circuit_breaker: self.circuit_breaker.clone(),
+			max_representable_difficulty: self.max_representable_difficulty,
+			verify_pool: self.verify_pool.clone(),
+			near_misses: self.near_misses.clone(),
+			time_to_block_warning: self.time_to_block_warning.clone(),
+			config: self.config.clone(),
+			shutdown: self.shutdown.clone(),
+			difficulty_source: self.difficulty_source.clone(),
+			target_checker: self.target_checker.clone(),
+			checkpoints: self.checkpoints.clone(),
+		}
+	}
+}
+
+/// Builder for [`RandomXAlgorithm`].
+///
+/// Settings of the shared caches are process-wide, and are applied once at
+/// startup with [`compute::configure`].
+pub struct RandomXAlgorithmBuilder<C> {
+	client: Arc<C>,
+	author: Option<app::Public>,
+	cancel_check_interval: u32,
+	round_timeout: Option<Duration>,
+	events: Option<Arc<MiningEvents>>,
+	verification_cache_size: usize,
+	nonce_rng: NonceRng,
+	check_retarget: bool,
+	work_algo: WorkAlgo,
+	trust_committed_key_hash: bool,
+	author_mismatch_policy: AuthorMismatchPolicy,
+	cpu_throttle: Option<CpuUtilization>,
+	max_representable_difficulty: Option<Difficulty>,
+	verify_threads: Option<usize>,
+	near_miss_threshold: Option<Difficulty>,
+	time_to_block_warning: Option<Duration>,
+	difficulty_source: Option<Arc<dyn DifficultySource>>,
+	target_checker: Option<Arc<dyn TargetChecker>>,
+	checkpoints: Checkpoints,
+}
+
+impl<C> RandomXAlgorithmBuilder<C> {
+	pub fn new(client: Arc<C>) -> Self {
+		Self {
+			client,
+			author: None,
+			cancel_check_interval: DEFAULT_CANCEL_CHECK_INTERVAL,
+			round_timeout: None,
+			events: None,
+			verification_cache_size: 0,
+			nonce_rng: NonceRng::default(),
+			check_retarget: false,
+			work_algo: WorkAlgo::default(),
+			trust_committed_key_hash: false,
+			author_mismatch_policy: AuthorMismatchPolicy::default(),
+			cpu_throttle: None,
+			max_representable_difficulty: None,
+			verify_threads: None,
+			near_miss_threshold: None,
+			time_to_block_warning: None,
+			difficulty_source: None,
+			target_checker: None,
+			checkpoints: Checkpoints::default(),
+		}
+	}
+
+	/// Author to mine for. Mining is disabled if not set.
+	pub fn author(mut self, author: Option<app::Public>) -> Self {
+		self.author = author;
+		self
+	}
+
+	/// Nonces tried between two polls of the cancellation flag.
+	pub fn cancel_check_interval(mut self, interval: u32) -> Self {
+		self.cancel_check_interval = interval;
+		self
+	}
+
+	/// Wall-clock budget of a single mining round.
+	pub fn round_timeout(mut self, timeout: Option<Duration>) -> Self {
+		self.round_timeout = timeout;
+		self
+	}
+
+	/// Sink for machine-readable mining events.
+	pub fn events(mut self, events: Option<Arc<MiningEvents>>) -> Self {
+		self.events = events;
+		self
+	}
+
+	/// Number of successfully verified seals remembered, so that blocks verified
+	/// again are accepted without recomputing their work. Zero disables it.
+	pub fn verification_cache_size(mut self, size: usize) -> Self {
+		self.verification_cache_size = size;
+		self
+	}
+
+	/// Random number generator nonces are drawn from.
+	pub fn nonce_rng(mut self, nonce_rng: NonceRng) -> Self {
+		self.nonce_rng = nonce_rng;
+		self
+	}
+
+	/// Reject seals whose difficulty is implausibly far from the one of their
+	/// parent, in addition to the runtime difficulty check.
+	pub fn check_retarget(mut self, check_retarget: bool) -> Self {
+		self.check_retarget = check_retarget;
+		self
+	}
+
+	/// Work function to mine and verify with. Anything but RandomX is rejected
+	/// by mainnet runtimes.
+	pub fn work_algo(mut self, work_algo: WorkAlgo) -> Self {
+		self.work_algo = work_algo;
+		self
+	}
+
+	/// Verify with the key hash committed in the pre-digest, when present,
+	/// without walking the chain to the key block. Meant for light nodes.
+	pub fn trust_committed_key_hash(mut self, trust: bool) -> Self {
+		self.trust_committed_key_hash = trust;
+		self
+	}
+
+	/// What mining does when no keystore key matches the author pre-digest.
+	pub fn author_mismatch_policy(mut self, policy: AuthorMismatchPolicy) -> Self {
+		self.author_mismatch_policy = policy;
+		self
+	}
+
+	/// Share of a core each mining thread aims to use, by sleeping between
+	/// batches of nonces. Not throttled if not set.
+	pub fn cpu_throttle(mut self, utilization: Option<CpuUtilization>) -> Self {
+		self.cpu_throttle = utilization;
+		self
+	}
+
+	/// Highest difficulty the runtime retarget can represent. Seals claiming a
+	/// higher difficulty are rejected. Not checked if not set.
+	pub fn max_representable_difficulty(mut self, max: Option<Difficulty>) -> Self {
+		self.max_representable_difficulty = max;
+		self
+	}
+
+	/// Recompute the work of verified seals on a pool of this many threads,
+	/// instead of on the importing thread, bounding the CPU used by sync.
+	/// Verified on the calling thread if not set.
+	pub fn verify_threads(mut self, threads: Option<usize>) -> Self {
+		self.verify_threads = threads;
+		self
+	}
+
+	/// Count and log mined hashes meeting this lower difficulty but not the
+	/// network one. Not counted if not set.
+	pub fn near_miss_threshold(mut self, threshold: Option<Difficulty>) -> Self {
+		self.near_miss_threshold = threshold;
+		self
+	}
+
+	/// Warn once when the measured hashrate implies an expected time to find a
+	/// block above this threshold. Not warned if not set.
+	pub fn time_to_block_warning(mut self, threshold: Option<Duration>) -> Self {
+		self.time_to_block_warning = threshold;
+		self
+	}
+
+	/// Difficulty to use instead of the runtime one, such as [`TestDifficulty`]
+	/// on test networks. Refused unless the runtime reports the blake2 test
+	/// identifier. Uses the runtime difficulty if not set.
+	pub fn difficulty_source(mut self, source: Option<Arc<dyn DifficultySource>>) -> Self {
+		self.difficulty_source = source;
+		self
+	}
+
+	/// Check of work against the difficulty target, used for mining and
+	/// verification. Uses [`DefaultTargetChecker`] if not set.
+	pub fn target_checker(mut self, checker: Option<Arc<dyn TargetChecker>>) -> Self {
+		self.target_checker = checker;
+		self
+	}
+
+	/// Trusted checkpoints resolving key blocks that the chain of a node synced
+	/// from a checkpoint does not reach.
+	pub fn checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+		self.checkpoints = checkpoints;
+		self
+	}
+
+	pub fn build(self) -> RandomXAlgorithm<C> {
+		let cache_settings = compute::cache_settings();
+		let randomx_config = compute::global_config();
+		let config = AlgorithmConfig {
+			full_cache_capacity: cache_settings.full_cache_capacity,
+			light_cache_capacity: cache_settings.light_cache_capacity,
+			light_mining: compute::light_mining(),
+			max_memory_bytes: cache_settings.max_memory_bytes,
+			memory_pressure_threshold: cache_settings.memory_pressure_threshold,
+			sanity_check_interval: cache_settings.sanity_check_interval,
+			cancel_check_interval: self.cancel_check_interval,
+			round_timeout: self.round_timeout,
+			verification_cache_size: self.verification_cache_size,
+			verify_threads: self.verify_threads,
+			nonce_rng: self.nonce_rng,
+			work_algo: self.work_algo,
+			check_retarget: self.check_retarget,
+			trust_committed_key_hash: self.trust_committed_key_hash,
+			author_mismatch_policy: self.author_mismatch_policy,
+			cpu_throttle: self.cpu_throttle.map(CpuUtilization::get),
+			max_representable_difficulty: self.max_representable_difficulty,
+			near_miss_threshold: self.near_miss_threshold,
+			time_to_block_warning: self.time_to_block_warning,
+			thermal_limit_celsius: cache_settings.thermal_limit.as_ref().map(|limit| limit.max_celsius()),
+			difficulty_overridden: self.difficulty_source.is_some(),
+			target_overridden: self.target_checker.is_some(),
+			checkpoints: self.checkpoints.len(),
+			large_pages: randomx_config.large_pages,
+			secure: randomx_config.secure,
+			network_salt: randomx_config.network_salt
+				.map(|salt| format!("{}", sp_core::hexdisplay::HexDisplay::from(&salt))),
+		};
+
+		let author_mismatch = AuthorMismatch::new(self.author_mismatch_policy);
+
+		RandomXAlgorithm {
+			client: self.client,
+			author: self.author,
+			stats: Arc::new(Mutex::new(Stats::new())),
+			cancellation: Cancellation::with_check_interval(self.cancel_check_interval),
+			round_timeout: self.round_timeout,
+			events: self.events,
+			verified_seals: if self.verification_cache_size > 0 {
+				Some(Arc::new(VerifiedSeals::new(self.verification_cache_size)))
+			} else {
+				None
+			},
+			nonce_rng: self.nonce_rng,
+			check_retarget: self.check_retarget,
+			paused: Arc::new(AtomicBool::new(false)),
+			work_algo: self.work_algo,
+			trust_committed_key_hash: self.trust_committed_key_hash,
+			author_mismatch: Arc::new(author_mismatch),
+			cpu_throttle: self.cpu_throttle,
+			circuit_breaker: Arc::new(CircuitBreaker::default()),
+			max_representable_difficulty: self.max_representable_difficulty,
+			verify_pool: self.verify_threads.map(|threads| Arc::new(VerifyPool::new(threads))),
+			near_misses: self.near_miss_threshold.map(|threshold| Arc::new(NearMisses::new(threshold))),
+			time_to_block_warning: self.time_to_block_warning
+				.map(|threshold| Arc::new(TimeToBlockWarning::new(threshold))),
+			config: Arc::new(config),
+			shutdown: Arc::new(Shutdown::with_release(compute::release_local_machines)),
+			difficulty_source: self.difficulty_source,
+			target_checker: self.target_checker.unwrap_or_else(|| Arc::new(DefaultTargetChecker)),
+			checkpoints: Arc::new(self.checkpoints),
+		}
+	}
+}
+
+impl<B: BlockT<Hash=H256>, C> PowAlgorithm<B> for RandomXAlgorithm<C> where
+	C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+	C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B> + ExpectedKeyHashApi<B>,
+{
+	type Difficulty = Difficulty;
+
+	fn difficulty(&self, parent: H256) -> Result<Difficulty, sc_consensus_pow::Error<B>> {
+		let overridden = self.difficulty_source.as_ref()
+			.and_then(|source| source.difficulty(&parent));
+
+		// Like the blake2 work, overriding the difficulty is only possible on
+		// runtimes identifying as test networks, so that it never reaches mainnet.
+		if overridden.is_some() {
+			let identifier = algorithm_identifier(self.client.as_ref(), &BlockId::Hash(parent))?;
+			if identifier != kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST {
+				return Err(sc_consensus_pow::Error::Other(format!(
+					"Refusing to override the difficulty of a runtime with algorithm identifier {:?}",
+					String::from_utf8_lossy(&identifier),
+				)))
+			}
+		}
+
+		let difficulty = match overridden {
+			Some(difficulty) => difficulty,
+			None => self.client.runtime_api().difficulty(&BlockId::Hash(parent))
+				.map_err(|e| sc_consensus_pow::Error::Environment(
+					format!("Fetching difficulty from runtime failed: {:?}", e)
+				))?,
+		};
+
+		Ok(difficulty)
+	}
+
+	fn break_tie(
+		&self,
+		own_seal: &RawSeal,
+		new_seal: &RawSeal,
+	) -> bool {
+		blake2_256(&own_seal[..]) > blake2_256(&new_seal[..])
+	}
+
+	/// Verify the seal, including its signature by the author of the
+	/// pre-digest. See [`seal_author`].
+	fn verify(
+		&self,
+		parent: &BlockId<B>,
+		pre_hash: &H256,
+		pre_digest: Option<&[u8]>,
+		seal: &RawSeal,
+		difficulty: Difficulty,
+	) -> Result<bool, sc_consensus_pow::Error<B>> {
+		let outcome = self.verify_detailed(parent, pre_hash, pre_digest, seal, difficulty)?;
+		if let VerifyOutcome::Invalid(reason) = outcome {
+			debug!(target: LOG_TARGET, "Rejected seal on top of {:?}: {}", parent, reason);
+		}
+
+		Ok(outcome.is_valid())
+	}
+}
+
+/// Seals already verified as valid, keyed by pre-hash, difficulty and encoded
+/// seal. The pre-hash commits to the parent, but not to the difficulty the
+/// seal is checked against.
+///
+/// Only successful verifications are remembered. A failure may come from a
+/// transient error, and must not make the block permanently invalid.
+pub struct VerifiedSeals {
+	seals: Mutex<LruCache<(H256, Difficulty, Vec<u8>), ()>>,
+}
+
+impl VerifiedSeals {
+	pub fn new(capacity: usize) -> Self {
+		Self { seals: Mutex::new(LruCache::new(capacity)) }
+	}
+
+	/// Run `verify` unless the seal is known to be valid, and remember it if
+	/// it turns out valid.
+	pub fn verify_with<E, F: FnOnce() -> Result<bool, E>>(
+		&self,
+		pre_hash: &H256,
+		difficulty: Difficulty,
+		seal: &[u8],
+		verify: F,
+	) -> Result<bool, E> {
+		let key = (*pre_hash, difficulty, seal.to_vec());
+
+		if self.seals.lock().get_mut(&key).is_some() {
+			return Ok(true)
+		}
+
+		let valid = verify()?;
+		if valid {
+			self.seals.lock().insert(key, ());
+		}
+
+		Ok(valid)
+	}
+}
+
+pub use kulupu_primitives::digest::{AUTHOR_DIGEST_V1, AUTHOR_DIGEST_V2};
+
+/// Encode an author pre-digest in the versioned format: the version tag
+/// followed by the public key.
+pub fn encode_author_digest(author: &app::Public) -> Vec<u8> {
+	let mut digest = vec![AUTHOR_DIGEST_V1];
+	author.encode_to(&mut digest);
+	digest
+}
+
+/// Decoded pre-digest.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PreDigest {
+	pub author: app::Public,
+	/// Key hash the author mined with, if committed. Lets verifiers check the
+	/// key hash without walking the chain, or skip the walk when trusted.
+	pub epoch_key_hash: Option<H256>,
+}
+
+/// Encode a pre-digest committing to `epoch_key_hash`: the version tag, the
+/// public key and the key hash.
+pub fn encode_committed_digest(author: &app::Public, epoch_key_hash: &H256) -> Vec<u8> {
+	let mut digest = vec![AUTHOR_DIGEST_V2];
+	author.encode_to(&mut digest);
+	epoch_key_hash.encode_to(&mut digest);
+	digest
+}
+
+/// Decode a pre-digest of `format`. Raw pre-digests are decoded as leniently
+/// as the rewards pallet does, versioned ones must hold exactly one public key.
+pub fn decode_pre_digest(format: PreDigestFormat, pre_digest: &[u8]) -> Option<PreDigest> {
+	let (author, epoch_key_hash) = split_pre_digest(format, pre_digest)?;
+
+	if format == PreDigestFormat::Versioned && author.len() != 32 {
+		return None
+	}
+
+	Some(PreDigest {
+		author: app::Public::decode(&mut &author[..]).ok()?,
+		epoch_key_hash: epoch_key_hash.map(H256::from_slice),
+	})
+}
+
+/// Decode the author of a pre-digest. See [`decode_pre_digest`].
+pub fn decode_author(format: PreDigestFormat, pre_digest: &[u8]) -> Option<app::Public> {
+	decode_pre_digest(format, pre_digest).map(|pre_digest| pre_digest.author)
+}
+
+/// Author of a block, as the raw sr25519 key rewards are attributed to. Seals
+/// are only accepted by `verify` if they are signed by this key, so the author
+/// of an imported block is the one who mined it.
+pub fn seal_author(format: PreDigestFormat, pre_digest: &[u8]) -> Option<sp_core::sr25519::Public> {
+	decode_author(format, pre_digest).map(Into::into)
+}
+
+/// Key hash to verify with, given the one committed in the pre-digest. A
+/// trusted commitment is used as-is, otherwise the chain is walked and the
+/// commitment must match. Returns `None` on mismatch.
+fn resolve_key_hash<E, F: FnOnce() -> Result<H256, E>>(
+	committed: Option<H256>,
+	trust_committed: bool,
+	walk: F,
+) -> Result<Option<H256>, E> {
+	match committed {
+		Some(committed) if trust_committed => Ok(Some(committed)),
+		Some(committed) => walk().map(|key_hash| Some(key_hash).filter(|k| *k == committed)),
+		None => walk().map(Some),
+	}
+}
+
+/// Factor by which a seal difficulty may at most differ from the difficulty of
+/// its parent. The runtime clamps each retarget by `CLAMP_FACTOR` relative to
+/// the window average, so this leaves a margin over a single clamp.
+pub const RETARGET_PLAUSIBILITY_FACTOR: u128 = kulupu_primitives::CLAMP_FACTOR * kulupu_primitives::CLAMP_FACTOR;
+
+/// Whether the difficulty embedded in a seal is within plausible retarget
+/// bounds of the parent difficulty. Both V1 and V2 seals start with their
+/// difficulty.
+pub fn is_plausible_seal_difficulty(parent_difficulty: Difficulty, seal: &[u8]) -> bool {
+	let seal_difficulty = match Difficulty::decode(&mut &seal[..]) {
+		Ok(difficulty) => difficulty,
+		Err(_) => return false,
+	};
+	let factor = U256::from(RETARGET_PLAUSIBILITY_FACTOR);
+
+	seal_difficulty >= parent_difficulty / factor &&
+		seal_difficulty <= parent_difficulty.saturating_mul(factor)
+}
+
+/// Whether the difficulty embedded in a seal is at most `max`, the highest
+/// difficulty the retarget can represent.
+pub fn is_representable_seal_difficulty(max: Difficulty, seal: &[u8]) -> bool {
+	match Difficulty::decode(&mut &seal[..]) {
+		Ok(difficulty) => difficulty <= max,
+		Err(_) => false,
+	}
+}
+
+/// Whether the pre-hash is a degenerate value no honest block can have. Such
+/// blocks are rejected before any work is recomputed, to limit wasted verifier
+/// work during spam.
+pub fn is_sentinel_pre_hash(pre_hash: &H256) -> bool {
+	*pre_hash == H256::zero() || *pre_hash == H256::repeat_byte(0xff)
+}
+
+/// Verify a V1 seal against an already resolved key hash.
+pub fn verify_seal_v1(
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> bool {
+	verify_seal_v1_with(&compute::RandomXBackend, key_hash, pre_hash, difficulty, seal)
+}
+
+pub fn verify_seal_v1_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> bool {
+	check_seal_v1_with(backend, key_hash, pre_hash, difficulty, seal).is_ok()
+}
+
+/// Like [`verify_seal_v1_with`], telling why an invalid seal is rejected.
+pub fn check_seal_v1_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> Result<(), InvalidReason> {
+	check_seal_v1_with_target(backend, &DefaultTargetChecker, key_hash, pre_hash, difficulty, seal)
+}
+
+/// Like [`check_seal_v1_with`], checking the work with `target`.
+pub fn check_seal_v1_with_target<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> Result<(), InvalidReason> {
+	if is_sentinel_pre_hash(pre_hash) {
+		return Err(InvalidReason::SentinelPreHash)
+	}
+
+	let seal = SealV1::decode(&mut &seal[..]).map_err(|_| InvalidReason::MalformedSeal)?;
+
+	let compute = ComputeV1 {
+		key_hash: *key_hash,
+		difficulty,
+		pre_hash: *pre_hash,
+		nonce: seal.nonce,
+	};
+
+	let (computed_seal, computed_work) = compute.seal_and_work_with(backend, ComputeMode::Sync);
+
+	if computed_seal != seal {
+		return Err(InvalidReason::SealMismatch)
+	}
+
+	if !target.is_valid(&computed_work, difficulty) {
+		return Err(InvalidReason::BelowDifficulty)
+	}
+
+	Ok(())
+}
+
+/// Canonical encoding of a V2 seal, for re-broadcast. Verification ignores
+/// bytes following the seal, so they are stripped rather than rejected, and
+/// are never propagated. `None` if no seal can be decoded.
+pub fn canonicalize_seal(seal: &[u8]) -> Option<RawSeal> {
+	SealV2::decode(&mut &seal[..]).ok().map(|seal| seal.encode())
+}
+
+/// Verify a V2 seal signed by an arbitrary sr25519 key, independently of any
+/// client or configured author. Meant for external tooling.
+pub fn verify_external(
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &sp_core::sr25519::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	verify_external_with(&compute::RandomXBackend, key_hash, pre_hash, author, difficulty, domain, seal)
+}
+
+pub fn verify_external_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &sp_core::sr25519::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	let author = app::Public::from(*author);
+	verify_seal_v2_with(backend, key_hash, pre_hash, &author, difficulty, domain, seal)
+}
+
+/// Check that the linked RandomX library reproduces the work of a known-good
+/// block, to catch a miscompiled library before it validates anything. Meant
+/// to run at startup against a recent mainnet block. Blocks of either signing
+/// domain are accepted, as the block itself is trusted.
+pub fn self_check_against_block(
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> bool {
+	self_check_against_block_with(&compute::RandomXBackend, key_hash, pre_hash, author, difficulty, seal)
+}
+
+/// Like [`self_check_against_block`], computing work with `backend`.
+pub fn self_check_against_block_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	seal: &[u8],
+) -> bool {
+	[SigningDomain::Legacy, SigningDomain::V2].iter().any(|domain| {
+		verify_seal_v2_with(backend, key_hash, pre_hash, author, difficulty, *domain, seal)
+	})
+}
+
+/// Verify a V2 seal against an already resolved key hash and author.
+///
+/// This needs no runtime, so it is also the primitive for SPV clients, which
+/// are trusted to get `difficulty` from the header chain they follow.
+pub fn verify_seal_v2(
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	verify_seal_v2_with(&compute::RandomXBackend, key_hash, pre_hash, author, difficulty, domain, seal)
+}
+
+pub fn verify_seal_v2_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	verify_share_with(backend, key_hash, pre_hash, author, difficulty, difficulty, domain, seal)
+}
+
+/// Verify a V2 seal signed for `difficulty`, but only require its work to meet
+/// `share_difficulty`. Used by pools to validate shares below the network
+/// difficulty.
+pub fn verify_share(
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	share_difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	verify_share_with(
+		&compute::RandomXBackend,
+		key_hash,
+		pre_hash,
+		author,
+		difficulty,
+		share_difficulty,
+		domain,
+		seal,
+	)
+}
+
+pub fn verify_share_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	share_difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	check_share_with(backend, key_hash, pre_hash, author, difficulty, share_difficulty, domain, seal).is_ok()
+}
+
+/// Like [`verify_share_with`], telling why an invalid seal is rejected.
+pub fn check_share_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	share_difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> Result<(), InvalidReason> {
+	check_share_with_target(
+		backend, &DefaultTargetChecker, key_hash, pre_hash, author, difficulty, share_difficulty,
+		domain, seal,
+	)
+}
+
+/// Like [`check_share_with`], checking the work with `target`.
+pub fn check_share_with_target<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	share_difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> Result<(), InvalidReason> {
+	if is_sentinel_pre_hash(pre_hash) {
+		return Err(InvalidReason::SentinelPreHash)
+	}
+
+	let seal = SealV2::decode(&mut &seal[..]).map_err(|_| InvalidReason::MalformedSeal)?;
+
+	let compute = ComputeV2 {
+		key_hash: *key_hash,
+		difficulty,
+		pre_hash: *pre_hash,
+		nonce: seal.nonce,
+	};
+
+	// The signature check is cheap, and must stay in front of the RandomX
+	// recompute. Otherwise blocks with bad signatures can be used to waste
+	// verifier CPU.
+	if !compute.verify_in(&seal.signature, author, domain) {
+		return Err(InvalidReason::BadSignature)
+	}
+
+	let (computed_seal, computed_work) = compute.seal_and_work_with(
+		backend,
+		seal.signature.clone(),
+		ComputeMode::Sync,
+	);
+
+	if computed_seal != seal {
+		return Err(InvalidReason::SealMismatch)
+	}
+
+	if !target.is_valid(&computed_work, share_difficulty) {
+		return Err(InvalidReason::BelowDifficulty)
+	}
+
+	Ok(())
+}
+
+/// Seal of a batch verified by [`verify_parallel`].
+#[derive(Clone, Debug)]
+pub struct SealToVerify {
+	pub key_hash: H256,
+	pub pre_hash: H256,
+	pub difficulty: Difficulty,
+	/// Author pre-digest. `None` for V1 seals, which are not signed.
+	pub author: Option<app::Public>,
+	pub domain: SigningDomain,
+	pub seal: Vec<u8>,
+}
+
+impl SealToVerify {
+	pub fn verify_with<W: WorkBackend + ?Sized>(&self, backend: &W) -> bool {
+		self.check_with(backend).is_ok()
+	}
+
+	/// Like [`verify_with`](Self::verify_with), telling why an invalid seal is
+	/// rejected.
+	pub fn check_with<W: WorkBackend + ?Sized>(&self, backend: &W) -> Result<(), InvalidReason> {
+		self.check_with_target(backend, &DefaultTargetChecker)
+	}
+
+	/// Like [`check_with`](Self::check_with), checking the work with `target`.
+	pub fn check_with_target<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+		&self,
+		backend: &W,
+		target: &T,
+	) -> Result<(), InvalidReason> {
+		match &self.author {
+			None => check_seal_v1_with_target(
+				backend, target, &self.key_hash, &self.pre_hash, self.difficulty, &self.seal[..],
+			),
+			Some(author) => check_share_with_target(
+				backend, target, &self.key_hash, &self.pre_hash, author, self.difficulty,
+				self.difficulty, self.domain, &self.seal[..],
+			),
+		}
+	}
+}
+
+/// Verify a batch of seals on a bounded pool, returning the results in the
+/// order of the batch. A seal whose verification panicked is reported invalid.
+pub fn verify_pooled(pool: &VerifyPool, seals: Vec<SealToVerify>) -> Vec<bool> {
+	verify_pooled_with(pool, Arc::new(compute::RandomXBackend), seals)
+}
+
+pub fn verify_pooled_with<W>(
+	pool: &VerifyPool,
+	backend: Arc<W>,
+	seals: Vec<SealToVerify>,
+) -> Vec<bool> where
+	W: WorkBackend + Send + Sync + 'static,
+{
+	let results = seals.into_iter().map(|seal| {
+		let backend = backend.clone();
+		pool.spawn(move || seal.verify_with(backend.as_ref()))
+	}).collect::<Vec<_>>();
+
+	results.into_iter()
+		.map(|result| result.recv().unwrap_or(false))
+		.collect()
+}
+
+/// Verify a batch of seals across `threads` threads, returning the results in
+/// the order of the batch.
+///
+/// Seals are grouped by key hash, and each group is verified by a single
+/// thread, so that each thread only builds machines for its own epochs. Seals
+/// of a thread that panicked are reported invalid.
+pub fn verify_parallel(seals: Vec<SealToVerify>, threads: usize) -> Vec<bool> {
+	verify_parallel_with(Arc::new(compute::RandomXBackend), seals, threads)
+}
+
+pub fn verify_parallel_with<W>(
+	backend: Arc<W>,
+	seals: Vec<SealToVerify>,
+	threads: usize,
+) -> Vec<bool> where
+	W: WorkBackend + Send + Sync + 'static,
+{
+	let mut groups: Vec<(H256, Vec<usize>)> = Vec::new();
+	for (index, seal) in seals.iter().enumerate() {
+		match groups.iter_mut().find(|(key_hash, _)| *key_hash == seal.key_hash) {
+			Some((_, indices)) => indices.push(index),
+			None => groups.push((seal.key_hash, vec![index])),
+		}
+	}
+
+	let threads = threads.max(1).min(groups.len().max(1));
+	let mut assigned = vec![Vec::<usize>::new(); threads];
+	for (i, (_, indices)) in groups.into_iter().enumerate() {
+		assigned[i % threads].extend(indices);
+	}
+
+	let seals = Arc::new(seals);
+	let handles = assigned.into_iter().map(|indices| {
+		let backend = backend.clone();
+		let seals = seals.clone();
+
+		std::thread::spawn(move || {
+			indices.into_iter()
+				.map(|index| (index, seals[index].verify_with(backend.as_ref())))
+				.collect::<Vec<_>>()
+		})
+	}).collect::<Vec<_>>();
+
+	let mut results = vec![false; seals.len()];
+	for handle in handles {
+		// Seals of a panicked thread are left invalid.
+		if let Ok(checked) = handle.join() {
+			for (index, valid) in checked {
+				results[index] = valid;
+			}
+		}
+	}
+
+	results
+}
+
+/// Check only the author signature of a V2 seal, without recomputing its work.
+///
+/// This does not establish that the seal meets the difficulty, and is only
+/// meant for clients that trust the proof of work from elsewhere.
+pub fn verify_signature_only(
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> bool {
+	let seal = match SealV2::decode(&mut &seal[..]) {
+		Ok(seal) => seal,
+		Err(_) => return false,
+	};
+
+	// The key hash is not part of the signed calculation.
+	let compute = ComputeV2 {
+		key_hash: H256::zero(),
+		difficulty,
+		pre_hash: *pre_hash,
+		nonce: seal.nonce,
+	};
+
+	compute.verify_in(&seal.signature, author, domain)
+}
+
+/// Recompute the work of a V2 seal whose signature is valid, without checking
+/// it against the difficulty target. Meant for forensic analysis of blocks.
+pub fn recompute_work(
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> Option<H256> {
+	recompute_work_with(&compute::RandomXBackend, key_hash, pre_hash, author, difficulty, domain, seal)
+}
+
+pub fn recompute_work_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	key_hash: &H256,
+	pre_hash: &H256,
+	author: &app::Public,
+	difficulty: Difficulty,
+	domain: SigningDomain,
+	seal: &[u8],
+) -> Option<H256> {
+	let seal = SealV2::decode(&mut &seal[..]).ok()?;
+
+	let compute = ComputeV2 {
+		key_hash: *key_hash,
+		difficulty,
+		pre_hash: *pre_hash,
+		nonce: seal.nonce,
+	};
+
+	if !compute.verify_in(&seal.signature, author, domain) {
+		return None
+	}
+
+	let (_, work) = compute.seal_and_work_with(backend, seal.signature, ComputeMode::Sync);
+	Some(work)
+}
+
+/// Reason a sealed header is inconsistent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ValidationError {
+	/// The header has no author pre-digest.
+	MissingPreDigest,
+	/// The author pre-digest is not a valid public key.
+	InvalidAuthor,
+	/// The seal could not be decoded.
+	MalformedSeal,
+	/// The difficulty in the seal is not the expected one.
+	DifficultyMismatch,
+	/// The seal is not signed by the author.
+	BadSignature,
+	/// The work of the seal does not meet the difficulty.
+	InsufficientWork,
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ValidationError::MissingPreDigest => write!(f, "Missing author pre-digest"),
+			ValidationError::InvalidAuthor => write!(f, "Invalid author pre-digest"),
+			ValidationError::MalformedSeal => write!(f, "Malformed seal"),
+			ValidationError::DifficultyMismatch => write!(f, "Seal difficulty mismatch"),
+			ValidationError::BadSignature => write!(f, "Bad seal signature"),
+			ValidationError::InsufficientWork => write!(f, "Insufficient work"),
+		}
+	}
+}
+
+impl std::error::Error for ValidationError { }
+
+/// Check that a V2 seal is consistent with its header: signed by the author
+/// of the pre-digest, and with work meeting the difficulty under `key_hash`.
+pub fn validate_sealed_header(
+	pre_hash: &H256,
+	pre_digest: Option<&[u8]>,
+	seal: &[u8],
+	difficulty: Difficulty,
+	key_hash: &H256,
+	domain: SigningDomain,
+	format: PreDigestFormat,
+) -> Result<(), ValidationError> {
+	validate_sealed_header_with(
+		&compute::RandomXBackend,
+		&DefaultTargetChecker,
+		pre_hash,
+		pre_digest,
+		seal,
+		difficulty,
+		key_hash,
+		domain,
+		format,
+	)
+}
+
+/// Like [`validate_sealed_header`], with a custom work backend and target check.
+pub fn validate_sealed_header_with<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	pre_hash: &H256,
+	pre_digest: Option<&[u8]>,
+	seal: &[u8],
+	difficulty: Difficulty,
+	key_hash: &H256,
+	domain: SigningDomain,
+	format: PreDigestFormat,
+) -> Result<(), ValidationError> {
+	let pre_digest = pre_digest.ok_or(ValidationError::MissingPreDigest)?;
+	let author = decode_author(format, pre_digest).ok_or(ValidationError::InvalidAuthor)?;
+	let seal = SealV2::decode(&mut &seal[..]).map_err(|_| ValidationError::MalformedSeal)?;
+
+	if seal.difficulty != difficulty {
+		return Err(ValidationError::DifficultyMismatch)
+	}
+
+	let compute = ComputeV2 {
+		key_hash: *key_hash,
+		difficulty,
+		pre_hash: *pre_hash,
+		nonce: seal.nonce,
+	};
+
+	if !compute.verify_in(&seal.signature, &author, domain) {
+		return Err(ValidationError::BadSignature)
+	}
+
+	let (_, work) = compute.seal_and_work_with(backend, seal.signature, ComputeMode::Sync);
+
+	if !target.is_valid(&work, difficulty) {
+		return Err(ValidationError::InsufficientWork)
+	}
+
+	Ok(())
+}
+
+/// Number of key hash epochs whose hashrate is kept in [`Stats`].
+const MAX_HASHRATE_EPOCHS: usize = 8;
+
+/// Snapshot of a mining worker thread.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WorkerState {
+	/// Name of the worker thread, or its id if unnamed.
+	pub thread: String,
+	/// Key hash the worker last mined or prepared for.
+	pub key_hash: Option<H256>,
+	/// Nonces tried so far.
+	pub nonces: u64,
+	/// Whether the worker is preparing its machine, which takes long when the
+	/// machine or dataset has to be rebuilt.
+	pub rebuilding_vm: bool,
+}
+
+pub struct Stats {
+	last_clear: Instant,
+	last_display: Instant,
+	round: u32,
+	key_hash: Option<H256>,
+	hashrate: f64,
+	epochs: Vec<(H256, EpochHashrate)>,
+	last_metrics: Option<MiningMetrics>,
+	workers: Vec<(ThreadId, WorkerState)>,
+}
+
+impl Stats {
+	pub fn new() -> Stats {
+		Self {
+			last_clear: Instant::now(),
+			last_display: Instant::now(),
+			round: 0,
+			key_hash: None,
+			hashrate: 0.0,
+			epochs: Vec::new(),
+			last_metrics: None,
+			workers: Vec::new(),
+		}
+	}
+
+	/// State of each thread that mined so far, in the order they started.
+	pub fn worker_states(&self) -> Vec<WorkerState> {
+		self.workers.iter().map(|(_, state)| state.clone()).collect()
+	}
+
+	/// Mark the calling thread as preparing its machine for `key_hash`, or as
+	/// done with it.
+	pub fn set_rebuilding_vm(&mut self, key_hash: H256, rebuilding: bool) {
+		let worker = self.current_worker();
+		worker.key_hash = Some(key_hash);
+		worker.rebuilding_vm = rebuilding;
+	}
+
+	fn current_worker(&mut self) -> &mut WorkerState {
+		let current = thread::current();
+		let id = current.id();
+
+		let index = match self.workers.iter().position(|(worker, _)| *worker == id) {
+			Some(index) => index,
+			None => {
+				let name = current.name().map(|name| name.to_string())
+					.unwrap_or_else(|| format!("{:?}", id));
+				self.workers.push((id, WorkerState {
+					thread: name,
+					key_hash: None,
+					nonces: 0,
+					rebuilding_vm: false,
+				}));
+				self.workers.len() - 1
+			},
+		};
+
+		&mut self.workers[index].1
+	}
+
+	/// Metrics of the most recent mining call.
+	pub fn last_metrics(&self) -> Option<MiningMetrics> {
+		self.last_metrics
+	}
+
+	/// Account `hashes` mined under `key_hash` by the calling thread, in a round
+	/// that started at `started` and took `duration`.
+	pub fn record_round(&mut self, key_hash: H256, hashes: u64, started: Instant, duration: Duration) {
+		let worker = self.current_worker();
+		worker.key_hash = Some(key_hash);
+		worker.nonces += hashes;
+
+		match self.epochs.iter_mut().find(|(epoch, _)| *epoch == key_hash) {
+			Some((_, hashrate)) => hashrate.record(hashes, started, duration),
+			None => {
+				if self.epochs.len() >= MAX_HASHRATE_EPOCHS {
+					self.epochs.remove(0);
+				}
+				let mut hashrate = EpochHashrate::default();
+				hashrate.record(hashes, started, duration);
+				self.epochs.push((key_hash, hashrate));
+			},
+		}
+	}
+
+	/// Average local hashrate under each of the recent key hashes, in hashes per
+	/// second, oldest first. Rounds of concurrent threads add up, as their
+	/// hashes are spread over the wall-clock time any thread was mining.
+	pub fn hashrate_by_epoch(&self) -> Vec<(H256, f64)> {
+		self.epochs.iter()
+			.map(|(key_hash, hashrate)| (*key_hash, hashrate.hashes_per_second()))
+			.collect()
+	}
+}
+
+/// Hashes mined under one key hash, and the wall-clock time spent mining them.
+#[derive(Default)]
+struct EpochHashrate {
+	hashes: u64,
+	busy: Duration,
+	busy_until: Option<Instant>,
+}
+
+impl EpochHashrate {
+	fn record(&mut self, hashes: u64, started: Instant, duration: Duration) {
+		let ended = started + duration;
+		self.hashes += hashes;
+
+		// Only the part of the round after the rounds recorded so far adds to
+		// the time spent, so that overlapping rounds are not counted twice.
+		self.busy += match self.busy_until {
+			Some(busy_until) if busy_until >= ended => Duration::from_secs(0),
+			Some(busy_until) if busy_until > started => ended - busy_until,
+			_ => duration,
+		};
+		self.busy_until = Some(self.busy_until.map_or(ended, |busy_until| busy_until.max(ended)));
+	}
+
+	fn hashes_per_second(&self) -> f64 {
+		let secs = self.busy.as_secs_f64();
+		if secs > 0.0 { self.hashes as f64 / secs } else { 0.0 }
+	}
+}
+
+/// Metrics of a single mining call.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MiningMetrics {
+	/// Nonces the call tried, fewer than its round if it ended early.
+	pub hashes: u64,
+	/// When the call started.
+	pub started: Instant,
+	/// Wall-clock time spent in the call.
+	pub duration: Duration,
+	/// Machines rebuilt during the call. A steady-state session reports zero;
+	/// anything else indicates epoch churn or cache thrash.
+	pub vm_rebuilds: u32,
+}
+
+impl MiningMetrics {
+	/// Run `f` on the calling thread, measuring the machines it rebuilds. `f`
+	/// returns its result and the nonces it tried.
+	pub fn measure<T, F: FnOnce() -> (T, u64)>(f: F) -> (T, MiningMetrics) {
+		let builds = compute::local_vm_builds();
+		let started = Instant::now();
+
+		let (result, hashes) = f();
+
+		let metrics = MiningMetrics {
+			hashes,
+			started,
+			duration: started.elapsed(),
+			vm_rebuilds: (compute::local_vm_builds() - builds) as u32,
+		};
+
+		(result, metrics)
+	}
+}
+
+/// Round size for which one mining call takes about `recheck_interval` at the
+/// measured hashrate. Always at least one nonce.
+pub fn recommended_round(measured_hps: f64, recheck_interval: Duration) -> u32 {
+	let round = measured_hps * recheck_interval.as_secs_f64();
+
+	if round.is_nan() || round < 1.0 {
+		1
+	} else if round >= u32::max_value() as f64 {
+		u32::max_value()
+	} else {
+		round as u32
+	}
+}
+
+/// Default number of nonces tried between two polls of the cancellation flag.
+pub const DEFAULT_CANCEL_CHECK_INTERVAL: u32 = 64;
+
+/// Cancellation flag for mining, shared between miners and their controller.
+#[derive(Clone, Debug)]
+pub struct Cancellation {
+	cancelled: Arc<AtomicBool>,
+	check_interval: u32,
+}
+
+impl Cancellation {
+	pub fn new() -> Self {
+		Self::with_check_interval(DEFAULT_CANCEL_CHECK_INTERVAL)
+	}
+
+	/// Create a cancellation flag that is polled every `check_interval` nonces.
+	///
+	/// Lower values stop mining sooner after cancellation, higher values waste
+	/// less time on polling.
+	pub fn with_check_interval(check_interval: u32) -> Self {
+		Self {
+			cancelled: Arc::new(AtomicBool::new(false)),
+			check_interval: check_interval.max(1),
+		}
+	}
+
+	pub fn check_interval(&self) -> u32 {
+		self.check_interval
+	}
+
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	pub fn reset(&self) {
+		self.cancelled.store(false, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::SeqCst)
+	}
+
+	fn poller(&self) -> CancellationPoller {
+		CancellationPoller {
+			cancellation: self,
+			tried: Cell::new(0),
+		}
+	}
+}
+
+impl Default for Cancellation {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Counts tried nonces and only reads the cancellation flag every
+/// `check_interval` of them.
+struct CancellationPoller<'a> {
+	cancellation: &'a Cancellation,
+	tried: Cell<u32>,
+}
+
+impl<'a> CancellationPoller<'a> {
+	fn poll(&self) -> bool {
+		let tried = self.tried.get() + 1;
+
+		if tried >= self.cancellation.check_interval {
+			self.tried.set(0);
+			self.cancellation.is_cancelled()
+		} else {
+			self.tried.set(tried);
+			false
+		}
+	}
+}
+
+/// Nonces tried between two throttling sleeps.
+pub const THROTTLE_BATCH: u32 = 16;
+
+/// Share of a core a mining thread aims to use, above zero and at most one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CpuUtilization(f64);
+
+impl CpuUtilization {
+	/// `None` if `share` is not above zero and at most one.
+	pub fn new(share: f64) -> Option<Self> {
+		if share > 0.0 && share <= 1.0 {
+			Some(Self(share))
+		} else {
+			None
+		}
+	}
+
+	pub fn get(self) -> f64 {
+		self.0
+	}
+}
+
+/// Sleeps between batches of nonces, so that mining uses about the configured
+/// share of a core. This is approximate.
+struct CpuThrottle {
+	utilization: f64,
+	nonces: Cell<u32>,
+	batch_started: Cell<Instant>,
+	slept: Cell<Duration>,
+}
+
+impl CpuThrottle {
+	/// Throttle to `utilization`. `None` for a whole core, which needs no
+	/// throttling.
+	fn new(utilization: CpuUtilization) -> Option<Self> {
+		if utilization.get() >= 1.0 {
+			return None
+		}
+
+		Some(Self {
+			utilization: utilization.get(),
+			nonces: Cell::new(0),
+			batch_started: Cell::new(Instant::now()),
+			slept: Cell::new(Duration::default()),
+		})
+	}
+
+	/// Account for one nonce, sleeping once a batch is complete.
+	fn tick(&self) {
+		let nonces = self.nonces.get() + 1;
+		if nonces < THROTTLE_BATCH {
+			self.nonces.set(nonces);
+			return
+		}
+
+		let busy = self.batch_started.get().elapsed();
+		let pause = busy.mul_f64((1.0 - self.utilization) / self.utilization);
+		std::thread::sleep(pause);
+
+		self.slept.set(self.slept.get() + pause);
+		self.nonces.set(0);
+		self.batch_started.set(Instant::now());
+	}
+}
+
+/// Conditions on which a mining round stops before all of its nonces are tried.
+#[derive(Default)]
+struct Interrupt<'a> {
+	cancellation: Option<CancellationPoller<'a>>,
+	deadline: Option<Instant>,
+	/// Throttle ticked between two nonces of the round.
+	throttle: Option<CpuThrottle>,
+	/// Counter every hash of the round is checked against.
+	near_misses: Option<&'a NearMisses>,
+	/// Check of work against the target. [`is_valid_hash`] if not set.
+	target: Option<&'a dyn TargetChecker>,
+	/// Hashes checked so far.
+	hashes: Cell<u64>,
+}
+
+impl<'a> Interrupt<'a> {
+	fn is_interrupted(&self) -> bool {
+		if self.cancellation.as_ref().map(|c| c.poll()).unwrap_or(false) {
+			return true
+		}
+
+		self.deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false)
+	}
+
+	/// Sleep as needed by the throttle, before the next nonce of the round.
+	fn pace(&self) {
+		if let Some(throttle) = self.throttle.as_ref() {
+			throttle.tick();
+		}
+	}
+
+	fn target(&self) -> &dyn TargetChecker {
+		self.target.unwrap_or(&DefaultTargetChecker)
+	}
+
+	fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool {
+		self.hashes.set(self.hashes.get() + 1);
+		self.target().is_valid(work, difficulty)
+	}
+}
+
+/// Keystore pair for the author pre-digest, looked up among all mining keys
+/// of the keystore, so that several authors can be kept around.
+pub fn author_pair(keystore: &LocalKeystore, author: &app::Public) -> Option<app::Pair> {
+	let public = SyncCryptoStore::sr25519_public_keys(keystore, app::ID)
+		.into_iter()
+		.map(app::Public::from)
+		.find(|public| public == author)?;
+
+	keystore.key_pair::<app::Pair>(&public).ok()
+}
+
+/// What mining does when no keystore key matches the author pre-digest.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum AuthorMismatchPolicy {
+	/// Skip the round, warning at most once per author and interval.
+	Skip,
+	/// Fail the round with an error, so that misconfiguration is caught early.
+	Strict,
+}
+
+impl Default for AuthorMismatchPolicy {
+	fn default() -> Self {
+		AuthorMismatchPolicy::Skip
+	}
+}
+
+/// Interval between two warnings about the same mismatched author.
+pub const AUTHOR_MISMATCH_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Handles author mismatches according to a policy, rate-limiting warnings.
+pub struct AuthorMismatch {
+	policy: AuthorMismatchPolicy,
+	interval: Duration,
+	warned: Mutex<Vec<(app::Public, Instant)>>,
+}
+
+impl AuthorMismatch {
+	pub fn new(policy: AuthorMismatchPolicy) -> Self {
+		Self::with_interval(policy, AUTHOR_MISMATCH_WARN_INTERVAL)
+	}
+
+	/// Warn about the same author at most once per `interval`.
+	pub fn with_interval(policy: AuthorMismatchPolicy, interval: Duration) -> Self {
+		Self { policy, interval, warned: Mutex::new(Vec::new()) }
+	}
+
+	/// Whether a mismatch of `author` at `now` is to be logged.
+	fn should_warn(&self, author: &app::Public, now: Instant) -> bool {
+		let mut warned = self.warned.lock();
+		let interval = self.interval;
+		warned.retain(|(_, at)| now.saturating_duration_since(*at) < interval);
+
+		if warned.iter().any(|(warned, _)| warned == author) {
+			false
+		} else {
+			warned.push((author.clone(), now));
+			true
+		}
+	}
+
+	fn handle<B: BlockT, T>(
+		&self,
+		author: &app::Public,
+	) -> Result<Option<T>, sc_consensus_pow::Error<B>> {
+		match self.policy {
+			AuthorMismatchPolicy::Strict => Err(sc_consensus_pow::Error::Other(format!(
+				"Unable to mine: no keystore key matches the author pre-digest {:?}",
+				author,
+			))),
+			AuthorMismatchPolicy::Skip => {
+				if self.should_warn(author, Instant::now()) {
+					warn!(
+						target: LOG_TARGET,
+						"Not mining: no keystore key matches the author pre-digest {:?}",
+						author,
+					);
+				}
+				Ok(None)
+			},
+		}
+	}
+}
+
+/// Keystore signer of the seals of `author`, applying the policy of
+/// `author_mismatch` if the keystore has no such key. Seals are only valid if
+/// signed by the author of the pre-digest, so no other key is ever used.
+pub fn mining_signer<'a, B: BlockT>(
+	keystore: &'a LocalKeystore,
+	author: &app::Public,
+	author_mismatch: &AuthorMismatch,
+) -> Result<Option<KeystoreSigner<'a>>, sc_consensus_pow::Error<B>> {
+	if SyncCryptoStore::has_keys(keystore, &[(author.encode(), app::ID)]) {
+		Ok(Some(KeystoreSigner { keystore, public: author.clone() }))
+	} else {
+		author_mismatch.handle(author)
+	}
+}
+
+impl Default for AuthorMismatch {
+	fn default() -> Self {
+		Self::new(AuthorMismatchPolicy::default())
+	}
+}
+
+/// Signs the V2 seals of a mining author.
+pub trait SealSigner {
+	/// Sign the signing message of a seal. Errors are considered transient.
+	fn sign(&self, message: &[u8]) -> Result<app::Signature, String>;
+}
+
+impl SealSigner for app::Pair {
+	fn sign(&self, message: &[u8]) -> Result<app::Signature, String> {
+		Ok(sp_core::Pair::sign(self, message))
+	}
+}
+
+/// Signs with a key held by a keystore, without extracting its pair.
+pub struct KeystoreSigner<'a> {
+	pub keystore: &'a dyn SyncCryptoStore,
+	pub public: app::Public,
+}
+
+impl<'a> SealSigner for KeystoreSigner<'a> {
+	fn sign(&self, message: &[u8]) -> Result<app::Signature, String> {
+		let key = sp_core::crypto::CryptoTypePublicPair(
+			sp_core::sr25519::CRYPTO_ID,
+			self.public.encode(),
+		);
+		let signature = self.keystore.sign_with(app::ID, &key, message)
+			.map_err(|e| format!("{:?}", e))?;
+
+		compute::parse_signature(&signature[..]).map_err(|e| e.to_string())
+	}
+}
+
+/// Attempts at signing a nonce before moving on to the next one.
+pub const SIGN_ATTEMPTS: usize = 3;
+
+/// Sign `message`, retrying up to [`SIGN_ATTEMPTS`] times.
+fn sign_with_retry<S: SealSigner + ?Sized>(signer: &S, message: &[u8]) -> Option<app::Signature> {
+	for attempt in 1..=SIGN_ATTEMPTS {
+		match signer.sign(message) {
+			Ok(signature) => return Some(signature),
+			Err(e) => warn!(
+				target: LOG_TARGET,
+				"Signing seal failed (attempt {}/{}): {}",
+				attempt,
+				SIGN_ATTEMPTS,
+				e,
+			),
+		}
+	}
+
+	None
+}
+
+/// Try up to `round` random nonces for a V1 seal, returning the seal and its work.
+fn mine_v1_with<W: WorkBackend, R: Rng>(
+	backend: &W,
+	rng: &mut R,
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	round: u32,
+	interrupt: &Interrupt,
+	events: Option<&MiningEvents>,
+) -> Result<Option<(SealV1, H256)>, compute::ComputeError> {
+	if let Some(events) = events {
+		events.job(pre_hash, key_hash, difficulty);
+	}
+
+	backend.try_work_loop(
+		key_hash,
+		ComputeMode::Mining,
+		|| {
+			let nonce = H256::random_using(&mut *rng);
+
+			let compute = ComputeV1 {
+				key_hash: *key_hash,
+				difficulty,
+				pre_hash: *pre_hash,
+				nonce,
+			};
+
+			(compute.input().encode(), compute)
+		},
+		|work, compute| {
+			if let Some(events) = events {
+				events.work(pre_hash, &compute.nonce, &work, difficulty, interrupt.target());
+			}
+
+			if let Some(near_misses) = interrupt.near_misses {
+				near_misses.record(&work, difficulty);
+			}
+
+			if interrupt.is_valid(&work, compute.difficulty) {
+				compute::Loop::Break(Some((compute.seal(), work)))
+			} else if interrupt.is_interrupted() {
+				compute::Loop::Break(None)
+			} else {
+				interrupt.pace();
+				compute::Loop::Continue
+			}
+		},
+		round as usize,
+	)
+}
+
+/// Try up to `round` random nonces for a V2 seal, returning the seal and its work.
+///
+/// Nonces that cannot be signed are skipped without being hashed. The round ends
+/// once `round` nonces could not be signed.
+fn mine_v2_with<W: WorkBackend, R: Rng, S: SealSigner + ?Sized>(
+	backend: &W,
+	rng: &mut R,
+	key_hash: &H256,
+	pre_hash: &H256,
+	difficulty: Difficulty,
+	signer: &S,
+	domain: SigningDomain,
+	round: u32,
+	interrupt: &Interrupt,
+	events: Option<&MiningEvents>,
+) -> Result<Option<(SealV2, H256)>, compute::ComputeError> {
+	if let Some(events) = events {
+		events.job(pre_hash, key_hash, difficulty);
+	}
+
+	let unsigned = Cell::new(0u32);
+
+	backend.try_work_loop(
+		key_hash,
+		ComputeMode::Mining,
+		|| loop {
+			let nonce = H256::random_using(&mut *rng);
+
+			let compute = ComputeV2 {
+				key_hash: *key_hash,
+				difficulty,
+				pre_hash: *pre_hash,
+				nonce,
+			};
+
+			match sign_with_retry(signer, &compute.signing_message(domain)[..]) {
+				Some(signature) => {
+					let (calculation, signature) = compute.input(signature);
+					return (compute::randomx_input(&calculation, &signature), Some((compute, signature)))
+				},
+				None => {
+					unsigned.set(unsigned.get() + 1);
+					if unsigned.get() >= round || interrupt.is_interrupted() {
+						return (Vec::new(), None)
+					}
+				},
+			}
+		},
+		|work, signed| {
+			let (compute, signature) = match signed {
+				Some(signed) => signed,
+				None => return compute::Loop::Break(None),
+			};
+
+			if let Some(events) = events {
+				events.work(pre_hash, &compute.nonce, &work, difficulty, interrupt.target());
+			}
+
+			if let Some(near_misses) = interrupt.near_misses {
+				near_misses.record(&work, difficulty);
+			}
+
+			if interrupt.is_valid(&work, difficulty) {
+				compute::Loop::Break(Some((compute.seal(signature), work)))
+			} else if interrupt.is_interrupted() {
+				compute::Loop::Break(None)
+			} else {
+				interrupt.pace();
+				compute::Loop::Continue
+			}
+		},
+		round as usize,
+	)
+}
+
+/// Random number generator nonces are drawn from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NonceRng {
+	/// Fast non-cryptographic generator.
+	Fast,
+	/// Cryptographic generator, so that nonces do not reveal patterns that
+	/// could fingerprint the miner.
+	Secure,
+}
+
+impl Default for NonceRng {
+	fn default() -> Self {
+		NonceRng::Fast
+	}
+}
+
+impl NonceRng {
+	/// Create a generator of this kind seeded from `source`.
+	pub fn seeded_from<R: RngCore>(self, source: R) -> Result<Box<dyn RngCore + Send>, rand::Error> {
+		Ok(match self {
+			NonceRng::Fast => Box::new(SmallRng::from_rng(source)?),
+			NonceRng::Secure => Box::new(StdRng::from_rng(source)?),
+		})
+	}
+}
+
+/// Failure of a mining round.
+#[derive(Debug)]
+pub enum MineError<B: BlockT> {
+	/// Mining is suspended after repeated compute failures, until the cooldown
+	/// of the circuit breaker elapsed.
+	Suspended,
+	/// Computing work failed.
+	Compute(compute::ComputeError),
+	/// Reading the chain or runtime state of the round failed.
+	Consensus(sc_consensus_pow::Error<B>),
+}
+
+impl<B: BlockT> fmt::Display for MineError<B> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MineError::Suspended => write!(f, "{}", BreakerError::<compute::ComputeError>::Open),
+			MineError::Compute(e) => write!(f, "Computing work failed: {}", e),
+			MineError::Consensus(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl<B: BlockT> std::error::Error for MineError<B> { }
+
+impl<B: BlockT> From<sc_consensus_pow::Error<B>> for MineError<B> {
+	fn from(e: sc_consensus_pow::Error<B>) -> Self {
+		MineError::Consensus(e)
+	}
+}
+
+/// Run a mining round through `circuit_breaker`, if any. Failures anywhere in
+/// the round, preparing the machine or computing work, count towards tripping
+/// the breaker.
+fn through_breaker<B: BlockT, T, F>(
+	circuit_breaker: Option<&CircuitBreaker>,
+	mine_round: F,
+) -> Result<T, MineError<B>> where
+	F: FnOnce() -> Result<T, compute::ComputeError>,
+{
+	match circuit_breaker {
+		Some(breaker) => breaker.call(Instant::now(), mine_round),
+		None => mine_round().map_err(BreakerError::Failed),
+	}.map_err(|e| match e {
+		BreakerError::Open => MineError::Suspended,
+		BreakerError::Failed(e) => MineError::Compute(e),
+	})
+}
+
+/// Options of a mining round, other than the block being mined.
+#[derive(Clone, Copy)]
+pub struct MiningOptions<'a> {
+	pub stats: &'a Arc<Mutex<Stats>>,
+	pub author_mismatch: &'a AuthorMismatch,
+	pub cancellation: Option<&'a Cancellation>,
+	/// Time after which the round stops, even if nonces are left.
+	pub deadline: Option<Instant>,
+	pub events: Option<&'a MiningEvents>,
+	pub nonce_rng: NonceRng,
+	pub work_algo: WorkAlgo,
+	pub cpu_throttle: Option<CpuUtilization>,
+	pub circuit_breaker: Option<&'a CircuitBreaker>,
+	pub near_misses: Option<&'a NearMisses>,
+	pub time_to_block_warning: Option<&'a TimeToBlockWarning>,
+	/// Check of work against the target. [`is_valid_hash`] if not set.
+	pub target_checker: Option<&'a dyn TargetChecker>,
+}
+
+impl<'a> MiningOptions<'a> {
+	/// Options of an unrestricted RandomX round, accounted in `stats`.
+	pub fn new(stats: &'a Arc<Mutex<Stats>>, author_mismatch: &'a AuthorMismatch) -> Self {
+		Self {
+			stats,
+			author_mismatch,
+			cancellation: None,
+			deadline: None,
+			events: None,
+			nonce_rng: NonceRng::default(),
+			work_algo: WorkAlgo::default(),
+			cpu_throttle: None,
+			circuit_breaker: None,
+			near_misses: None,
+			time_to_block_warning: None,
+			target_checker: None,
+		}
+	}
+}
+
+pub fn mine<B, C>(
+	client: &C,
+	keystore: &LocalKeystore,
+	parent: &BlockId<B>,
+	pre_hash: &H256,
+	pre_digest: Option<&[u8]>,
+	difficulty: Difficulty,
+	round: u32,
+	options: &MiningOptions,
+) -> Result<Option<RawSeal>, MineError<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+	C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B>,
+{
+	mine_with_work(client, keystore, parent, pre_hash, pre_digest, difficulty, round, options)
+		.map(|mined| mined.map(|(seal, _)| seal))
+}
+
+/// Like [`mine`], also returning the work of the seal, so that callers
+/// reporting it do not need to recompute it.
+pub fn mine_with_work<B, C>(
+	client: &C,
+	keystore: &LocalKeystore,
+	parent: &BlockId<B>,
+	pre_hash: &H256,
+	pre_digest: Option<&[u8]>,
+	difficulty: Difficulty,
+	round: u32,
+	options: &MiningOptions,
+) -> Result<Option<(RawSeal, H256)>, MineError<B>> where
+	B: BlockT<Hash=H256>,
+	C: HeaderBackend<B> + AuxStore + ProvideRuntimeApi<B>,
+	C::Api: DifficultyApi<B, Difficulty> + AlgorithmApi<B> + KeyHashApi<B>,
+{
+	let MiningOptions {
+		stats,
+		author_mismatch,
+		cancellation,
+		deadline,
+		events,
+		nonce_rng,
+		work_algo,
+		cpu_throttle,
+		circuit_breaker,
+		near_misses,
+		time_to_block_warning,
+		target_checker,
+	} = *options;
+
+	if cancellation.map(|c| c.is_cancelled()).unwrap_or(false) {
+		return Ok(None)
+	}
+
+	if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+		return Ok(None)
+	}
+
+	let version = algorithm_version_for(client, parent, work_algo)?;
+
+	let mut rng = nonce_rng.seeded_from(&mut thread_rng())
+		.map_err(|e| sc_consensus_pow::Error::Environment(
+			format!("Initialize RNG failed for mining: {:?}", e)
+		))?;
+	let params = key_hash_params(client, parent)?;
+	let key_hash = key_hash_selection_with(client, parent, &params)?.key_hash;
+
+	let pre_digest = pre_digest.ok_or(sc_consensus_pow::Error::<B>::Other(
+		"Unable to mine: pre-digest not set".to_string(),
+	))?;
+
+	let author = decode_author(version.pre_digest_format(), pre_digest).ok_or_else(|| {
+		sc_consensus_pow::Error::<B>::Other(
+			"Unable to mine: author pre-digest decoding failed".to_string(),
+		)
+	})?;
+
+	let signer = match mining_signer(keystore, &author, author_mismatch)? {
+		Some(signer) => signer,
+		None => return Ok(None),
+	};
+
+	let interrupt = Interrupt {
+		cancellation: cancellation.map(|c| c.poller()),
+		deadline,
+		throttle: cpu_throttle.and_then(CpuThrottle::new),
+		near_misses,
+		target: target_checker,
+		..Default::default()
+	};
+
+	let mine_round = || -> Result<_, compute::ComputeError> {
+		if work_algo == WorkAlgo::RandomX {
+			stats.lock().set_rebuilding_vm(key_hash, true);
+			let prepared = compute::try_warm(&key_hash, ComputeMode::Mining);
+			stats.lock().set_rebuilding_vm(key_hash, false);
+			prepared?;
+		}
+
+		let (mined, metrics) = MiningMetrics::measure(|| (match version {
+			RandomXAlgorithmVersion::V1 => mine_v1_with(
+				&work_algo,
+				&mut rng,
+				&key_hash,
+				pre_hash,
+				difficulty,
+				round,
+				&interrupt,
+				events,
+			).map(|mined| mined.map(|(seal, work)| (seal.encode(), work))),
+			RandomXAlgorithmVersion::V2 | RandomXAlgorithmVersion::V3 => mine_v2_with(
+				&work_algo,
+				&mut rng,
+				&key_hash,
+				pre_hash,
+				difficulty,
+				&signer,
+				version.signing_domain(),
+				round,
+				&interrupt,
+				events,
+			).map(|mined| mined.map(|(seal, work)| (seal.encode(), work))),
+		}, interrupt.hashes.get()));
+
+		mined.map(|mined| (mined, metrics))
+	};
+
+	let (maybe_seal, metrics) = through_breaker(circuit_breaker, mine_round)?;
+
+	if metrics.vm_rebuilds > 0 {
+		debug!(
+			target: LOG_TARGET,
+			"Mining round rebuilt {} machine(s) under key hash {}",
+			metrics.vm_rebuilds,
+			key_hash,
+		);
+	}
+
+	let now = Instant::now();
+
+	let maybe_display = {
+		let mut stats = stats.lock();
+		stats.record_round(key_hash, metrics.hashes, metrics.started, metrics.duration);
+		stats.last_metrics = Some(metrics);
+
+		let since_last_clear = now.checked_duration_since(stats.last_clear);
+		let since_last_display = now.checked_duration_since(stats.last_display);
+
+		if let (Some(since_last_clear), Some(since_last_display)) =
+			(since_last_clear, since_last_display)
+		{
+			let mut ret = None;
+
+			stats.round += metrics.hashes as u32;
+			stats.key_hash = Some(key_hash);
+			let duration = since_last_clear;
+
+			let clear = duration >= Duration::new(600, 0);
+			let display = clear || since_last_display >= Duration::new(2, 0);
+
+			if display {
+				stats.last_display = now;
+				stats.hashrate = stats.round as f64 / duration.as_secs_f64();
+				ret = Some((duration, stats.round));
+			}
+
+			if clear {
+				stats.last_clear = now;
+				stats.round = 0;
+			}
+
+			ret
+		} else {
+			warn!(
+				target: LOG_TARGET,
+				"Calculating duration failed, the system time may have changed and the hashrate calculation may be temporarily inaccurate."
+			);
+
+			None
+		}
+	};
+
+	if let Some((duration, round)) = maybe_display {
+		if let Some(warning) = time_to_block_warning {
+			warning.check(difficulty, round as f64 / duration.as_secs_f64());
+		}
+
+		let hashrate = round / duration.as_secs() as u32;
+		let network_hashrate = difficulty / U256::from(60);
+		let every: u32 = (network_hashrate / U256::from(hashrate)).unique_saturated_into();
+		let every_duration = Duration::new(60, 0) * every;
+		info!(
+			target: LOG_TARGET,
+			"Local hashrate: {} H/s, network hashrate: {} H/s, expected one block every {} ({} blocks)",
+			hashrate,
+			network_hashrate,
+			humantime::format_duration(every_duration).to_string(),
+			every,
+		);
+	}
+
+	Ok(maybe_seal)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Pair;
+	use crate::mock::CountingBackend;
+
+	fn test_compute() -> ComputeV2 {
+		ComputeV2 {
+			key_hash: H256::repeat_byte(1),
+			pre_hash: H256::repeat_byte(2),
+			difficulty: U256::one(),
+			nonce: H256::repeat_byte(3),
+		}
+	}
+
+	#[test]
+	fn algorithm_version_follows_era() {
+		let identifier_at = |number: u64| if number < 10 {
+			kulupu_primitives::ALGORITHM_IDENTIFIER_V1
+		} else {
+			kulupu_primitives::ALGORITHM_IDENTIFIER_V2
+		};
+
+		for number in 0..20 {
+			let version = RandomXAlgorithmVersion::from_identifier(&identifier_at(number))
+				.expect("Identifier is known");
+			let expected = if number < 10 {
+				RandomXAlgorithmVersion::V1
+			} else {
+				RandomXAlgorithmVersion::V2
+			};
+
+			assert_eq!(version, expected);
+		}
+
+		assert_eq!(
+			RandomXAlgorithmVersion::from_identifier(b"randomx3"),
+			Some(RandomXAlgorithmVersion::V3),
+		);
+		assert_eq!(RandomXAlgorithmVersion::from_identifier(b"randomx4"), None);
+	}
+
+	#[test]
+	fn key_block_selection_is_logged() {
+		use crate::mock::{MockClient, TestBlock, capture_logs};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let logs = capture_logs();
+		let client = Arc::new(MockClient::new(4300, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+
+		// Parents on either side of the offset into the second period.
+		for (parent_number, key_number) in [(4223, 0), (4224, 4096), (4300, 4096)].iter() {
+			let parent_hash = client.chain.hash(*parent_number);
+			let parent = BlockId::<TestBlock>::Hash(parent_hash);
+			let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+				.unwrap()
+				.expect("A nonce meeting difficulty 4 is found among 1000");
+			assert_eq!(
+				algorithm.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty).unwrap(),
+				VerifyOutcome::Valid,
+			);
+
+			let expected = format!(
+				"Verifying seal: parent {} (#{}) selects key block #{} with key hash {}",
+				parent_hash,
+				parent_number,
+				key_number,
+				client.chain.hash(*key_number),
+			);
+			assert!(logs.records().into_iter().any(|(target, level, message)| {
+				target == LOG_TARGET && level == log::Level::Debug && message == expected
+			}));
+		}
+	}
+
+	#[test]
+	fn pruned_key_block_is_reported() {
+		use crate::mock::{MockChain, TestBlock};
+
+		let mut chain = MockChain::new(4300);
+		let parent = BlockId::<TestBlock>::Number(4300);
+
+		let selection = try_key_hash_selection(&chain, &parent).unwrap();
+		assert_eq!(selection.key_number, 4096);
+		assert_eq!(selection.key_hash, chain.hash(4096));
+
+		chain.prune(4096);
+		assert_eq!(chain.info().best_number, 4300);
+		assert_eq!(
+			chain.status(BlockId::Hash(chain.hash(4096))).unwrap(),
+			sp_blockchain::BlockStatus::Unknown,
+		);
+		assert_eq!(
+			try_key_hash_selection(&chain, &parent),
+			Err(KeyHashError::PrunedAncestor(chain.hash(4096))),
+		);
+		assert_eq!(
+			try_key_hash_selection(&chain, &BlockId::<TestBlock>::Number(5000)),
+			Err(KeyHashError::ParentNotFound),
+		);
+
+		let pruned = key_hash_selection(&chain, &parent).unwrap_err();
+		assert_eq!(pruned_ancestor(&pruned), Some(chain.hash(4096)));
+		let missing = key_hash_selection(&chain, &BlockId::<TestBlock>::Number(5000)).unwrap_err();
+		assert_eq!(pruned_ancestor(&missing), None);
+	}
+
+	#[test]
+	fn checkpoint_resolves_key_block_across_gap() {
+		use crate::mock::{MockChain, TestBlock};
+
+		let mut chain = MockChain::new(4300);
+		let parent = BlockId::<TestBlock>::Number(4300);
+		let key_hash = chain.hash(4096);
+		for number in 4000..4200 {
+			chain.prune(number);
+		}
+
+		let params = DEFAULT_KEY_HASH_PARAMS;
+		assert_eq!(
+			try_key_hash_selection_with_checkpoints(&chain, &parent, &params, &Checkpoints::default()),
+			Err(KeyHashError::PrunedAncestor(chain.hash(4199))),
+		);
+
+		let checkpoints = Checkpoints::new(vec![(4096, key_hash)]);
+		let selection = try_key_hash_selection_with_checkpoints(&chain, &parent, &params, &checkpoints).unwrap();
+		assert_eq!(selection.key_number, 4096);
+		assert_eq!(selection.key_hash, key_hash);
+
+		let elsewhere = Checkpoints::new(vec![(0, chain.hash(0))]);
+		assert!(try_key_hash_selection_with_checkpoints(&chain, &parent, &params, &elsewhere).is_err());
+	}
+
+	#[test]
+	fn key_block_follows_custom_schedule() {
+		use crate::mock::{MockChain, TestBlock};
+
+		let chain = MockChain::new(300);
+		let params = KeyHashParams { period: 100, offset: 10 };
+
+		for (parent_number, expected) in &[(105, 0), (110, 100), (209, 100), (210, 200)] {
+			let selection = try_key_hash_selection_with(
+				&chain, &BlockId::<TestBlock>::Number(*parent_number), &params,
+			).unwrap();
+
+			assert_eq!(selection.key_number, *expected);
+			assert_eq!(selection.key_hash, chain.hash(*expected));
+		}
+
+		assert_eq!(key_number_with(&KeyHashParams { period: 0, offset: 10 }, 250), 0);
+	}
+
+	#[test]
+	fn verification_follows_runtime_key_block_schedule() {
+		use crate::mock::{MapKeyHashCache, MockClient, TestBlock};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		client.runtime.set_key_hash_params(KeyHashParams { period: 8, offset: 2 });
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+
+		let parent_hash = client.chain.hash(20);
+		let parent = BlockId::<TestBlock>::Hash(parent_hash);
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+
+		let cache = MapKeyHashCache::default();
+		let outcome = algorithm.verify_with_key_hash_cache(
+			&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty, Some(&cache),
+		).unwrap();
+		assert_eq!(outcome, VerifyOutcome::Valid);
+		// Under the default schedule, children of #20 would be keyed by genesis.
+		assert_eq!(cache.entries().get(&parent_hash), Some(&client.chain.hash(16)));
+		assert!(client.runtime.key_hash_params_calls.load(Ordering::SeqCst) >= 2);
+	}
+
+	#[test]
+	fn seal_is_rejected_when_runtime_records_another_key_hash() {
+		use crate::mock::{MockClient, TestBlock};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+		let verify = || algorithm.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty)
+			.unwrap();
+
+		assert_eq!(verify(), VerifyOutcome::Valid);
+
+		client.runtime.set_expected_key_hash(Some(client.chain.hash(0)));
+		assert_eq!(verify(), VerifyOutcome::Valid);
+
+		client.runtime.set_expected_key_hash(Some(H256::repeat_byte(9)));
+		assert_eq!(verify(), VerifyOutcome::Invalid(InvalidReason::KeyHashMismatch));
+		assert_eq!(client.runtime.expected_key_hash_calls.load(Ordering::SeqCst), 3);
+	}
+
+	#[test]
+	fn builder_verify_threads_run_verification_on_pool() {
+		use crate::mock::{MockClient, TestBlock};
+
+		#[derive(Default)]
+		struct ThreadRecording {
+			threads: Mutex<Vec<Option<String>>>,
+			panics: AtomicBool,
+		}
+
+		impl TargetChecker for ThreadRecording {
+			fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool {
+				if self.panics.load(Ordering::SeqCst) {
+					panic!("Target check panics");
+				}
+				self.threads.lock().push(std::thread::current().name().map(|name| name.to_string()));
+				is_valid_hash(work, difficulty)
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let checker = Arc::new(ThreadRecording::default());
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.target_checker(Some(checker.clone()))
+			.verify_threads(Some(2))
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+		checker.threads.lock().clear();
+
+		let outcome = algorithm.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty)
+			.unwrap();
+		assert_eq!(outcome, VerifyOutcome::Valid);
+		let threads = checker.threads.lock().clone();
+		assert!(!threads.is_empty());
+		assert!(threads.iter().all(|name| {
+			name.as_ref().map_or(false, |name| name.starts_with("pow-verify-"))
+		}));
+
+		// A panicking verification job fails the import instead of the
+		// importing thread.
+		checker.panics.store(true, Ordering::SeqCst);
+		assert!(algorithm.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty)
+			.is_err());
+	}
+
+	#[test]
+	fn verification_with_populated_cache_skips_walk_and_schedule() {
+		use crate::mock::{MapKeyHashCache, MockClient, TestBlock};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let miner = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let parent_hash = miner.chain.hash(20);
+		let parent = BlockId::<TestBlock>::Hash(parent_hash);
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+		let seal = RandomXAlgorithm::builder(miner.clone())
+			.work_algo(WorkAlgo::Blake2Test)
+			.build()
+			.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+
+		// The verifier has pruned every block between the parent and the key block.
+		let mut pruned = MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST);
+		for number in 1..20 {
+			pruned.chain.prune(number);
+		}
+		let verifier = Arc::new(pruned);
+		let algorithm = RandomXAlgorithm::builder(verifier.clone())
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+		let verify = |cache: &MapKeyHashCache| algorithm.verify_with_key_hash_cache(
+			&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty, Some(cache),
+		);
+
+		let err = verify(&MapKeyHashCache::default()).unwrap_err();
+		assert_eq!(pruned_ancestor(&err), Some(verifier.chain.hash(19)));
+		let calls = verifier.runtime.key_hash_params_calls.load(Ordering::SeqCst);
+
+		let cache = MapKeyHashCache::default();
+		cache.insert(parent_hash, verifier.chain.hash(0));
+		assert_eq!(verify(&cache).unwrap(), VerifyOutcome::Valid);
+		assert_eq!(verifier.runtime.key_hash_params_calls.load(Ordering::SeqCst), calls);
+	}
+
+	#[test]
+	fn caller_key_hash_cache_skips_chain_walk() {
+		use std::cell::RefCell;
+		use std::collections::HashMap;
+		use crate::mock::{MockChain, TestBlock};
+
+		#[derive(Default)]
+		struct MapCache(RefCell<HashMap<H256, H256>>);
+
+		impl KeyHashCache for MapCache {
+			fn get(&self, parent_hash: &H256) -> Option<H256> {
+				self.0.borrow().get(parent_hash).cloned()
+			}
+
+			fn insert(&self, parent_hash: H256, key_hash: H256) {
+				self.0.borrow_mut().insert(parent_hash, key_hash);
+			}
+		}
+
+		let chain = MockChain::new(150);
+		let parent = BlockId::<TestBlock>::Hash(chain.hash(150));
+		let walks = Cell::new(0);
+		let walk = || {
+			walks.set(walks.get() + 1);
+			try_key_hash_selection(&chain, &parent).map(|selection| selection.key_hash)
+		};
+
+		let cache = MapCache::default();
+		cache.insert(chain.hash(150), H256::repeat_byte(7));
+		assert_eq!(cached_key_hash(Some(&cache), &parent, walk), Ok(H256::repeat_byte(7)));
+		assert_eq!(walks.get(), 0);
+
+		let empty = MapCache::default();
+		assert_eq!(cached_key_hash(Some(&empty), &parent, walk), Ok(chain.hash(0)));
+		assert_eq!(cached_key_hash(Some(&empty), &parent, walk), Ok(chain.hash(0)));
+		assert_eq!(walks.get(), 1);
+
+		let by_number = BlockId::<TestBlock>::Number(150);
+		assert_eq!(cached_key_hash(Some(&cache), &by_number, walk), Ok(chain.hash(0)));
+		assert_eq!(walks.get(), 2);
+	}
+
+	#[test]
+	fn key_hash_for_number_follows_epochs() {
+		use crate::mock::{MockChain, TestBlock};
+
+		let chain = MockChain::new(300);
+		let params = KeyHashParams { period: 100, offset: 10 };
+
+		for (number, expected) in &[(1, 0), (110, 0), (111, 100), (210, 100), (211, 200), (300, 200)] {
+			assert_eq!(
+				key_hash_for_number_with::<TestBlock, _>(&chain, *number, &params).unwrap(),
+				chain.hash(*expected),
+				"block #{}", number,
+			);
+		}
+
+		assert!(key_hash_for_number_with::<TestBlock, _>(&chain, 0, &params).is_err());
+		assert!(key_hash_for_number_with::<TestBlock, _>(&chain, 302, &params).is_err());
+	}
+
+	#[test]
+	fn key_hash_for_number_follows_runtime_schedule() {
+		use crate::mock::{MockClient, TestBlock};
+
+		let client = MockClient::new(300, kulupu_primitives::ALGORITHM_IDENTIFIER_V2);
+		assert_eq!(key_hash_for_number::<TestBlock, _>(&client, 300).unwrap(), client.chain.hash(0));
+
+		client.runtime.set_key_hash_params(KeyHashParams { period: 100, offset: 10 });
+		assert_eq!(key_hash_for_number::<TestBlock, _>(&client, 300).unwrap(), client.chain.hash(200));
+		assert_eq!(key_hash_for_number::<TestBlock, _>(&client, 111).unwrap(), client.chain.hash(100));
+		assert!(key_hash_for_number::<TestBlock, _>(&client, 0).is_err());
+	}
+
+	#[test]
+	fn key_block_near_genesis_is_genesis() {
+		use crate::mock::{MockChain, TestBlock};
+
+		let KeyHashParams { period, offset } = DEFAULT_KEY_HASH_PARAMS;
+		let chain = MockChain::new(period + offset);
+
+		let cases = [
+			(0, 0),
+			(1, 0),
+			(offset - 1, 0),
+			(offset, 0),
+			(period - 1, 0),
+			(period, 0),
+			(period + offset - 1, 0),
+			(period + offset, period),
+		];
+
+		for (parent_number, expected) in &cases {
+			assert_eq!(key_number(*parent_number), *expected, "parent #{}", parent_number);
+
+			let selection = try_key_hash_selection(
+				&chain, &BlockId::<TestBlock>::Number(*parent_number),
+			).unwrap();
+			assert_eq!(selection.key_number, *expected);
+			assert_eq!(selection.key_hash, chain.hash(*expected));
+		}
+	}
+
+	#[test]
+	fn builder_applies_options() {
+		let algorithm = RandomXAlgorithm::builder(Arc::new(()))
+			.cancel_check_interval(8)
+			.round_timeout(Some(Duration::from_secs(1)))
+			.build();
+		assert_eq!(algorithm.cancellation().check_interval(), 8);
+		assert_eq!(algorithm.round_timeout, Some(Duration::from_secs(1)));
+	}
+
+	#[test]
+	fn config_snapshot_reflects_builder() {
+		let _guard = crate::mock::lock_shared_caches();
+
+		let algorithm = RandomXAlgorithm::builder(Arc::new(()))
+			.nonce_rng(NonceRng::Secure)
+			.verify_threads(Some(2))
+			.check_retarget(true)
+			.near_miss_threshold(Some(U256::from(1000)))
+			.checkpoints(Checkpoints::new(vec![(4, H256::repeat_byte(4))]))
+			.build();
+		let config = algorithm.config_snapshot();
+
+		assert_eq!(config.light_cache_capacity, compute::DEFAULT_LIGHT_CACHE_CAPACITY);
+		assert_eq!(config.full_cache_capacity, compute::DEFAULT_FULL_CACHE_CAPACITY);
+		assert_eq!(config.nonce_rng, NonceRng::Secure);
+		assert_eq!(config.verify_threads, Some(2));
+		assert!(config.check_retarget);
+		assert!(!config.light_mining);
+		assert_eq!(config.near_miss_threshold, Some(U256::from(1000)));
+		assert_eq!(config.checkpoints, 1);
+		assert_eq!(config.network_salt, None);
+
+		let json = serde_json::to_string(&config).unwrap();
+		assert!(json.contains(&format!(
+			"\"lightCacheCapacity\":{}", compute::DEFAULT_LIGHT_CACHE_CAPACITY,
+		)));
+		assert_eq!(serde_json::from_str::<AlgorithmConfig>(&json).unwrap(), config);
+	}
+
+	#[test]
+	fn cached_key_hashes_follow_evictions() {
+		let _guard = crate::mock::lock_shared_caches();
+		compute::flush_caches();
+
+		let algorithm = RandomXAlgorithm::new(Arc::new(()));
+		let (first, second) = (H256::repeat_byte(1), H256::repeat_byte(2));
+
+		compute::compute(&first, &0u64, ComputeMode::Sync);
+		compute::compute(&second, &0u64, ComputeMode::Sync);
+		let cached = algorithm.cached_key_hashes();
+		assert_eq!(cached.len(), 2);
+		assert!(cached.contains(&first) && cached.contains(&second));
+
+		compute::set_cache_capacity(1, 1);
+		assert_eq!(algorithm.cached_key_hashes(), vec![second]);
+
+		compute::set_cache_capacity(
+			compute::DEFAULT_FULL_CACHE_CAPACITY,
+			compute::DEFAULT_LIGHT_CACHE_CAPACITY,
+		);
+		compute::flush_caches();
+	}
+
+	#[test]
+	fn invalid_signature_never_computes_work() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let compute = test_compute();
+
+		let seal = compute.seal(compute.sign(&other)).encode();
+		assert!(!verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+			compute.difficulty, SigningDomain::Legacy, &seal[..],
+		));
+		assert_eq!(backend.calls(), 0);
+
+		let seal = compute.seal(compute.sign(&author)).encode();
+		assert!(verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+			compute.difficulty, SigningDomain::Legacy, &seal[..],
+		));
+		assert_eq!(backend.calls(), 1);
+	}
+
+	#[test]
+	fn signature_only_verification_skips_work() {
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+
+		let mut seal = compute.seal(compute.sign_in(&author, SigningDomain::V2));
+		assert!(verify_signature_only(
+			&compute.pre_hash, &author.public(), compute.difficulty, SigningDomain::V2,
+			&seal.encode()[..],
+		));
+
+		seal.signature = app::Signature::decode(&mut &[0u8; 64][..]).unwrap();
+		assert!(!verify_signature_only(
+			&compute.pre_hash, &author.public(), compute.difficulty, SigningDomain::V2,
+			&seal.encode()[..],
+		));
+	}
+
+	#[test]
+	fn share_verified_against_share_difficulty() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+		let seal = compute.seal(compute.sign(&author)).encode();
+
+		assert!(verify_share_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+			compute.difficulty, U256::one(), SigningDomain::Legacy, &seal[..],
+		));
+		assert!(!verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+			compute.difficulty, SigningDomain::Legacy, &seal[..],
+		));
+	}
+
+	#[test]
+	fn sealed_header_validation_reports_each_failure() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let compute = test_compute();
+		let pre_digest = author.public().encode();
+		let seal = compute.seal(compute.sign(&author)).encode();
+
+		let validate = |pre_digest: Option<&[u8]>, seal: &[u8], difficulty| {
+			validate_sealed_header_with(
+				&backend, &DefaultTargetChecker, &compute.pre_hash, pre_digest, seal, difficulty,
+				&compute.key_hash, SigningDomain::Legacy, PreDigestFormat::Raw,
+			)
+		};
+
+		assert_eq!(validate(Some(&pre_digest[..]), &seal[..], compute.difficulty), Ok(()));
+		assert_eq!(
+			validate(None, &seal[..], compute.difficulty),
+			Err(ValidationError::MissingPreDigest),
+		);
+		assert_eq!(
+			validate(Some(&[1, 2, 3][..]), &seal[..], compute.difficulty),
+			Err(ValidationError::InvalidAuthor),
+		);
+		assert_eq!(
+			validate(Some(&pre_digest[..]), &seal[1..], compute.difficulty),
+			Err(ValidationError::MalformedSeal),
+		);
+		assert_eq!(
+			validate(Some(&pre_digest[..]), &seal[..], U256::from(2)),
+			Err(ValidationError::DifficultyMismatch),
+		);
+
+		let other_seal = compute.seal(compute.sign(&other)).encode();
+		assert_eq!(
+			validate(Some(&pre_digest[..]), &other_seal[..], compute.difficulty),
+			Err(ValidationError::BadSignature),
+		);
+
+		let hard = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+		let hard_seal = hard.seal(hard.sign(&author)).encode();
+		assert_eq!(
+			validate(Some(&pre_digest[..]), &hard_seal[..], hard.difficulty),
+			Err(ValidationError::InsufficientWork),
+		);
+	}
+
+	#[test]
+	fn parallel_verification_preserves_order() {
+		let backend = Arc::new(CountingBackend::default());
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+
+		let seal_to_verify = |key_hash: u8, signer: &app::Pair| {
+			let compute = ComputeV2 { key_hash: H256::repeat_byte(key_hash), ..test_compute() };
+
+			SealToVerify {
+				key_hash: compute.key_hash,
+				pre_hash: compute.pre_hash,
+				difficulty: compute.difficulty,
+				author: Some(author.public()),
+				domain: SigningDomain::Legacy,
+				seal: compute.seal(compute.sign(signer)).encode(),
+			}
+		};
+
+		let seals = vec![
+			seal_to_verify(1, &author),
+			seal_to_verify(2, &other),
+			seal_to_verify(1, &other),
+			seal_to_verify(2, &author),
+			seal_to_verify(1, &author),
+		];
+
+		assert_eq!(
+			verify_parallel_with(backend.clone(), seals, 2),
+			vec![true, false, false, true, true],
+		);
+		assert_eq!(backend.calls(), 3);
+	}
+
+	#[test]
+	fn parallel_verification_reports_panicked_thread_invalid() {
+		struct PanicsOnKey(H256);
+
+		impl WorkBackend for PanicsOnKey {
+			fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+				assert_ne!(*key_hash, self.0, "Machine for the key hash failed");
+				H256::from(sp_core::blake2_256(&(key_hash, input).encode()[..]))
+			}
+		}
+
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let seal_to_verify = |key_hash: u8| {
+			let compute = ComputeV2 { key_hash: H256::repeat_byte(key_hash), ..test_compute() };
+
+			SealToVerify {
+				key_hash: compute.key_hash,
+				pre_hash: compute.pre_hash,
+				difficulty: compute.difficulty,
+				author: Some(author.public()),
+				domain: SigningDomain::Legacy,
+				seal: compute.seal(compute.sign(&author)).encode(),
+			}
+		};
+
+		// The seals of key hash 2 are verified on a thread of their own.
+		let seals = vec![seal_to_verify(1), seal_to_verify(2), seal_to_verify(1)];
+		assert_eq!(
+			verify_parallel_with(Arc::new(PanicsOnKey(H256::repeat_byte(2))), seals, 2),
+			vec![true, false, true],
+		);
+	}
+
+	#[test]
+	fn external_seal_is_verified_against_given_key() {
+		let backend = CountingBackend::default();
+		let signer = sp_core::sr25519::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let seal = compute.seal(compute.sign(&app::Pair::from(signer.clone()))).encode();
+
+		let verify = |author: &sp_core::sr25519::Public| verify_external_with(
+			&backend, &compute.key_hash, &compute.pre_hash, author,
+			compute.difficulty, SigningDomain::Legacy, &seal[..],
+		);
+
+		assert!(verify(&signer.public()));
+		assert!(!verify(&sp_core::sr25519::Pair::from_seed(&[2u8; 32]).public()));
+	}
+
+	#[test]
+	fn self_check_catches_diverging_work() {
+		struct Miscompiled;
+
+		impl WorkBackend for Miscompiled {
+			fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+				H256::from(sp_core::blake2_256(&(input, key_hash).encode()[..]))
+			}
+		}
+
+		let reference = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let key_hash = H256::repeat_byte(1);
+		let pre_hash = H256::repeat_byte(2);
+		let difficulty = U256::from(1000);
+
+		// A block known to be good under the reference library.
+		let (seal, _) = mine_v2_with(
+			&reference,
+			&mut SmallRng::seed_from_u64(0),
+			&key_hash,
+			&pre_hash,
+			difficulty,
+			&pair,
+			SigningDomain::V2,
+			100_000,
+			&Interrupt::default(),
+			None,
+		).unwrap().expect("Difficulty 1000 is met within 100000 nonces");
+		let seal = seal.encode();
+
+		assert!(self_check_against_block_with(
+			&reference, &key_hash, &pre_hash, &pair.public(), difficulty, &seal[..],
+		));
+		assert!(!self_check_against_block_with(
+			&Miscompiled, &key_hash, &pre_hash, &pair.public(), difficulty, &seal[..],
+		));
+	}
+
+	#[test]
+	fn canonical_seal_strips_trailing_bytes() {
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let seal = compute.seal(compute.sign(&author)).encode();
+
+		let mut padded = seal.clone();
+		padded.extend_from_slice(&[0xaa; 5]);
+
+		assert_eq!(canonicalize_seal(&seal[..]), Some(seal.clone()));
+		assert_eq!(canonicalize_seal(&padded[..]), Some(seal.clone()));
+		assert_eq!(canonicalize_seal(&seal[..seal.len() - 1]), None);
+	}
+
+	#[test]
+	fn seal_author_is_the_signing_key() {
+		let backend = CountingBackend::default();
+		let signer = sp_core::sr25519::Pair::from_seed(&[1u8; 32]);
+		let author = app::Pair::from(signer.clone());
+		let compute = test_compute();
+		let seal = compute.seal(compute.sign(&author)).encode();
+
+		let digests = [
+			(PreDigestFormat::Raw, author.public().encode()),
+			(PreDigestFormat::Versioned, encode_author_digest(&author.public())),
+		];
+		for (format, digest) in digests.iter() {
+			let recipient = seal_author(*format, &digest[..]).unwrap();
+			assert_eq!(recipient, signer.public());
+			assert!(verify_external_with(
+				&backend, &compute.key_hash, &compute.pre_hash, &recipient,
+				compute.difficulty, SigningDomain::Legacy, &seal[..],
+			));
+		}
+
+		assert_eq!(seal_author(PreDigestFormat::Raw, &[0u8; 3][..]), None);
+	}
+
+	#[test]
+	fn seal_v2_verification_uses_claimed_difficulty() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let seal = compute.seal(compute.sign(&author)).encode();
+
+		let verify = |difficulty| verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author.public(), difficulty,
+			SigningDomain::Legacy, &seal[..],
+		);
+
+		assert!(verify(U256::one()));
+		// The seal is signed over its difficulty, so a wrong claim never verifies.
+		assert!(!verify(U256::from(2)));
+		assert!(!verify(U256::zero()));
+		assert_eq!(backend.calls(), 1);
+	}
+
+	#[test]
+	fn recompute_work_ignores_difficulty() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+		let signature = compute.sign(&author);
+		let (seal, work) = compute.seal_and_work_with(&backend, signature, ComputeMode::Sync);
+		let seal = seal.encode();
+
+		let recompute = |author: &app::Public| recompute_work_with(
+			&backend, &compute.key_hash, &compute.pre_hash, author,
+			compute.difficulty, SigningDomain::Legacy, &seal[..],
+		);
+
+		assert!(!is_valid_hash(&work, compute.difficulty));
+		assert_eq!(recompute(&author.public()), Some(work));
+		assert_eq!(recompute(&app::Pair::from_seed(&[2u8; 32]).public()), None);
+	}
+
+	#[test]
+	fn segment_work_sums_valid_difficulties() {
+		let segment = [
+			(U256::from(10), true),
+			(U256::from(20), true),
+			(U256::from(1000), false),
+			(U256::from(30), true),
+		];
+
+		assert_eq!(segment_work(&segment), U256::from(60));
+		assert_eq!(segment_work(&[]), U256::zero());
+		assert_eq!(
+			segment_work(&[(U256::max_value(), true), (U256::from(1), true)]),
+			U256::max_value(),
+		);
+	}
+
+	#[test]
+	fn solve_probability_follows_target() {
+		assert_eq!(solve_probability(U256::one()), 1.0);
+		assert_eq!(solve_probability(U256::zero()), 1.0);
+
+		for difficulty in &[U256::from(3), U256::from(1_000_000), U256::from(5) << 200] {
+			assert_eq!(solve_probability(*difficulty * 2), solve_probability(*difficulty) / 2.0);
+		}
+		assert_eq!(solve_probability(U256::from(1) << 128), 2f64.powi(-128));
+	}
+
+	#[test]
+	fn author_digest_formats_decode_to_same_author() {
+		let author = app::Pair::from_seed(&[1u8; 32]).public();
+
+		let legacy = author.encode();
+		let versioned = encode_author_digest(&author);
+
+		assert_eq!(legacy.len(), 32);
+		assert_eq!(versioned.len(), 33);
+		assert_eq!(decode_author(PreDigestFormat::Raw, &legacy[..]), Some(author.clone()));
+		assert_eq!(decode_author(PreDigestFormat::Versioned, &versioned[..]), Some(author.clone()));
+		assert_eq!(decode_author(PreDigestFormat::Raw, &legacy[..16]), None);
+
+		// Tags are only read where the runtime reads them, so that raw keys
+		// starting with a tag byte keep their meaning.
+		assert_ne!(decode_author(PreDigestFormat::Raw, &versioned[..]), Some(author));
+		assert_eq!(decode_author(PreDigestFormat::Versioned, &legacy[..]), None);
+	}
+
+	#[test]
+	fn committed_key_hash_is_checked_against_walk() {
+		let author = app::Pair::from_seed(&[1u8; 32]).public();
+		let key_hash = H256::repeat_byte(7);
+		let other = H256::repeat_byte(8);
+
+		let digest = encode_committed_digest(&author, &key_hash);
+		assert_eq!(
+			decode_pre_digest(PreDigestFormat::Versioned, &digest[..]),
+			Some(PreDigest { author: author.clone(), epoch_key_hash: Some(key_hash) }),
+		);
+		assert_eq!(decode_author(PreDigestFormat::Versioned, &digest[..]), Some(author.clone()));
+		assert_eq!(
+			decode_pre_digest(PreDigestFormat::Raw, &digest[..]).and_then(|d| d.epoch_key_hash),
+			None,
+		);
+
+		let walk = || Ok::<_, ()>(key_hash);
+		assert_eq!(resolve_key_hash(Some(key_hash), false, walk), Ok(Some(key_hash)));
+		assert_eq!(resolve_key_hash(Some(other), false, walk), Ok(None));
+		assert_eq!(resolve_key_hash(None, false, walk), Ok(Some(key_hash)));
+
+		let unreachable = || -> Result<H256, ()> { panic!("Trusted commitments skip the walk") };
+		assert_eq!(resolve_key_hash(Some(other), true, unreachable), Ok(Some(other)));
+	}
+
+	#[test]
+	fn implausible_seal_difficulty_rejected() {
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let parent_difficulty = U256::from(1_000_000);
+
+		let seal = |difficulty: u64| {
+			let compute = ComputeV2 { difficulty: U256::from(difficulty), ..test_compute() };
+			compute.seal(compute.sign(&author)).encode()
+		};
+
+		assert!(is_plausible_seal_difficulty(parent_difficulty, &seal(1_000_000)[..]));
+		assert!(is_plausible_seal_difficulty(parent_difficulty, &seal(1_500_000)[..]));
+		assert!(is_plausible_seal_difficulty(parent_difficulty, &seal(300_000)[..]));
+		assert!(!is_plausible_seal_difficulty(parent_difficulty, &seal(1_000)[..]));
+		assert!(!is_plausible_seal_difficulty(parent_difficulty, &seal(1_000_000_000)[..]));
+		assert!(!is_plausible_seal_difficulty(parent_difficulty, &[0u8; 4][..]));
+	}
+
+	#[test]
+	fn verified_seals_are_not_recomputed() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let compute = test_compute();
+		let verified_seals = VerifiedSeals::new(4);
+
+		let verify = |seal: &[u8]| verified_seals.verify_with(&compute.pre_hash, compute.difficulty, seal, || {
+			Ok::<_, ()>(verify_seal_v2_with(
+				&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+				compute.difficulty, SigningDomain::Legacy, seal,
+			))
+		});
+
+		let seal = compute.seal(compute.sign(&author)).encode();
+		assert_eq!(verify(&seal[..]), Ok(true));
+		assert_eq!(backend.calls(), 1);
+		assert_eq!(verify(&seal[..]), Ok(true));
+		assert_eq!(backend.calls(), 1);
+
+		let bad_seal = compute.seal(compute.sign(&other)).encode();
+		assert_eq!(verify(&bad_seal[..]), Ok(false));
+		assert_eq!(
+			verified_seals.verify_with(&compute.pre_hash, compute.difficulty, &bad_seal[..], || Ok::<_, ()>(true)),
+			Ok(true),
+		);
+
+		// A seal valid at one difficulty is checked again at another.
+		assert_eq!(
+			verified_seals.verify_with(&compute.pre_hash, compute.difficulty * U256::from(2), &seal[..], || Ok::<_, ()>(false)),
+			Ok(false),
+		);
+	}
+
+	#[test]
+	fn verification_cache_skips_repeated_seals() {
+		use std::sync::atomic::AtomicUsize;
+		use crate::mock::{MockClient, TestBlock};
+
+		#[derive(Default)]
+		struct Counting {
+			calls: AtomicUsize,
+		}
+
+		impl TargetChecker for Counting {
+			fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool {
+				self.calls.fetch_add(1, Ordering::SeqCst);
+				is_valid_hash(work, difficulty)
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let checker = Arc::new(Counting::default());
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.target_checker(Some(checker.clone()))
+			.verification_cache_size(4)
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+		let verify = |difficulty| algorithm.verify_detailed(
+			&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty,
+		).unwrap();
+
+		let hashes = || checker.calls.load(Ordering::SeqCst);
+		let mined = hashes();
+		assert_eq!(verify(difficulty), VerifyOutcome::Valid);
+		assert_eq!(hashes(), mined + 1);
+		assert_eq!(verify(difficulty), VerifyOutcome::Valid);
+		assert_eq!(hashes(), mined + 1);
+
+		// The cached outcome does not carry over to another difficulty.
+		assert!(!verify(difficulty * U256::from(2)).is_valid());
+	}
+
+	#[test]
+	fn status_reflects_mining_author() {
+		let _guard = crate::mock::lock_shared_caches();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let algorithm = RandomXAlgorithm::builder(Arc::new(()))
+			.author(Some(pair.public()))
+			.build();
+
+		let status = algorithm.status();
+		assert!(status.mining_enabled);
+		assert_eq!(status.author, Some(pair.public()));
+		assert_eq!(status.active_key_hash, None);
+
+		let status = RandomXAlgorithm::new(Arc::new(())).status();
+		assert!(!status.mining_enabled);
+		assert_eq!(status.author, None);
+	}
+
+	#[test]
+	fn hashrate_is_recorded_per_epoch() {
+		let first = H256::repeat_byte(1);
+		let second = H256::repeat_byte(2);
+		let start = Instant::now();
+		let at = |secs: u64| start + Duration::from_secs(secs);
+		let mut stats = Stats::new();
+
+		stats.record_round(first, 100, at(0), Duration::from_secs(1));
+		stats.record_round(second, 50, at(1), Duration::from_secs(1));
+		stats.record_round(first, 300, at(2), Duration::from_secs(1));
+
+		assert_eq!(stats.hashrate_by_epoch(), vec![(first, 200.0), (second, 50.0)]);
+
+		for i in 0..MAX_HASHRATE_EPOCHS {
+			stats.record_round(H256::from_low_u64_be(i as u64), 1, at(3), Duration::from_secs(1));
+		}
+		assert_eq!(stats.hashrate_by_epoch().len(), MAX_HASHRATE_EPOCHS);
+		assert!(stats.hashrate_by_epoch().iter().all(|(epoch, _)| *epoch != first));
+	}
+
+	#[test]
+	fn concurrent_rounds_add_up_over_wall_clock_time() {
+		let key_hash = H256::repeat_byte(1);
+		let start = Instant::now();
+		let at = |secs: u64| start + Duration::from_secs(secs);
+		let mut stats = Stats::new();
+
+		// Four threads mining over the same two seconds.
+		for _ in 0..4 {
+			stats.record_round(key_hash, 100, at(0), Duration::from_secs(2));
+		}
+		assert_eq!(stats.hashrate_by_epoch(), vec![(key_hash, 200.0)]);
+
+		stats.record_round(key_hash, 100, at(1), Duration::from_secs(2));
+		assert_eq!(stats.hashrate_by_epoch(), vec![(key_hash, 500.0 / 3.0)]);
+	}
+
+	#[test]
+	fn worker_states_are_tracked_per_thread() {
+		let stats = Arc::new(Mutex::new(Stats::new()));
+		let key_hash = H256::repeat_byte(1);
+
+		let handles = (0..2u64).map(|i| {
+			let stats = stats.clone();
+			thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
+				stats.lock().set_rebuilding_vm(key_hash, true);
+				stats.lock().set_rebuilding_vm(key_hash, false);
+				for _ in 0..=i {
+					stats.lock().record_round(key_hash, 10, Instant::now(), Duration::from_millis(1));
+				}
+			}).unwrap()
+		}).collect::<Vec<_>>();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+
+		let mut states = stats.lock().worker_states();
+		states.sort_by(|a, b| a.thread.cmp(&b.thread));
+		assert_eq!(states.len(), 2);
+		for (i, state) in states.iter().enumerate() {
+			assert_eq!(state.thread, format!("worker-{}", i));
+			assert_eq!(state.key_hash, Some(key_hash));
+			assert_eq!(state.nonces, 10 * (i as u64 + 1));
+			assert!(!state.rebuilding_vm);
+		}
+	}
+
+	#[test]
+	fn mining_metrics_count_vm_rebuilds() {
+		let _guard = crate::mock::lock_shared_caches();
+		let first = H256::repeat_byte(0x51);
+		let second = H256::repeat_byte(0x52);
+		let work = |key_hash: &H256| compute::compute(key_hash, &0u64, ComputeMode::Sync);
+
+		MiningMetrics::measure(|| (work(&first), 1));
+
+		let (_, steady) = MiningMetrics::measure(|| (work(&first), 1));
+		assert_eq!(steady.vm_rebuilds, 0);
+		assert_eq!(steady.hashes, 1);
+
+		let (_, switched) = MiningMetrics::measure(|| {
+			work(&second);
+			work(&first);
+			((), 2)
+		});
+		assert_eq!(switched.vm_rebuilds, 2);
+	}
+
+	#[test]
+	fn mining_metrics_count_hashes_done() {
+		use crate::mock::{MockClient, TestBlock};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_digest = author.encode();
+		let mine = |difficulty| algorithm.mine(
+			&keystore, &parent, &H256::repeat_byte(2), Some(&pre_digest[..]), difficulty, 1000,
+		).unwrap();
+
+		// Difficulty one is met by the first nonce, ending the round early.
+		assert!(mine(U256::one()).is_some());
+		assert_eq!(algorithm.last_mining_metrics().unwrap().hashes, 1);
+
+		assert!(mine(U256::max_value()).is_none());
+		assert_eq!(algorithm.last_mining_metrics().unwrap().hashes, 1000);
+	}
+
+	#[test]
+	fn recommended_round_scales_with_hashrate() {
+		let interval = Duration::from_millis(500);
+
+		assert_eq!(recommended_round(1000.0, interval), 500);
+		assert_eq!(recommended_round(2000.0, interval), 1000);
+		assert_eq!(recommended_round(4000.0, interval), 2000);
+		assert_eq!(recommended_round(4000.0, interval * 2), 4000);
+
+		assert_eq!(recommended_round(0.0, interval), 1);
+		assert_eq!(recommended_round(f64::NAN, interval), 1);
+		assert_eq!(recommended_round(f64::INFINITY, interval), u32::max_value());
+	}
+
+	#[test]
+	fn cancellation_is_detected_within_check_interval() {
+		for interval in &[1, 8, 64] {
+			let cancellation = Cancellation::with_check_interval(*interval);
+			let poller = cancellation.poller();
+
+			for _ in 0..(*interval / 2 + 3) {
+				assert!(!poller.poll());
+			}
+
+			cancellation.cancel();
+
+			let polled = (1..=*interval).find(|_| poller.poll());
+			assert!(polled.is_some(), "cancellation not detected within {} nonces", interval);
+		}
+	}
+
+	#[test]
+	fn sentinel_pre_hash_rejected_without_work() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+
+		for pre_hash in &[H256::zero(), H256::repeat_byte(0xff)] {
+			let compute = ComputeV2 { pre_hash: *pre_hash, ..test_compute() };
+			let seal = compute.seal(compute.sign(&author)).encode();
+
+			assert!(!verify_seal_v2_with(
+				&backend, &compute.key_hash, &compute.pre_hash, &author.public(),
+				compute.difficulty, SigningDomain::Legacy, &seal[..],
+			));
+
+			let seal = ComputeV1 {
+				key_hash: compute.key_hash,
+				pre_hash: compute.pre_hash,
+				difficulty: compute.difficulty,
+				nonce: compute.nonce,
+			}.seal().encode();
+
+			assert!(!verify_seal_v1_with(
+				&backend, &compute.key_hash, &compute.pre_hash, compute.difficulty, &seal[..],
+			));
+		}
+
+		assert_eq!(backend.calls(), 0);
+	}
+
+	#[test]
+	fn blake2_test_work_mines_and_verifies_without_randomx() {
+		let _guard = crate::mock::lock_shared_caches();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::from(4), ..test_compute() };
+		let work_algo = WorkAlgo::Blake2Test;
+
+		let mainnet = kulupu_primitives::ALGORITHM_IDENTIFIER_V2;
+		let testnet = kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST;
+		assert_eq!(RandomXAlgorithmVersion::from_identifier_for(&mainnet, work_algo), None);
+		assert_eq!(RandomXAlgorithmVersion::from_identifier_for(&testnet, WorkAlgo::RandomX), None);
+		assert_eq!(
+			RandomXAlgorithmVersion::from_identifier_for(&testnet, work_algo),
+			Some(RandomXAlgorithmVersion::V2),
+		);
+
+		let caches = compute::cache_count();
+		let builds = compute::local_vm_builds();
+
+		let (seal, _) = mine_v2_with(
+			&work_algo,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			1000,
+			&Interrupt::default(),
+			None,
+		).unwrap().expect("Difficulty four is met within a thousand nonces");
+
+		assert!(verify_seal_v2_with(
+			&work_algo, &compute.key_hash, &compute.pre_hash, &pair.public(),
+			compute.difficulty, SigningDomain::Legacy, &seal.encode()[..],
+		));
+		assert_eq!(compute::cache_count(), caches);
+		assert_eq!(compute::local_vm_builds(), builds);
+	}
+
+	#[test]
+	fn author_mismatch_warnings_are_rate_limited() {
+		use crate::mock::{TestBlock, capture_logs};
+
+		let logs = capture_logs();
+		let author = app::Pair::from_seed(&[0x5a; 32]).public();
+		let other = app::Pair::from_seed(&[0x5b; 32]).public();
+		let mismatch = AuthorMismatch::new(AuthorMismatchPolicy::Skip);
+		let warnings = |author: &app::Public| logs.records().into_iter()
+			.filter(|(target, _, message)| {
+				target == LOG_TARGET && message.contains(&format!("{:?}", author))
+			})
+			.count();
+
+		for _ in 0..3 {
+			assert!(mismatch.handle::<TestBlock, RawSeal>(&author).unwrap().is_none());
+		}
+		assert_eq!(warnings(&author), 1);
+
+		assert!(mismatch.handle::<TestBlock, RawSeal>(&other).unwrap().is_none());
+		assert_eq!(warnings(&other), 1);
+
+		let now = Instant::now();
+		let short = AuthorMismatch::with_interval(AuthorMismatchPolicy::Skip, Duration::from_secs(1));
+		assert!(short.should_warn(&author, now));
+		assert!(!short.should_warn(&author, now + Duration::from_millis(500)));
+		assert!(short.should_warn(&author, now + Duration::from_secs(2)));
+
+		let strict = AuthorMismatch::new(AuthorMismatchPolicy::Strict);
+		assert!(strict.handle::<TestBlock, RawSeal>(&author).is_err());
+	}
+
+	#[test]
+	fn mined_seal_verifies_against_mock_runtime() {
+		use crate::mock::{MockClient, TestBlock};
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(10, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(10));
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::from(4);
+
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+		assert!(algorithm.verify(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty).unwrap());
+
+		// The seal is only valid for the author it was mined for, and mining
+		// for an author without a keystore key does not happen.
+		let payout = app::Pair::from_seed(&[0x5c; 32]).public().encode();
+		assert!(!algorithm.verify(&parent, &pre_hash, Some(&payout[..]), &seal, difficulty).unwrap());
+		assert_eq!(
+			algorithm.mine(&keystore, &parent, &pre_hash, Some(&payout[..]), difficulty, 1000).unwrap(),
+			None,
+		);
+	}
+
+	#[test]
+	fn throttled_mining_sleeps_about_half_the_time() {
+		struct SlowBackend;
+
+		impl WorkBackend for SlowBackend {
+			fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+				std::thread::sleep(Duration::from_millis(2));
+				H256::from(blake2_256(&(key_hash, input).encode()[..]))
+			}
+		}
+
+		assert!(CpuUtilization::new(0.0).is_none());
+		assert!(CpuUtilization::new(1.5).is_none());
+		assert!(CpuUtilization::new(f64::NAN).is_none());
+		assert!(CpuThrottle::new(CpuUtilization::new(1.0).unwrap()).is_none());
+
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+		let interrupt = Interrupt {
+			throttle: CpuThrottle::new(CpuUtilization::new(0.5).unwrap()),
+			..Default::default()
+		};
+
+		let started = Instant::now();
+		let seal = mine_v2_with(
+			&SlowBackend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			THROTTLE_BATCH * 4,
+			&interrupt,
+			None,
+		).unwrap();
+		let elapsed = started.elapsed().as_secs_f64();
+		let slept = interrupt.throttle.as_ref().unwrap().slept.get().as_secs_f64();
+
+		assert!(seal.is_none());
+		assert!(slept > elapsed * 0.3 && slept < elapsed * 0.7, "slept {}s of {}s", slept, elapsed);
+	}
+
+	#[test]
+	fn test_difficulty_is_refused_outside_test_networks() {
+		use crate::mock::MockClient;
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(1, kulupu_primitives::ALGORITHM_IDENTIFIER_V2));
+		client.runtime.set_difficulty(U256::from(1000));
+		let parent = client.chain.hash(1);
+
+		let algorithm = RandomXAlgorithm::builder(client.clone()).build();
+		assert_eq!(PowAlgorithm::<crate::mock::TestBlock>::difficulty(&algorithm, parent).unwrap(), U256::from(1000));
+
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.difficulty_source(Some(Arc::new(TestDifficulty)))
+			.build();
+		assert!(PowAlgorithm::<crate::mock::TestBlock>::difficulty(&algorithm, parent).is_err());
+
+		client.runtime.set_identifier(kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST);
+		assert_eq!(PowAlgorithm::<crate::mock::TestBlock>::difficulty(&algorithm, parent).unwrap(), U256::one());
+	}
+
+	#[test]
+	fn test_difficulty_accepts_first_nonce() {
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let difficulty = TestDifficulty.difficulty(&H256::repeat_byte(9)).unwrap();
+		assert_eq!(difficulty, U256::one());
+		assert!(is_valid_hash(&H256::repeat_byte(0xff), difficulty));
+
+		for seed in 0..8 {
+			let compute = ComputeV2 { difficulty, ..test_compute() };
+			let (seal, _) = mine_v2_with(
+				&backend,
+				&mut SmallRng::seed_from_u64(seed),
+				&compute.key_hash,
+				&compute.pre_hash,
+				compute.difficulty,
+				&pair,
+				SigningDomain::Legacy,
+				1,
+				&Interrupt::default(),
+				None,
+			).unwrap().expect("Any hash is valid at difficulty one");
+
+			assert!(verify_seal_v2_with(
+				&backend, &compute.key_hash, &compute.pre_hash, &pair.public(),
+				difficulty, SigningDomain::Legacy, &seal.encode()[..],
+			));
+		}
+		assert_eq!(backend.calls(), 16);
+	}
+
+	#[test]
+	fn mined_work_matches_recomputation() {
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::from(4), ..test_compute() };
+
+		let (seal, work) = mine_v2_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			1000,
+			&Interrupt::default(),
+			None,
+		).unwrap().expect("Difficulty four is met within a thousand nonces");
+
+		let winning = ComputeV2 { nonce: seal.nonce, ..compute };
+		let (_, recomputed) = winning.seal_and_work_with(&backend, seal.signature, ComputeMode::Sync);
+		assert_eq!(work, recomputed);
+	}
+
+	#[test]
+	fn mining_retries_failed_signing() {
+		struct FlakySigner {
+			pair: app::Pair,
+			failures: std::cell::Cell<usize>,
+		}
+
+		impl SealSigner for FlakySigner {
+			fn sign(&self, message: &[u8]) -> Result<app::Signature, String> {
+				if self.failures.get() > 0 {
+					self.failures.set(self.failures.get() - 1);
+					return Err("Keystore busy".to_string())
+				}
+				SealSigner::sign(&self.pair, message)
+			}
+		}
+
+		let signer = FlakySigner {
+			pair: app::Pair::from_seed(&[1u8; 32]),
+			failures: std::cell::Cell::new(1),
+		};
+		let backend = CountingBackend::default();
+		let compute = test_compute();
+
+		let (seal, _) = mine_v2_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&signer,
+			SigningDomain::Legacy,
+			1,
+			&Interrupt::default(),
+			None,
+		).unwrap().expect("Signing succeeds on the second attempt");
+
+		assert_eq!(signer.failures.get(), 0);
+		assert!(verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &signer.pair.public(),
+			compute.difficulty, SigningDomain::Legacy, &seal.encode()[..],
+		));
+	}
+
+	#[test]
+	fn unsigned_nonces_are_not_hashed() {
+		struct FailingSigner {
+			attempts: std::cell::Cell<usize>,
+		}
+
+		impl SealSigner for FailingSigner {
+			fn sign(&self, _message: &[u8]) -> Result<app::Signature, String> {
+				self.attempts.set(self.attempts.get() + 1);
+				Err("Keystore locked".to_string())
+			}
+		}
+
+		let signer = FailingSigner { attempts: Default::default() };
+		let backend = CountingBackend::default();
+		let compute = test_compute();
+
+		let mined = mine_v2_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&signer,
+			SigningDomain::Legacy,
+			5,
+			&Interrupt::default(),
+			None,
+		).unwrap();
+
+		assert!(mined.is_none());
+		assert_eq!(signer.attempts.get(), 5 * SIGN_ATTEMPTS);
+		// Only the empty input ending the round is hashed.
+		assert_eq!(backend.calls(), 1);
+	}
+
+	#[test]
+	fn failing_mining_rounds_trip_the_breaker() {
+		struct FailingBackend;
+
+		impl WorkBackend for FailingBackend {
+			fn work(&self, _key_hash: &H256, _input: &[u8], _mode: ComputeMode) -> H256 {
+				unreachable!("Work is only computed through try_work_loop")
+			}
+
+			fn try_work_loop<FPre, I, FValidate, R>(
+				&self,
+				_key_hash: &H256,
+				_mode: ComputeMode,
+				_f_pre: FPre,
+				_f_validate: FValidate,
+				_round: usize,
+			) -> Result<Option<R>, compute::ComputeError> where
+				FPre: FnMut() -> (Vec<u8>, I),
+				FValidate: Fn(H256, I) -> compute::Loop<Option<R>>,
+			{
+				Err(compute::ComputeError::MachineUnavailable)
+			}
+		}
+
+		let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(60));
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let attempts = std::cell::Cell::new(0);
+		let mine_round = || through_breaker::<crate::mock::TestBlock, _, _>(Some(&breaker), || {
+			attempts.set(attempts.get() + 1);
+			mine_v2_with(
+				&FailingBackend,
+				&mut SmallRng::seed_from_u64(0),
+				&compute.key_hash,
+				&compute.pre_hash,
+				compute.difficulty,
+				&pair,
+				SigningDomain::Legacy,
+				1,
+				&Interrupt::default(),
+				None,
+			)
+		});
+
+		for _ in 0..2 {
+			assert!(matches!(
+				mine_round(),
+				Err(MineError::Compute(compute::ComputeError::MachineUnavailable)),
+			));
+		}
+		assert!(matches!(mine_round(), Err(MineError::Suspended)));
+		assert_eq!(attempts.get(), 2);
+	}
+
+	#[test]
+	fn paused_algorithm_does_not_mine() {
+		use std::sync::atomic::AtomicUsize;
+		use crate::mock::{MockClient, TestBlock};
+
+		#[derive(Default)]
+		struct Counting {
+			calls: AtomicUsize,
+		}
+
+		impl TargetChecker for Counting {
+			fn is_valid(&self, work: &H256, difficulty: Difficulty) -> bool {
+				self.calls.fetch_add(1, Ordering::SeqCst);
+				is_valid_hash(work, difficulty)
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_V2));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let checker = Arc::new(Counting::default());
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.target_checker(Some(checker.clone()))
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let key_hash = client.chain.hash(0);
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let mine = || algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), U256::one(), 10)
+			.unwrap();
+
+		let hashes = || checker.calls.load(Ordering::SeqCst);
+		assert!(mine().is_some());
+		let hashed = hashes();
+		assert!(hashed > 0);
+		let builds = compute::local_vm_builds();
+		assert_eq!(compute::current_vm_key_hash(), Some(key_hash));
+
+		algorithm.pause();
+		assert!(algorithm.is_paused());
+		assert!(mine().is_none());
+		assert_eq!(hashes(), hashed);
+		assert!(algorithm.cached_key_hashes().contains(&key_hash));
+
+		// Mining resumes on the machine built before the pause.
+		algorithm.resume();
+		assert!(mine().is_some());
+		assert!(hashes() > hashed);
+		assert_eq!(compute::local_vm_builds(), builds);
+	}
+
+	#[test]
+	fn mining_uses_keystore_key_matching_author() {
+		let keystore = LocalKeystore::in_memory();
+		SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+
+		let pair = author_pair(&keystore, &author).expect("Author key is in the keystore");
+		assert_eq!(pair.public(), author);
+		assert!(author_pair(&keystore, &app::Pair::from_seed(&[9u8; 32]).public()).is_none());
+
+		let backend = CountingBackend::default();
+		let compute = test_compute();
+		let (seal, _) = mine_v2_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			10,
+			&Interrupt::default(),
+			None,
+		).unwrap().expect("Difficulty one accepts any work");
+
+		assert!(verify_seal_v2_with(
+			&backend, &compute.key_hash, &compute.pre_hash, &author,
+			compute.difficulty, SigningDomain::Legacy, &seal.encode()[..],
+		));
+	}
+
+	#[test]
+	fn secure_nonce_rng_is_cryptographic() {
+		let first_nonce = |nonce_rng: NonceRng| {
+			let mut rng = nonce_rng.seeded_from(StdRng::seed_from_u64(7)).unwrap();
+			H256::random_using(&mut rng)
+		};
+
+		let mut expected = StdRng::from_rng(StdRng::seed_from_u64(7)).unwrap();
+		assert_eq!(first_nonce(NonceRng::Secure), H256::random_using(&mut expected));
+		assert_eq!(first_nonce(NonceRng::Secure), first_nonce(NonceRng::Secure));
+
+		let mut fast = SmallRng::from_rng(StdRng::seed_from_u64(7)).unwrap();
+		assert_eq!(first_nonce(NonceRng::Fast), H256::random_using(&mut fast));
+	}
+
+	#[test]
+	fn mining_stops_at_deadline() {
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let round = 1_000_000;
+
+		let interrupt = Interrupt {
+			deadline: Some(Instant::now() + Duration::from_millis(10)),
+			..Default::default()
+		};
+
+		let seal = mine_v2_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			U256::max_value(),
+			&pair,
+			SigningDomain::Legacy,
+			round,
+			&interrupt,
+			None,
+		).unwrap();
+
+		assert!(seal.is_none());
+		assert!(backend.calls() < round as usize);
+	}
+
+	#[test]
+	fn mining_emits_job_and_block_events() {
+		use crate::events::MiningEvent;
+
+		let emitted = Arc::new(Mutex::new(Vec::new()));
+		let events = {
+			let emitted = emitted.clone();
+			MiningEvents::new(move |event| emitted.lock().push(event))
+		};
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+
+		let (seal, work) = mine_v2_with(
+			&CountingBackend::default(),
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			compute.difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			10,
+			&Interrupt::default(),
+			Some(&events),
+		).unwrap().expect("Difficulty one accepts any work");
+
+		let emitted = emitted.lock().clone();
+		assert_eq!(emitted, vec![
+			MiningEvent::JobStarted {
+				pre_hash: compute.pre_hash,
+				key_hash: compute.key_hash,
+				difficulty: compute.difficulty,
+			},
+			MiningEvent::BlockFound {
+				pre_hash: compute.pre_hash,
+				nonce: seal.nonce,
+				work,
+			},
+		]);
+
+		let json = serde_json::to_string(&emitted[0]).unwrap();
+		assert!(json.contains("\"event\":\"jobStarted\""));
+		assert_eq!(serde_json::from_str::<MiningEvent>(&json).unwrap(), emitted[0]);
+	}
+
+	#[test]
+	fn difficulty_fallback_survives_transient_errors() {
+		let parent = H256::repeat_byte(1);
+		let other = H256::repeat_byte(2);
+		let calls = Cell::new(0);
+		let flaky = || {
+			calls.set(calls.get() + 1);
+			if calls.get() == 1 { Err("State unavailable") } else { Ok(U256::from(7)) }
+		};
+
+		let retry = DifficultyFetcher::new(DifficultyFallback::Retry {
+			attempts: 2,
+			delay: Duration::from_millis(0),
+		});
+		assert_eq!(retry.fetch(&parent, flaky), Ok(U256::from(7)));
+		assert_eq!(calls.get(), 2);
+
+		calls.set(0);
+		assert_eq!(DifficultyFetcher::default().fetch(&parent, flaky), Err("State unavailable"));
+		assert_eq!(calls.get(), 1);
+
+		let last_known = DifficultyFetcher::new(DifficultyFallback::LastKnown);
+		assert_eq!(last_known.fetch(&parent, || Err::<Difficulty, _>("Unavailable")), Err("Unavailable"));
+		assert_eq!(last_known.fetch(&parent, || Ok::<_, &str>(U256::from(5))), Ok(U256::from(5)));
+		assert_eq!(last_known.fetch(&parent, || Err("Unavailable")), Ok(U256::from(5)));
+		// The difficulty of another parent, such as one on another fork, is never used.
+		assert_eq!(last_known.fetch(&other, || Err::<Difficulty, _>("Unavailable")), Err("Unavailable"));
+	}
+
+	#[test]
+	fn mining_proceeds_with_difficulty_retry() {
+		use crate::mock::{MockClient, TestBlock};
+
+		/// Algorithm whose first difficulty query fails, as right after an import.
+		struct Flaky<A> {
+			inner: A,
+			failed: Cell<bool>,
+		}
+
+		impl<A: PowAlgorithm<TestBlock, Difficulty=Difficulty>> PowAlgorithm<TestBlock> for Flaky<A> {
+			type Difficulty = Difficulty;
+
+			fn difficulty(&self, parent: H256) -> Result<Difficulty, sc_consensus_pow::Error<TestBlock>> {
+				if !self.failed.replace(true) {
+					return Err(sc_consensus_pow::Error::Environment("State unavailable".to_string()))
+				}
+				self.inner.difficulty(parent)
+			}
+
+			fn verify(
+				&self,
+				parent: &BlockId<TestBlock>,
+				pre_hash: &H256,
+				pre_digest: Option<&[u8]>,
+				seal: &RawSeal,
+				difficulty: Difficulty,
+			) -> Result<bool, sc_consensus_pow::Error<TestBlock>> {
+				self.inner.verify(parent, pre_hash, pre_digest, seal, difficulty)
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(3, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		client.runtime.set_difficulty(U256::from(4));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.build();
+		let parent = client.chain.hash(3);
+
+		let failing = Flaky { inner: algorithm.clone(), failed: Cell::new(false) };
+		assert!(PowAlgorithm::<TestBlock>::difficulty(&failing, parent).is_err());
+
+		let mining = MiningDifficulty::new(
+			Flaky { inner: algorithm.clone(), failed: Cell::new(false) },
+			DifficultyFallback::Retry { attempts: 1, delay: Duration::from_millis(0) },
+		);
+		let difficulty = PowAlgorithm::<TestBlock>::difficulty(&mining, parent).unwrap();
+		assert_eq!(difficulty, U256::from(4));
+
+		let parent = BlockId::<TestBlock>::Hash(parent);
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let seal = algorithm.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1000)
+			.unwrap()
+			.expect("A nonce meeting difficulty 4 is found among 1000");
+		assert!(mining.verify(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty).unwrap());
+	}
+
+	#[test]
+	fn near_misses_are_counted_without_seal() {
+		struct FixedBackend(H256);
+
+		impl WorkBackend for FixedBackend {
+			fn work(&self, _key_hash: &H256, _input: &[u8], _mode: ComputeMode) -> H256 {
+				self.0
+			}
+		}
+
+		let mut work = H256::zero();
+		(U256::one() << 200).to_big_endian(&mut work[..]);
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let difficulty = U256::one() << 60;
+		let near_misses = NearMisses::new(U256::one() << 40);
+		let interrupt = Interrupt { near_misses: Some(&near_misses), ..Default::default() };
+
+		let mined = mine_v2_with(
+			&FixedBackend(work),
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			difficulty,
+			&pair,
+			SigningDomain::Legacy,
+			4,
+			&interrupt,
+			None,
+		).unwrap();
+
+		assert!(mined.is_none());
+		assert_eq!(near_misses.count(), 4);
+		assert!(!near_misses.record(&work, U256::one() << 20));
+		assert!(!near_misses.record(&H256::repeat_byte(0xff), difficulty));
+		assert_eq!(near_misses.count(), 4);
+	}
+
+	#[test]
+	fn custom_target_checker_replaces_default() {
+		use std::sync::atomic::AtomicUsize;
+
+		struct FixedBackend(H256);
+
+		impl WorkBackend for FixedBackend {
+			fn work(&self, _key_hash: &H256, _input: &[u8], _mode: ComputeMode) -> H256 {
+				self.0
+			}
+		}
+
+		struct LeadingZeroBits {
+			bits: u32,
+			calls: AtomicUsize,
+		}
+
+		impl TargetChecker for LeadingZeroBits {
+			fn is_valid(&self, work: &H256, _difficulty: Difficulty) -> bool {
+				self.calls.fetch_add(1, Ordering::SeqCst);
+				U256::from(&work[..]).leading_zeros() >= self.bits
+			}
+		}
+
+		let mut work = H256::zero();
+		(U256::one() << 200).to_big_endian(&mut work[..]);
+		let backend = FixedBackend(work);
+		let compute = test_compute();
+		let difficulty = U256::one() << 60;
+		let seal = |difficulty| SealToVerify {
+			key_hash: compute.key_hash,
+			pre_hash: compute.pre_hash,
+			difficulty,
+			author: None,
+			domain: SigningDomain::Legacy,
+			seal: ComputeV1 {
+				key_hash: compute.key_hash,
+				pre_hash: compute.pre_hash,
+				difficulty,
+				nonce: compute.nonce,
+			}.seal().encode(),
+		};
+
+		let lenient = LeadingZeroBits { bits: 50, calls: Default::default() };
+		assert_eq!(seal(difficulty).check_with(&backend), Err(InvalidReason::BelowDifficulty));
+		assert_eq!(seal(difficulty).check_with_target(&backend, &lenient), Ok(()));
+		assert_eq!(lenient.calls.load(Ordering::SeqCst), 1);
+
+		let strict = LeadingZeroBits { bits: 60, calls: Default::default() };
+		assert_eq!(seal(U256::one()).check_with(&backend), Ok(()));
+		assert_eq!(
+			seal(U256::one()).check_with_target(&backend, &strict),
+			Err(InvalidReason::BelowDifficulty),
+		);
+
+		let interrupt = Interrupt { target: Some(&lenient), ..Default::default() };
+		let mined = mine_v1_with(
+			&backend,
+			&mut SmallRng::seed_from_u64(0),
+			&compute.key_hash,
+			&compute.pre_hash,
+			difficulty,
+			1,
+			&interrupt,
+			None,
+		).unwrap();
+		assert_eq!(mined.map(|(_, mined_work)| mined_work), Some(work));
+		assert_eq!(lenient.calls.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn custom_target_checker_drives_block_verification() {
+		use crate::mock::{MockClient, TestBlock};
+
+		struct AcceptAll;
+
+		impl TargetChecker for AcceptAll {
+			fn is_valid(&self, _work: &H256, _difficulty: Difficulty) -> bool {
+				true
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = LocalKeystore::in_memory();
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&keystore, app::ID, None).unwrap()
+		);
+		let builder = || RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test);
+		let custom = builder().target_checker(Some(Arc::new(AcceptAll))).build();
+		let default = builder().build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_hash = H256::repeat_byte(2);
+		let pre_digest = author.encode();
+		let difficulty = U256::max_value();
+		let seal = custom.mine(&keystore, &parent, &pre_hash, Some(&pre_digest[..]), difficulty, 1)
+			.unwrap()
+			.expect("Any work meets the target of AcceptAll");
+
+		assert_eq!(
+			custom.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty).unwrap(),
+			VerifyOutcome::Valid,
+		);
+		assert_eq!(
+			default.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &seal, difficulty).unwrap(),
+			VerifyOutcome::Invalid(InvalidReason::BelowDifficulty),
+		);
+	}
+
+	#[test]
+	fn shutdown_drains_slow_rounds_and_releases_machines() {
+		use crate::mock::{MockClient, TestBlock};
+		use std::sync::mpsc;
+
+		struct Slow {
+			entered: std::sync::Mutex<Option<mpsc::Sender<()>>>,
+			returned: Arc<AtomicBool>,
+		}
+
+		impl TargetChecker for Slow {
+			fn is_valid(&self, _work: &H256, _difficulty: Difficulty) -> bool {
+				if let Some(entered) = self.entered.lock().unwrap().take() {
+					entered.send(()).unwrap();
+					thread::sleep(Duration::from_millis(50));
+					self.returned.store(true, Ordering::SeqCst);
+				}
+				false
+			}
+		}
+
+		let _guard = crate::mock::lock_shared_caches();
+		let client = Arc::new(MockClient::new(20, kulupu_primitives::ALGORITHM_IDENTIFIER_BLAKE2_TEST));
+		let keystore = Arc::new(LocalKeystore::in_memory());
+		let author = app::Public::from(
+			SyncCryptoStore::sr25519_generate_new(&*keystore, app::ID, None).unwrap()
+		);
+		let (entered, wait_entered) = mpsc::channel();
+		let returned = Arc::new(AtomicBool::new(false));
+		let algorithm = RandomXAlgorithm::builder(client.clone())
+			.author(Some(author.clone()))
+			.work_algo(WorkAlgo::Blake2Test)
+			.verify_threads(Some(1))
+			.target_checker(Some(Arc::new(Slow {
+				entered: std::sync::Mutex::new(Some(entered)),
+				returned: returned.clone(),
+			})))
+			.build();
+
+		let parent = BlockId::<TestBlock>::Hash(client.chain.hash(20));
+		let pre_hash = H256::repeat_byte(3);
+		let pre_digest = author.encode();
+		compute::warm(&H256::repeat_byte(1), ComputeMode::Sync);
+
+		let mining = {
+			let algorithm = algorithm.clone();
+			let keystore = keystore.clone();
+			thread::spawn(move || {
+				compute::warm(&H256::repeat_byte(1), ComputeMode::Sync);
+				let mined = algorithm.mine(
+					&*keystore, &parent, &pre_hash, Some(&pre_digest[..]), U256::max_value(), 1000,
+				);
+				(mined.is_ok(), compute::holds_machines())
+			})
+		};
+
+		wait_entered.recv().unwrap();
+		algorithm.shutdown();
+
+		assert!(returned.load(Ordering::SeqCst), "Shut down before the round returned");
+		assert!(!compute::holds_machines());
+		assert_eq!(mining.join().unwrap(), (true, false));
+		assert_eq!(algorithm.verify_pool.as_ref().unwrap().threads(), 0);
+
+		let pre_digest = author.encode();
+		assert_eq!(
+			algorithm.mine(&*keystore, &parent, &pre_hash, Some(&pre_digest[..]), U256::from(1), 1).unwrap(),
+			None,
+		);
+		assert!(algorithm.verify_detailed(&parent, &pre_hash, Some(&pre_digest[..]), &vec![0u8; 8], U256::from(1)).is_err());
+		compute::flush_caches();
+	}
+
+	#[test]
+	fn pooled_verification_is_bounded() {
+		use std::sync::atomic::AtomicUsize;
+
+		struct ThreadBackend {
+			inner: CountingBackend,
+			active: AtomicUsize,
+			max_active: AtomicUsize,
+			threads: std::sync::Mutex<Vec<ThreadId>>,
+		}
+
+		impl WorkBackend for ThreadBackend {
+			fn work(&self, key_hash: &H256, input: &[u8], mode: ComputeMode) -> H256 {
+				let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+				self.max_active.fetch_max(active, Ordering::SeqCst);
+				self.threads.lock().unwrap().push(thread::current().id());
+				thread::sleep(Duration::from_millis(2));
+				let work = self.inner.work(key_hash, input, mode);
+				self.active.fetch_sub(1, Ordering::SeqCst);
+				work
+			}
+		}
+
+		let backend = Arc::new(ThreadBackend {
+			inner: CountingBackend::default(),
+			active: AtomicUsize::new(0),
+			max_active: AtomicUsize::new(0),
+			threads: Default::default(),
+		});
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let mut seals = (0..6u8).map(|i| {
+			let compute = ComputeV2 { key_hash: H256::repeat_byte(i + 1), ..test_compute() };
+			SealToVerify {
+				key_hash: compute.key_hash,
+				pre_hash: compute.pre_hash,
+				difficulty: compute.difficulty,
+				author: Some(pair.public()),
+				domain: SigningDomain::Legacy,
+				seal: compute.seal(compute.sign(&pair)).encode(),
+			}
+		}).collect::<Vec<_>>();
+		seals[3].pre_hash = H256::repeat_byte(7);
+
+		let pool = VerifyPool::new(1);
+		let results = verify_pooled_with(&pool, backend.clone(), seals);
+
+		assert_eq!(results, vec![true, true, true, false, true, true]);
+		assert_eq!(backend.inner.calls(), 5);
+		assert_eq!(backend.max_active.load(Ordering::SeqCst), 1);
+
+		let threads = backend.threads.lock().unwrap();
+		assert!(threads.iter().all(|id| *id == threads[0]));
+		assert_ne!(threads[0], thread::current().id());
+	}
+
+	#[test]
+	fn unrepresentable_seal_difficulty_is_rejected() {
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = ComputeV2 { difficulty: U256::max_value(), ..test_compute() };
+		let seal = compute.seal(compute.sign(&author)).encode();
+		let cap = U256::max_value() >> 1;
+
+		assert!(!is_representable_seal_difficulty(cap, &seal[..]));
+		assert!(is_representable_seal_difficulty(U256::max_value(), &seal[..]));
+		assert!(is_representable_seal_difficulty(cap, &test_compute().seal(compute.sign(&author)).encode()[..]));
+		assert!(!is_representable_seal_difficulty(cap, &[0u8; 3][..]));
+	}
+
+	#[test]
+	fn walked_key_hash_is_checked_against_runtime() {
+		let walked = H256::repeat_byte(1);
+
+		assert!(matches_expected_key_hash(&walked, None));
+		assert!(matches_expected_key_hash(&walked, Some(walked)));
+		assert!(!matches_expected_key_hash(&walked, Some(H256::repeat_byte(2))));
+	}
+
+	#[test]
+	fn invalid_seals_report_their_reason() {
+		let backend = CountingBackend::default();
+		let author = app::Pair::from_seed(&[1u8; 32]);
+		let compute = test_compute();
+		let seal = compute.seal(compute.sign(&author)).encode();
+		let check = |pre_hash: &H256, author: &app::Public, difficulty, seal: &[u8]| check_share_with(
+			&backend, &compute.key_hash, pre_hash, author, compute.difficulty, difficulty,
+			SigningDomain::Legacy, seal,
+		);
+
+		assert_eq!(check(&compute.pre_hash, &author.public(), compute.difficulty, &seal[..]), Ok(()));
+		assert_eq!(
+			check(&compute.pre_hash, &author.public(), compute.difficulty, &seal[..seal.len() - 1]),
+			Err(InvalidReason::MalformedSeal),
+		);
+		assert_eq!(
+			check(&compute.pre_hash, &app::Pair::from_seed(&[2u8; 32]).public(), compute.difficulty, &seal[..]),
+			Err(InvalidReason::BadSignature),
+		);
+		assert_eq!(
+			check(&H256::zero(), &author.public(), compute.difficulty, &seal[..]),
+			Err(InvalidReason::SentinelPreHash),
+		);
+		assert_eq!(
+			check(&compute.pre_hash, &author.public(), U256::max_value(), &seal[..]),
+			Err(InvalidReason::BelowDifficulty),
+		);
+
+		// Signed for the verified difficulty, but claiming another one.
+		let mut mismatched = compute.seal(compute.sign(&author));
+		mismatched.difficulty = U256::from(2);
+		assert_eq!(
+			check(&compute.pre_hash, &author.public(), compute.difficulty, &mismatched.encode()[..]),
+			Err(InvalidReason::SealMismatch),
+		);
+		assert_eq!(backend.calls(), 3);
+
+		assert_eq!(
+			check_seal_v1_with(&backend, &compute.key_hash, &compute.pre_hash, compute.difficulty, &[0u8; 3][..]),
+			Err(InvalidReason::MalformedSeal),
+		);
+	}
+
+	#[test]
+	fn invalid_context_reports_its_reason() {
+		let author = app::Pair::from_seed(&[1u8; 32]).public();
+
+		assert_eq!(
+			version_for_identifier(&kulupu_primitives::ALGORITHM_IDENTIFIER_V2, WorkAlgo::RandomX),
+			Ok(RandomXAlgorithmVersion::V2),
+		);
+		assert_eq!(
+			version_for_identifier(b"unknown!", WorkAlgo::RandomX),
+			Err(InvalidReason::UnsupportedVersion),
+		);
+
+		assert_eq!(required_author(RandomXAlgorithmVersion::V1, None), Ok(None));
+		assert_eq!(
+			required_author(RandomXAlgorithmVersion::V2, Some(&author.encode()[..])),
+			Ok(Some(author.clone())),
+		);
+		assert_eq!(
+			required_author(RandomXAlgorithmVersion::V3, Some(&encode_author_digest(&author)[..])),
+			Ok(Some(author)),
+		);
+		assert_eq!(required_author(RandomXAlgorithmVersion::V2, None), Err(InvalidReason::MissingDigest));
+		assert_eq!(
+			required_author(RandomXAlgorithmVersion::V3, Some(&[0u8; 3][..])),
+			Err(InvalidReason::MalformedDigest),
+		);
+
+		assert!(VerifyOutcome::Valid.is_valid());
+		assert!(!VerifyOutcome::from(Err(InvalidReason::BadSignature)).is_valid());
+	}
 }
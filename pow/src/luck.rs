@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Luck of a solo miner, comparing hashes spent to hashes expected per block.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use sp_core::H256;
+use kulupu_primitives::Difficulty;
+use log::*;
+use crate::{LOG_TARGET, is_valid_hash, solve_probability};
+
+/// Number of hashes expected to find a block at `difficulty`.
+pub fn expected_hashes(difficulty: Difficulty) -> f64 {
+	1.0 / solve_probability(difficulty)
+}
+
+/// Running tally of found blocks, optionally over the latest ones only.
+#[derive(Clone, Debug, Default)]
+pub struct LuckTracker {
+	window: Option<usize>,
+	/// Hashes spent and hashes expected, per found block.
+	finds: VecDeque<(u64, f64)>,
+}
+
+impl LuckTracker {
+	/// Tracker over all found blocks.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Tracker over the latest `window` found blocks, for rolling luck.
+	pub fn with_window(window: usize) -> Self {
+		Self { window: Some(window.max(1)), finds: VecDeque::new() }
+	}
+
+	/// Record a block found after `hashes_spent` hashes since the previous one.
+	pub fn record_block_found(&mut self, hashes_spent: u64, difficulty: Difficulty) {
+		if self.window == Some(self.finds.len()) {
+			self.finds.pop_front();
+		}
+		self.finds.push_back((hashes_spent, expected_hashes(difficulty)));
+	}
+
+	/// Number of tracked blocks.
+	pub fn blocks(&self) -> usize {
+		self.finds.len()
+	}
+
+	/// Hashes expected over hashes spent for the tracked blocks. Above one is
+	/// lucky, below one unlucky. One if nothing was tracked yet.
+	pub fn luck_ratio(&self) -> f64 {
+		let spent = self.finds.iter().map(|(spent, _)| *spent as f64).sum::<f64>();
+		let expected = self.finds.iter().map(|(_, expected)| *expected).sum::<f64>();
+
+		if self.finds.is_empty() {
+			1.0
+		} else if spent == 0.0 {
+			f64::INFINITY
+		} else {
+			expected / spent
+		}
+	}
+}
+
+/// Counter of near misses, hashes meeting a lower threshold difficulty but not
+/// the difficulty mined at.
+#[derive(Debug)]
+pub struct NearMisses {
+	threshold: Difficulty,
+	count: AtomicU64,
+}
+
+impl NearMisses {
+	pub fn new(threshold: Difficulty) -> Self {
+		Self { threshold, count: AtomicU64::new(0) }
+	}
+
+	pub fn threshold(&self) -> Difficulty {
+		self.threshold
+	}
+
+	/// Count `work` if it is a near miss at `difficulty`, returning whether it is.
+	pub fn record(&self, work: &H256, difficulty: Difficulty) -> bool {
+		let near_miss = is_valid_hash(work, self.threshold) && !is_valid_hash(work, difficulty);
+
+		if near_miss {
+			let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+			debug!(target: LOG_TARGET, "Near miss {} at difficulty {}: {}", count, difficulty, work);
+		}
+
+		near_miss
+	}
+
+	pub fn count(&self) -> u64 {
+		self.count.load(Ordering::Relaxed)
+	}
+}
+
+/// Expected time for a hashrate of `hashrate_hps` to find a block at
+/// `difficulty`, saturating on absurd values. `None` if the hashrate is not
+/// positive.
+pub fn expected_time_to_block(difficulty: Difficulty, hashrate_hps: f64) -> Option<Duration> {
+	if !(hashrate_hps > 0.0) {
+		return None
+	}
+
+	let secs = expected_hashes(difficulty) / hashrate_hps;
+	let max = Duration::from_secs(u64::max_value() / 2);
+
+	if secs >= max.as_secs_f64() {
+		Some(max)
+	} else {
+		Some(Duration::from_secs_f64(secs))
+	}
+}
+
+/// Warning, logged once, that the expected time to find a block is above a
+/// threshold. An impractical time to block usually means a misconfiguration,
+/// such as mining on the wrong network.
+#[derive(Debug)]
+pub struct TimeToBlockWarning {
+	threshold: Duration,
+	warned: AtomicBool,
+}
+
+impl TimeToBlockWarning {
+	pub fn new(threshold: Duration) -> Self {
+		Self { threshold, warned: AtomicBool::new(false) }
+	}
+
+	pub fn threshold(&self) -> Duration {
+		self.threshold
+	}
+
+	/// Warn if the expected time to block at the measured hashrate is above the
+	/// threshold, unless already warned. Returns whether it warned.
+	pub fn check(&self, difficulty: Difficulty, hashrate_hps: f64) -> bool {
+		let expected = match expected_time_to_block(difficulty, hashrate_hps) {
+			Some(expected) if expected > self.threshold => expected,
+			_ => return false,
+		};
+
+		if self.warned.swap(true, Ordering::SeqCst) {
+			return false
+		}
+
+		warn!(
+			target: LOG_TARGET,
+			"Expected one block every {} at {} H/s and difficulty {}, more than {}. \
+			 Check that the node is on the intended network.",
+			humantime::format_duration(Duration::from_secs(expected.as_secs())),
+			hashrate_hps,
+			difficulty,
+			humantime::format_duration(self.threshold),
+		);
+
+		true
+	}
+
+	pub fn warned(&self) -> bool {
+		self.warned.load(Ordering::SeqCst)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn impractical_time_to_block_warns_once() {
+		let logs = crate::mock::capture_logs();
+		let year = Duration::from_secs(365 * 24 * 60 * 60);
+		let warning = TimeToBlockWarning::new(year);
+
+		let expected = expected_time_to_block(Difficulty::from(1000), 10.0).unwrap();
+		assert!((expected.as_secs_f64() - 100.0).abs() < 1e-6);
+		assert_eq!(expected_time_to_block(Difficulty::from(1000), 0.0), None);
+		assert!(!warning.check(Difficulty::from(1000), 10.0));
+		assert!(!warning.warned());
+
+		let difficulty = Difficulty::from(1u64 << 50);
+		assert!(warning.check(difficulty, 5.0));
+		assert!(!warning.check(difficulty, 5.0));
+		assert!(warning.warned());
+
+		let warnings = logs.records().into_iter()
+			.filter(|(target, level, message)| {
+				target == LOG_TARGET && *level == log::Level::Warn &&
+					message.contains(&format!("difficulty {}", difficulty))
+			})
+			.count();
+		assert_eq!(warnings, 1);
+	}
+
+	#[test]
+	fn luck_ratio_compares_expected_to_spent() {
+		let mut tracker = LuckTracker::new();
+		assert_eq!(tracker.luck_ratio(), 1.0);
+
+		tracker.record_block_found(500, Difficulty::from(1000));
+		assert_eq!(tracker.luck_ratio(), 2.0);
+		tracker.record_block_found(3500, Difficulty::from(1000));
+		assert_eq!(tracker.luck_ratio(), 0.5);
+		assert_eq!(tracker.blocks(), 2);
+
+		let mut rolling = LuckTracker::with_window(1);
+		rolling.record_block_found(3500, Difficulty::from(1000));
+		rolling.record_block_found(250, Difficulty::from(1000));
+		assert_eq!(rolling.luck_ratio(), 4.0);
+		assert_eq!(rolling.blocks(), 1);
+	}
+}
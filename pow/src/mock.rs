@@ -0,0 +1,390 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Mock work backend, chain and runtime for tests
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, atomic::{AtomicUsize, Ordering}};
+use codec::Encode;
+use lazy_static::lazy_static;
+use sp_core::{H256, U256, blake2_256};
+use sp_api::{ApiRef, ProvideRuntimeApi};
+use sp_runtime::generic::BlockId;
+use sp_runtime::testing::{Block, ExtrinsicWrapper, Header};
+use sp_runtime::traits::Header as HeaderT;
+use sp_blockchain::{self as blockchain, HeaderBackend, BlockStatus};
+use sp_consensus_pow::DifficultyApi;
+use sc_client_api::backend::AuxStore;
+use kulupu_primitives::{
+	Difficulty, AlgorithmApi, KeyHashApi, ExpectedKeyHashApi, KeyHashParams,
+	DEFAULT_KEY_HASH_PARAMS,
+};
+use crate::compute::{ComputeMode, WorkBackend};
+use crate::KeyHashCache;
+
+lazy_static! {
+	static ref SHARED_CACHES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Tests touching the process-wide shared caches must hold this lock, so that
+/// they do not interleave.
+pub fn lock_shared_caches() -> MutexGuard<'static, ()> {
+	SHARED_CACHES_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Work backend hashing with blake2 instead of RandomX, counting its invocations.
+#[derive(Default)]
+pub struct CountingBackend {
+	calls: AtomicUsize,
+}
+
+impl CountingBackend {
+	pub fn calls(&self) -> usize {
+		self.calls.load(Ordering::SeqCst)
+	}
+}
+
+impl WorkBackend for CountingBackend {
+	fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+		self.calls.fetch_add(1, Ordering::SeqCst);
+		H256::from(blake2_256(&(key_hash, input).encode()[..]))
+	}
+}
+
+/// Work backend hashing with blake2, recording every input it is given.
+#[derive(Default)]
+pub struct RecordingBackend {
+	inputs: Mutex<Vec<(H256, Vec<u8>)>>,
+}
+
+impl RecordingBackend {
+	pub fn inputs(&self) -> Vec<(H256, Vec<u8>)> {
+		self.inputs.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+}
+
+impl WorkBackend for RecordingBackend {
+	fn work(&self, key_hash: &H256, input: &[u8], _mode: ComputeMode) -> H256 {
+		self.inputs.lock().unwrap_or_else(|e| e.into_inner()).push((*key_hash, input.to_vec()));
+		H256::from(blake2_256(&(key_hash, input).encode()[..]))
+	}
+}
+
+/// Logs captured by the process-wide test logger, as target, level and message.
+pub struct CapturedLogs {
+	records: Mutex<Vec<(String, log::Level, String)>>,
+}
+
+impl CapturedLogs {
+	pub fn records(&self) -> Vec<(String, log::Level, String)> {
+		self.records.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+}
+
+impl log::Log for CapturedLogs {
+	fn enabled(&self, _metadata: &log::Metadata) -> bool {
+		true
+	}
+
+	fn log(&self, record: &log::Record) {
+		self.records.lock().unwrap_or_else(|e| e.into_inner()).push((
+			record.target().to_string(),
+			record.level(),
+			record.args().to_string(),
+		));
+	}
+
+	fn flush(&self) { }
+}
+
+lazy_static! {
+	static ref CAPTURED_LOGS: CapturedLogs = CapturedLogs { records: Mutex::new(Vec::new()) };
+}
+
+/// Install the capturing logger, once per test process, and return its logs.
+pub fn capture_logs() -> &'static CapturedLogs {
+	static INIT: std::sync::Once = std::sync::Once::new();
+	INIT.call_once(|| {
+		log::set_logger(&*CAPTURED_LOGS).expect("No other logger is installed by tests");
+		log::set_max_level(log::LevelFilter::Trace);
+	});
+
+	&CAPTURED_LOGS
+}
+
+pub type TestBlock = Block<ExtrinsicWrapper<u64>>;
+
+/// Linear chain of headers, from which some can be pruned.
+pub struct MockChain {
+	headers: HashMap<H256, Header>,
+	hashes: Vec<H256>,
+}
+
+impl MockChain {
+	/// Chain with blocks `0..=best`.
+	pub fn new(best: u64) -> Self {
+		let mut chain = Self { headers: HashMap::new(), hashes: Vec::new() };
+		let mut parent_hash = H256::zero();
+
+		for number in 0..=best {
+			let header = Header {
+				parent_hash,
+				number,
+				state_root: H256::from_low_u64_be(number),
+				extrinsics_root: Default::default(),
+				digest: Default::default(),
+			};
+			parent_hash = header.hash();
+			chain.hashes.push(parent_hash);
+			chain.headers.insert(parent_hash, header);
+		}
+
+		chain
+	}
+
+	pub fn hash(&self, number: u64) -> H256 {
+		self.hashes[number as usize]
+	}
+
+	pub fn prune(&mut self, number: u64) {
+		let hash = self.hash(number);
+		self.headers.remove(&hash);
+	}
+}
+
+impl HeaderBackend<TestBlock> for MockChain {
+	fn header(&self, id: BlockId<TestBlock>) -> blockchain::Result<Option<Header>> {
+		let hash = match id {
+			BlockId::Hash(hash) => hash,
+			BlockId::Number(number) => match self.hashes.get(number as usize) {
+				Some(hash) => *hash,
+				None => return Ok(None),
+			},
+		};
+
+		Ok(self.headers.get(&hash).cloned())
+	}
+
+	fn info(&self) -> blockchain::Info<TestBlock> {
+		let best_number = self.hashes.len() as u64 - 1;
+		let best_hash = self.hash(best_number);
+
+		blockchain::Info {
+			best_hash,
+			best_number,
+			genesis_hash: self.hash(0),
+			finalized_hash: best_hash,
+			finalized_number: best_number,
+			number_leaves: 1,
+		}
+	}
+
+	fn status(&self, id: BlockId<TestBlock>) -> blockchain::Result<BlockStatus> {
+		match self.header(id)? {
+			Some(_) => Ok(BlockStatus::InChain),
+			None => Ok(BlockStatus::Unknown),
+		}
+	}
+
+	fn number(&self, hash: H256) -> blockchain::Result<Option<u64>> {
+		Ok(self.headers.get(&hash).map(|header| header.number))
+	}
+
+	fn hash(&self, number: u64) -> blockchain::Result<Option<H256>> {
+		Ok(self.hashes.get(number as usize).cloned())
+	}
+}
+
+/// State answered by the mock runtime, with the number of calls made to it.
+pub struct MockRuntime {
+	pub identifier: Mutex<[u8; 8]>,
+	pub difficulty: Mutex<Difficulty>,
+	pub key_hash_params: Mutex<KeyHashParams>,
+	pub expected_key_hash: Mutex<Option<H256>>,
+	pub identifier_calls: AtomicUsize,
+	pub difficulty_calls: AtomicUsize,
+	pub key_hash_params_calls: AtomicUsize,
+	pub expected_key_hash_calls: AtomicUsize,
+}
+
+impl MockRuntime {
+	fn new(identifier: [u8; 8]) -> Self {
+		Self {
+			identifier: Mutex::new(identifier),
+			difficulty: Mutex::new(U256::one()),
+			key_hash_params: Mutex::new(DEFAULT_KEY_HASH_PARAMS),
+			expected_key_hash: Mutex::new(None),
+			identifier_calls: AtomicUsize::new(0),
+			difficulty_calls: AtomicUsize::new(0),
+			key_hash_params_calls: AtomicUsize::new(0),
+			expected_key_hash_calls: AtomicUsize::new(0),
+		}
+	}
+
+	pub fn set_identifier(&self, identifier: [u8; 8]) {
+		*self.identifier.lock().unwrap_or_else(|e| e.into_inner()) = identifier;
+	}
+
+	pub fn set_difficulty(&self, difficulty: Difficulty) {
+		*self.difficulty.lock().unwrap_or_else(|e| e.into_inner()) = difficulty;
+	}
+
+	pub fn set_key_hash_params(&self, params: KeyHashParams) {
+		*self.key_hash_params.lock().unwrap_or_else(|e| e.into_inner()) = params;
+	}
+
+	pub fn set_expected_key_hash(&self, key_hash: Option<H256>) {
+		*self.expected_key_hash.lock().unwrap_or_else(|e| e.into_inner()) = key_hash;
+	}
+
+	/// Total number of runtime calls made.
+	pub fn calls(&self) -> usize {
+		self.identifier_calls.load(Ordering::SeqCst) +
+			self.difficulty_calls.load(Ordering::SeqCst) +
+			self.key_hash_params_calls.load(Ordering::SeqCst) +
+			self.expected_key_hash_calls.load(Ordering::SeqCst)
+	}
+}
+
+/// Runtime API handed out by [`MockClient`].
+pub struct MockRuntimeApi {
+	runtime: Arc<MockRuntime>,
+}
+
+sp_api::mock_impl_runtime_apis! {
+	impl DifficultyApi<TestBlock, Difficulty> for MockRuntimeApi {
+		type Error = blockchain::Error;
+
+		fn difficulty(&self) -> Difficulty {
+			self.runtime.difficulty_calls.fetch_add(1, Ordering::SeqCst);
+			*self.runtime.difficulty.lock().unwrap_or_else(|e| e.into_inner())
+		}
+	}
+
+	impl AlgorithmApi<TestBlock> for MockRuntimeApi {
+		fn identifier(&self) -> [u8; 8] {
+			self.runtime.identifier_calls.fetch_add(1, Ordering::SeqCst);
+			*self.runtime.identifier.lock().unwrap_or_else(|e| e.into_inner())
+		}
+	}
+
+	impl KeyHashApi<TestBlock> for MockRuntimeApi {
+		fn key_hash_params(&self) -> KeyHashParams {
+			self.runtime.key_hash_params_calls.fetch_add(1, Ordering::SeqCst);
+			*self.runtime.key_hash_params.lock().unwrap_or_else(|e| e.into_inner())
+		}
+	}
+
+	impl ExpectedKeyHashApi<TestBlock> for MockRuntimeApi {
+		fn expected_key_hash(&self) -> Option<H256> {
+			self.runtime.expected_key_hash_calls.fetch_add(1, Ordering::SeqCst);
+			*self.runtime.expected_key_hash.lock().unwrap_or_else(|e| e.into_inner())
+		}
+	}
+}
+
+/// Key hash cache over a map, recording the key hashes verification resolves.
+#[derive(Default)]
+pub struct MapKeyHashCache(Mutex<HashMap<H256, H256>>);
+
+impl MapKeyHashCache {
+	pub fn entries(&self) -> HashMap<H256, H256> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+}
+
+impl KeyHashCache for MapKeyHashCache {
+	fn get(&self, parent_hash: &H256) -> Option<H256> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner()).get(parent_hash).cloned()
+	}
+
+	fn insert(&self, parent_hash: H256, key_hash: H256) {
+		self.0.lock().unwrap_or_else(|e| e.into_inner()).insert(parent_hash, key_hash);
+	}
+}
+
+/// Client over a [`MockChain`] and a [`MockRuntime`], with an in-memory aux store.
+pub struct MockClient {
+	pub chain: MockChain,
+	pub runtime: Arc<MockRuntime>,
+	aux: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MockClient {
+	/// Client over blocks `0..=best`, whose runtime reports `identifier`.
+	pub fn new(best: u64, identifier: [u8; 8]) -> Self {
+		Self {
+			chain: MockChain::new(best),
+			runtime: Arc::new(MockRuntime::new(identifier)),
+			aux: Mutex::new(HashMap::new()),
+		}
+	}
+}
+
+impl ProvideRuntimeApi<TestBlock> for MockClient {
+	type Api = MockRuntimeApi;
+
+	fn runtime_api<'a>(&'a self) -> ApiRef<'a, Self::Api> {
+		MockRuntimeApi { runtime: self.runtime.clone() }.into()
+	}
+}
+
+impl HeaderBackend<TestBlock> for MockClient {
+	fn header(&self, id: BlockId<TestBlock>) -> blockchain::Result<Option<Header>> {
+		self.chain.header(id)
+	}
+
+	fn info(&self) -> blockchain::Info<TestBlock> {
+		self.chain.info()
+	}
+
+	fn status(&self, id: BlockId<TestBlock>) -> blockchain::Result<BlockStatus> {
+		self.chain.status(id)
+	}
+
+	fn number(&self, hash: H256) -> blockchain::Result<Option<u64>> {
+		self.chain.number(hash)
+	}
+
+	fn hash(&self, number: u64) -> blockchain::Result<Option<H256>> {
+		HeaderBackend::hash(&self.chain, number)
+	}
+}
+
+impl AuxStore for MockClient {
+	fn insert_aux<
+		'a,
+		'b: 'a,
+		'c: 'a,
+		I: IntoIterator<Item=&'a (&'c [u8], &'c [u8])>,
+		D: IntoIterator<Item=&'a &'b [u8]>,
+	>(&self, insert: I, delete: D) -> blockchain::Result<()> {
+		let mut aux = self.aux.lock().unwrap_or_else(|e| e.into_inner());
+		for (key, value) in insert {
+			aux.insert(key.to_vec(), value.to_vec());
+		}
+		for key in delete {
+			aux.remove(*key);
+		}
+		Ok(())
+	}
+
+	fn get_aux(&self, key: &[u8]) -> blockchain::Result<Option<Vec<u8>>> {
+		Ok(self.aux.lock().unwrap_or_else(|e| e.into_inner()).get(key).cloned())
+	}
+}
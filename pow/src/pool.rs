@@ -0,0 +1,550 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Validation of solutions submitted by external miners.
+
+use std::fmt;
+use codec::{Encode, Decode};
+use lru_cache::LruCache;
+use sp_core::{H256, H512, U256, sr25519};
+use sp_consensus_pow::Seal as RawSeal;
+use kulupu_primitives::Difficulty;
+use crate::{app, DefaultTargetChecker, TargetChecker};
+use crate::compute::{ComputeV2, ComputeMode, RandomXBackend, WorkBackend, SigningDomain, parse_signature};
+
+/// Job handed out to external miners.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MiningJob {
+	pub pre_hash: H256,
+	pub key_hash: H256,
+	pub difficulty: Difficulty,
+	pub author: app::Public,
+	pub signing_domain: SigningDomain,
+}
+
+/// Version of the [`MiningJob`] binary format written by [`MiningJob::to_bytes`].
+pub const MINING_JOB_FORMAT_VERSION: u8 = 1;
+
+/// Length of a [`MiningJob`] in binary format version 1.
+pub const MINING_JOB_V1_LEN: usize = 130;
+
+/// Reason a binary [`MiningJob`] could not be parsed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum JobFormatError {
+	/// The input is empty.
+	Empty,
+	/// The format version is not known.
+	UnsupportedVersion(u8),
+	/// The input does not have the length of its format version.
+	BadLength { expected: usize, actual: usize },
+	/// The signing domain byte is not known.
+	UnknownSigningDomain(u8),
+}
+
+impl fmt::Display for JobFormatError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			JobFormatError::Empty => write!(f, "Empty mining job"),
+			JobFormatError::UnsupportedVersion(version) =>
+				write!(f, "Unsupported mining job format version {}", version),
+			JobFormatError::BadLength { expected, actual } =>
+				write!(f, "Mining job is {} bytes, expected {}", actual, expected),
+			JobFormatError::UnknownSigningDomain(domain) =>
+				write!(f, "Unknown signing domain {}", domain),
+		}
+	}
+}
+
+impl std::error::Error for JobFormatError { }
+
+impl MiningJob {
+	/// Encode the job in the binary format for pool clients. Version 1 is
+	/// 130 bytes, in this order:
+	///
+	/// - 1 byte: format version, [`MINING_JOB_FORMAT_VERSION`].
+	/// - 32 bytes: pre-hash.
+	/// - 32 bytes: key hash.
+	/// - 32 bytes: difficulty, as an unsigned 256-bit little-endian integer.
+	/// - 32 bytes: author sr25519 public key.
+	/// - 1 byte: signing domain, `0` for legacy and `1` for V2.
+	///
+	/// Hashes and the public key are their raw bytes.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(MINING_JOB_V1_LEN);
+		bytes.push(MINING_JOB_FORMAT_VERSION);
+		bytes.extend_from_slice(&self.pre_hash[..]);
+		bytes.extend_from_slice(&self.key_hash[..]);
+
+		let mut difficulty = [0u8; 32];
+		self.difficulty.to_little_endian(&mut difficulty);
+		bytes.extend_from_slice(&difficulty);
+
+		self.author.encode_to(&mut bytes);
+		bytes.push(match self.signing_domain {
+			SigningDomain::Legacy => 0,
+			SigningDomain::V2 => 1,
+		});
+
+		bytes
+	}
+
+	/// Decode a job written by [`MiningJob::to_bytes`].
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, JobFormatError> {
+		match bytes.first() {
+			None => return Err(JobFormatError::Empty),
+			Some(&MINING_JOB_FORMAT_VERSION) => (),
+			Some(version) => return Err(JobFormatError::UnsupportedVersion(*version)),
+		}
+		if bytes.len() != MINING_JOB_V1_LEN {
+			return Err(JobFormatError::BadLength { expected: MINING_JOB_V1_LEN, actual: bytes.len() })
+		}
+
+		let author = app::Public::decode(&mut &bytes[97..129])
+			.expect("Public keys are any 32 bytes; qed");
+		let signing_domain = match bytes[129] {
+			0 => SigningDomain::Legacy,
+			1 => SigningDomain::V2,
+			domain => return Err(JobFormatError::UnknownSigningDomain(domain)),
+		};
+
+		Ok(Self {
+			pre_hash: H256::from_slice(&bytes[1..33]),
+			key_hash: H256::from_slice(&bytes[33..65]),
+			difficulty: Difficulty::from_little_endian(&bytes[65..97]),
+			author,
+			signing_domain,
+		})
+	}
+}
+
+/// Reason a submitted solution was rejected.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SubmitError {
+	/// The solution is for a different job than the current one.
+	StaleJob,
+	/// The seal or signature could not be parsed.
+	MalformedSeal,
+	/// The signature does not match the job author.
+	BadSignature,
+	/// The nonce was already submitted for this job.
+	DuplicateShare,
+	/// The work does not meet the difficulty. `quality` is the highest
+	/// difficulty it does meet.
+	LowDifficulty { quality: Difficulty },
+}
+
+impl fmt::Display for SubmitError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SubmitError::StaleJob => write!(f, "Stale job"),
+			SubmitError::MalformedSeal => write!(f, "Malformed seal"),
+			SubmitError::BadSignature => write!(f, "Bad signature"),
+			SubmitError::DuplicateShare => write!(f, "Duplicate share"),
+			SubmitError::LowDifficulty { quality } =>
+				write!(f, "Low difficulty, work only meets difficulty {}", quality),
+		}
+	}
+}
+
+impl std::error::Error for SubmitError { }
+
+/// Highest difficulty the given work meets.
+pub fn work_quality(work: &H256) -> Difficulty {
+	let num_work = U256::from(&work[..]);
+
+	if num_work.is_zero() {
+		U256::max_value()
+	} else {
+		U256::max_value() / num_work
+	}
+}
+
+/// Recently submitted nonces, to reject duplicate submissions.
+pub struct SeenNonces {
+	seen: LruCache<(H256, H256), ()>,
+}
+
+impl SeenNonces {
+	pub fn new(capacity: usize) -> Self {
+		Self { seen: LruCache::new(capacity) }
+	}
+
+	/// Record a nonce for the given pre-hash. Returns `false` if it was
+	/// already seen.
+	pub fn insert(&mut self, pre_hash: &H256, nonce: &H256) -> bool {
+		self.seen.insert((*pre_hash, *nonce), ()).is_none()
+	}
+}
+
+/// Recently submitted signatures, to flag submissions reusing one as
+/// suspicious. Signatures cover the nonce, so a reused signature can only be a
+/// replay, which would fail the recompute anyway, but is much cheaper to
+/// catch here.
+pub struct SeenSignatures {
+	seen: LruCache<H512, ()>,
+}
+
+impl SeenSignatures {
+	pub fn new(capacity: usize) -> Self {
+		Self { seen: LruCache::new(capacity) }
+	}
+
+	/// Record a signature. Returns `false` if it was already seen.
+	pub fn insert(&mut self, signature: &sr25519::Signature) -> bool {
+		self.seen.insert(H512::from(signature.0), ()).is_none()
+	}
+}
+
+/// Validate a solution submitted for `job`, returning the encoded seal. Work
+/// is checked against the job difficulty by `target`.
+pub fn submit_solution<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	job: &MiningJob,
+	seen: &mut SeenNonces,
+	pre_hash: &H256,
+	nonce: &H256,
+	signature: &[u8],
+) -> Result<RawSeal, SubmitError> {
+	if *pre_hash != job.pre_hash {
+		return Err(SubmitError::StaleJob)
+	}
+
+	let signature = parse_signature(signature).map_err(|_| SubmitError::MalformedSeal)?;
+
+	let compute = ComputeV2 {
+		key_hash: job.key_hash,
+		difficulty: job.difficulty,
+		pre_hash: job.pre_hash,
+		nonce: *nonce,
+	};
+
+	if !compute.verify_in(&signature, &job.author, job.signing_domain) {
+		return Err(SubmitError::BadSignature)
+	}
+
+	if !seen.insert(pre_hash, nonce) {
+		return Err(SubmitError::DuplicateShare)
+	}
+
+	let (seal, work) = compute.seal_and_work_with(backend, signature, ComputeMode::Mining);
+
+	if !target.is_valid(&work, job.difficulty) {
+		return Err(SubmitError::LowDifficulty { quality: work_quality(&work) })
+	}
+
+	Ok(seal.encode())
+}
+
+/// Validate a batch of solutions signed offline for `job`, returning the
+/// encoded seal of each valid one. A nonce repeated within the batch is
+/// rejected as a duplicate.
+pub fn verify_signed_batch(
+	job: &MiningJob,
+	entries: &[(H256, sr25519::Signature)],
+) -> Vec<Result<RawSeal, SubmitError>> {
+	verify_signed_batch_with(&RandomXBackend, &DefaultTargetChecker, job, entries)
+}
+
+/// Like [`verify_signed_batch`], with a custom work backend and target check.
+pub fn verify_signed_batch_with<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	job: &MiningJob,
+	entries: &[(H256, sr25519::Signature)],
+) -> Vec<Result<RawSeal, SubmitError>> {
+	let mut seen = SeenNonces::new(entries.len().max(1));
+
+	entries.iter().map(|(nonce, signature)| {
+		submit_solution(backend, target, job, &mut seen, &job.pre_hash, nonce, signature.as_ref())
+	}).collect()
+}
+
+/// Check a batch of shares for `job` at the pool share difficulty, returning
+/// whether each one is valid. Shares are signed over the job difficulty, but
+/// their work only has to meet `share_difficulty`.
+///
+/// All shares are computed on the machine of the calling thread, which is only
+/// built once for the job key hash.
+pub fn verify_shares(
+	job: &MiningJob,
+	submissions: &[(H256, sr25519::Signature)],
+	share_difficulty: Difficulty,
+) -> Vec<bool> {
+	verify_shares_with(&RandomXBackend, &DefaultTargetChecker, job, submissions, share_difficulty)
+}
+
+/// Like [`verify_shares`], with a custom work backend and target check.
+pub fn verify_shares_with<W: WorkBackend + ?Sized, T: TargetChecker + ?Sized>(
+	backend: &W,
+	target: &T,
+	job: &MiningJob,
+	submissions: &[(H256, sr25519::Signature)],
+	share_difficulty: Difficulty,
+) -> Vec<bool> {
+	submissions.iter().map(|(nonce, signature)| {
+		let signature = app::Signature::from(signature.clone());
+		let compute = ComputeV2 {
+			key_hash: job.key_hash,
+			difficulty: job.difficulty,
+			pre_hash: job.pre_hash,
+			nonce: *nonce,
+		};
+
+		if !compute.verify_in(&signature, &job.author, job.signing_domain) {
+			return false
+		}
+
+		let (_, work) = compute.seal_and_work_with(backend, signature, ComputeMode::Mining);
+		target.is_valid(&work, share_difficulty)
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Pair;
+	use crate::mock::CountingBackend;
+
+	fn job(pair: &app::Pair, difficulty: Difficulty) -> MiningJob {
+		MiningJob {
+			pre_hash: H256::repeat_byte(1),
+			key_hash: H256::repeat_byte(2),
+			difficulty,
+			author: pair.public(),
+			signing_domain: SigningDomain::V2,
+		}
+	}
+
+	fn signature(job: &MiningJob, nonce: &H256, pair: &app::Pair) -> Vec<u8> {
+		ComputeV2 {
+			key_hash: job.key_hash,
+			difficulty: job.difficulty,
+			pre_hash: job.pre_hash,
+			nonce: *nonce,
+		}.sign_in(pair, job.signing_domain).encode()
+	}
+
+	#[test]
+	fn job_binary_format_is_pinned() {
+		let job = MiningJob {
+			pre_hash: H256::repeat_byte(1),
+			key_hash: H256::repeat_byte(2),
+			difficulty: U256::from(0x0102),
+			author: app::Public::decode(&mut &[4u8; 32][..]).unwrap(),
+			signing_domain: SigningDomain::V2,
+		};
+
+		let mut expected = vec![0x01];
+		expected.extend_from_slice(&[0x01; 32]);
+		expected.extend_from_slice(&[0x02; 32]);
+		expected.extend_from_slice(&[0x02, 0x01]);
+		expected.extend_from_slice(&[0x00; 30]);
+		expected.extend_from_slice(&[0x04; 32]);
+		expected.push(0x01);
+
+		let bytes = job.to_bytes();
+		assert_eq!(bytes, expected);
+		assert_eq!(MiningJob::from_bytes(&bytes), Ok(job.clone()));
+
+		let legacy = MiningJob { signing_domain: SigningDomain::Legacy, ..job };
+		assert_eq!(legacy.to_bytes()[129], 0x00);
+		assert_eq!(MiningJob::from_bytes(&legacy.to_bytes()), Ok(legacy));
+	}
+
+	#[test]
+	fn malformed_job_bytes_are_refused() {
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let bytes = job(&pair, U256::max_value()).to_bytes();
+
+		assert_eq!(MiningJob::from_bytes(&[]), Err(JobFormatError::Empty));
+		assert_eq!(
+			MiningJob::from_bytes(&bytes[..129]),
+			Err(JobFormatError::BadLength { expected: 130, actual: 129 }),
+		);
+
+		let mut future = bytes.clone();
+		future[0] = 2;
+		assert_eq!(MiningJob::from_bytes(&future), Err(JobFormatError::UnsupportedVersion(2)));
+
+		let mut domain = bytes;
+		domain[129] = 7;
+		assert_eq!(MiningJob::from_bytes(&domain), Err(JobFormatError::UnknownSigningDomain(7)));
+	}
+
+	#[test]
+	fn shares_are_verified_in_batch() {
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let job = job(&pair, U256::max_value());
+
+		let share = |nonce: u8, signer: &app::Pair, signed_nonce: u8| {
+			let signature = ComputeV2 {
+				key_hash: job.key_hash,
+				difficulty: job.difficulty,
+				pre_hash: job.pre_hash,
+				nonce: H256::repeat_byte(signed_nonce),
+			}.sign_in(signer, job.signing_domain);
+			(H256::repeat_byte(nonce), sr25519::Signature::from(signature))
+		};
+		let submissions = vec![
+			share(3, &pair, 3),
+			share(4, &other, 4),
+			share(5, &pair, 5),
+			share(6, &pair, 7),
+		];
+
+		assert_eq!(
+			verify_shares_with(&backend, &DefaultTargetChecker, &job, &submissions, U256::one()),
+			vec![true, false, true, false],
+		);
+		assert_eq!(backend.calls(), 2);
+
+		assert_eq!(
+			verify_shares_with(&backend, &DefaultTargetChecker, &job, &submissions, U256::max_value()),
+			vec![false; 4],
+		);
+	}
+
+	#[test]
+	fn signed_batch_returns_seals_of_valid_entries() {
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let job = job(&pair, U256::one());
+
+		let entry = |nonce: u8, signer: &app::Pair| {
+			let nonce = H256::repeat_byte(nonce);
+			let signature = ComputeV2 {
+				key_hash: job.key_hash,
+				difficulty: job.difficulty,
+				pre_hash: job.pre_hash,
+				nonce,
+			}.sign_in(signer, job.signing_domain);
+			(nonce, sr25519::Signature::from(signature))
+		};
+		let entries = vec![entry(3, &pair), entry(4, &other), entry(5, &pair), entry(3, &pair)];
+
+		let results = verify_signed_batch_with(&backend, &DefaultTargetChecker, &job, &entries);
+		let mut seen = SeenNonces::new(4);
+		let expected = |nonce: u8| submit_solution(
+			&backend, &DefaultTargetChecker, &job, &mut seen, &job.pre_hash, &H256::repeat_byte(nonce),
+			&signature(&job, &H256::repeat_byte(nonce), &pair),
+		);
+
+		assert_eq!(results[0], expected(3));
+		assert!(results[0].is_ok());
+		assert_eq!(results[1], Err(SubmitError::BadSignature));
+		assert_eq!(results[2], expected(5));
+		assert!(results[2].is_ok());
+		assert_eq!(results[3], Err(SubmitError::DuplicateShare));
+	}
+
+	#[test]
+	fn resubmitted_signature_is_flagged() {
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let job = job(&pair, U256::one());
+		let sign = |nonce: u8| sr25519::Signature::from_slice(
+			&signature(&job, &H256::repeat_byte(nonce), &pair)[..],
+		);
+		let (first, second) = (sign(3), sign(4));
+		let mut seen = SeenSignatures::new(1);
+
+		assert!(seen.insert(&first));
+		assert!(!seen.insert(&first));
+		assert!(seen.insert(&second));
+		// Only the latest signatures are remembered.
+		assert!(seen.insert(&first));
+	}
+
+	#[test]
+	fn rejects_each_invalid_submission() {
+		let backend = CountingBackend::default();
+		let target = DefaultTargetChecker;
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let other = app::Pair::from_seed(&[2u8; 32]);
+		let mut seen = SeenNonces::new(16);
+		let nonce = H256::repeat_byte(3);
+
+		let easy = job(&pair, U256::one());
+		let sig = signature(&easy, &nonce, &pair);
+
+		assert_eq!(
+			submit_solution(&backend, &target, &easy, &mut seen, &H256::repeat_byte(9), &nonce, &sig),
+			Err(SubmitError::StaleJob),
+		);
+		assert_eq!(
+			submit_solution(&backend, &target, &easy, &mut seen, &easy.pre_hash, &nonce, &sig[1..]),
+			Err(SubmitError::MalformedSeal),
+		);
+		assert_eq!(
+			submit_solution(
+				&backend, &target, &easy, &mut seen, &easy.pre_hash, &nonce,
+				&signature(&easy, &nonce, &other),
+			),
+			Err(SubmitError::BadSignature),
+		);
+		assert!(submit_solution(&backend, &target, &easy, &mut seen, &easy.pre_hash, &nonce, &sig).is_ok());
+		assert_eq!(
+			submit_solution(&backend, &target, &easy, &mut seen, &easy.pre_hash, &nonce, &sig),
+			Err(SubmitError::DuplicateShare),
+		);
+
+		let hard = job(&pair, U256::max_value());
+		let nonce = H256::repeat_byte(4);
+		match submit_solution(
+			&backend, &target, &hard, &mut seen, &hard.pre_hash, &nonce,
+			&signature(&hard, &nonce, &pair),
+		) {
+			Err(SubmitError::LowDifficulty { quality }) => assert!(quality < hard.difficulty),
+			other => panic!("Unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn solutions_and_shares_follow_target_checker() {
+		struct AcceptAll;
+
+		impl TargetChecker for AcceptAll {
+			fn is_valid(&self, _work: &H256, _difficulty: Difficulty) -> bool {
+				true
+			}
+		}
+
+		let backend = CountingBackend::default();
+		let pair = app::Pair::from_seed(&[1u8; 32]);
+		let hard = job(&pair, U256::max_value());
+		let nonce = H256::repeat_byte(4);
+		let sig = signature(&hard, &nonce, &pair);
+
+		assert!(submit_solution(
+			&backend, &DefaultTargetChecker, &hard, &mut SeenNonces::new(1), &hard.pre_hash, &nonce, &sig,
+		).is_err());
+		assert!(submit_solution(
+			&backend, &AcceptAll, &hard, &mut SeenNonces::new(1), &hard.pre_hash, &nonce, &sig,
+		).is_ok());
+
+		let share = (nonce, sr25519::Signature::from_slice(&sig[..]));
+		assert_eq!(
+			verify_shares_with(&backend, &DefaultTargetChecker, &hard, &[share.clone()], U256::max_value()),
+			vec![false],
+		);
+		assert_eq!(verify_shares_with(&backend, &AcceptAll, &hard, &[share], U256::max_value()), vec![true]);
+	}
+}
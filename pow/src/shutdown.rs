@@ -0,0 +1,248 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Coordinator draining in-flight compute before machines and caches are
+//! dropped, so that none is dropped in the middle of a RandomX call.
+
+use std::thread::{self, ThreadId};
+use parking_lot::{Condvar, Mutex};
+
+type Teardown = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+	stopping: bool,
+	/// Thread of each call in flight.
+	in_flight: Vec<ThreadId>,
+	/// Teardown waiting for the calls in flight on the stopping thread.
+	teardown: Option<Teardown>,
+}
+
+impl State {
+	fn in_flight_on(&self, thread: ThreadId) -> bool {
+		self.in_flight.contains(&thread)
+	}
+}
+
+/// Tracks compute calls in flight, refusing new ones once stopped.
+pub struct Shutdown {
+	state: Mutex<State>,
+	drained: Condvar,
+	release: fn(),
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Shutdown {
+	pub fn new() -> Self {
+		Self::with_release(|| ())
+	}
+
+	/// Coordinator running `release` on every thread once stopping, as soon
+	/// as it has no call in flight: when its last call returns, when a call of
+	/// it is refused, and on the stopping thread. Thread-local machines can
+	/// only be dropped by their own thread.
+	pub fn with_release(release: fn()) -> Self {
+		Self { state: Mutex::new(State::default()), drained: Condvar::new(), release }
+	}
+
+	pub fn is_stopping(&self) -> bool {
+		self.state.lock().stopping
+	}
+
+	pub fn in_flight(&self) -> usize {
+		self.state.lock().in_flight.len()
+	}
+
+	/// Mark a call as in flight until the guard is dropped. `None` once
+	/// stopping, in which case the call is not to be made.
+	pub fn enter(&self) -> Option<InFlight> {
+		let current = thread::current().id();
+		let mut state = self.state.lock();
+		if state.stopping {
+			let idle = !state.in_flight_on(current);
+			drop(state);
+			if idle {
+				(self.release)();
+			}
+			return None
+		}
+
+		state.in_flight.push(current);
+		Some(InFlight(self))
+	}
+
+	/// Run `f` as an in-flight call, unless stopping.
+	pub fn run<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+		let _in_flight = self.enter()?;
+		Some(f())
+	}
+
+	/// Refuse new calls, wait for those in flight on other threads to return,
+	/// then run `teardown`. Called from within a call, `teardown` runs on this
+	/// thread when its calls return. Only the first stop runs its teardown.
+	pub fn stop_with<F: FnOnce() + Send + 'static>(&self, teardown: F) {
+		let current = thread::current().id();
+		let mut state = self.state.lock();
+		if state.stopping {
+			return
+		}
+
+		state.stopping = true;
+		while state.in_flight.iter().any(|thread| *thread != current) {
+			self.drained.wait(&mut state);
+		}
+
+		if state.in_flight_on(current) {
+			state.teardown = Some(Box::new(teardown));
+			return
+		}
+		drop(state);
+
+		(self.release)();
+		teardown();
+	}
+}
+
+/// Guard of a call in flight. See [`Shutdown::enter`].
+pub struct InFlight<'a>(&'a Shutdown);
+
+impl<'a> Drop for InFlight<'a> {
+	fn drop(&mut self) {
+		let current = thread::current().id();
+		let mut state = self.0.state.lock();
+		if let Some(index) = state.in_flight.iter().position(|thread| *thread == current) {
+			state.in_flight.remove(index);
+		}
+
+		let idle = state.stopping && !state.in_flight_on(current);
+		let teardown = if state.in_flight.is_empty() { state.teardown.take() } else { None };
+		self.0.drained.notify_all();
+		drop(state);
+
+		if idle {
+			(self.0.release)();
+		}
+		if let Some(teardown) = teardown {
+			teardown();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, mpsc, atomic::{AtomicBool, Ordering}};
+	use std::thread;
+	use std::time::Duration;
+
+	#[test]
+	fn teardown_waits_for_in_flight_compute() {
+		let shutdown = Arc::new(Shutdown::new());
+		let returned = Arc::new(AtomicBool::new(false));
+		let (entered, wait_entered) = mpsc::channel();
+
+		let compute = {
+			let shutdown = shutdown.clone();
+			let returned = returned.clone();
+			thread::spawn(move || shutdown.run(|| {
+				entered.send(()).unwrap();
+				thread::sleep(Duration::from_millis(50));
+				returned.store(true, Ordering::SeqCst);
+			}))
+		};
+
+		wait_entered.recv().unwrap();
+		assert_eq!(shutdown.in_flight(), 1);
+
+		let dropped = Arc::new(AtomicBool::new(false));
+		shutdown.stop_with({
+			let dropped = dropped.clone();
+			move || {
+				assert!(returned.load(Ordering::SeqCst), "Machines dropped before compute returned");
+				dropped.store(true, Ordering::SeqCst);
+			}
+		});
+		assert!(dropped.load(Ordering::SeqCst));
+
+		assert_eq!(compute.join().unwrap(), Some(()));
+		assert!(shutdown.is_stopping());
+		assert_eq!(shutdown.run(|| ()), None);
+	}
+
+	#[test]
+	fn stop_from_within_a_call_defers_teardown() {
+		let shutdown = Shutdown::new();
+		let torn_down = Arc::new(AtomicBool::new(false));
+
+		let ret = shutdown.run(|| {
+			let torn_down = torn_down.clone();
+			shutdown.stop_with(move || torn_down.store(true, Ordering::SeqCst));
+			torn_down.load(Ordering::SeqCst)
+		});
+
+		assert_eq!(ret, Some(false));
+		assert!(torn_down.load(Ordering::SeqCst));
+		assert_eq!(shutdown.in_flight(), 0);
+	}
+
+	#[test]
+	fn idle_threads_release_once_stopping() {
+		thread_local! {
+			static HELD: std::cell::Cell<bool> = std::cell::Cell::new(true);
+		}
+		fn release() {
+			HELD.with(|held| held.set(false));
+		}
+		let held = || HELD.with(|held| held.get());
+
+		let shutdown = Arc::new(Shutdown::with_release(release));
+		let (entered, wait_entered) = mpsc::channel();
+
+		let in_flight = {
+			let shutdown = shutdown.clone();
+			thread::spawn(move || {
+				shutdown.run(|| {
+					entered.send(()).unwrap();
+					thread::sleep(Duration::from_millis(50));
+					held()
+				}).unwrap() && !held()
+			})
+		};
+		let refused = {
+			let shutdown = shutdown.clone();
+			thread::spawn(move || {
+				while !shutdown.is_stopping() {
+					thread::sleep(Duration::from_millis(1));
+				}
+				shutdown.run(|| ()).is_none() && !held()
+			})
+		};
+
+		wait_entered.recv().unwrap();
+		shutdown.stop_with(|| ());
+
+		assert!(!held());
+		assert!(in_flight.join().unwrap());
+		assert!(refused.join().unwrap());
+	}
+}
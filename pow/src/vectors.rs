@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Deterministic V2 seal test vectors, for downstream verifiers.
+
+use codec::Encode;
+use serde::{Serialize, Deserialize};
+use sp_core::{H256, U256, Bytes, Pair};
+use kulupu_primitives::Difficulty;
+use crate::app;
+use crate::is_valid_hash;
+use crate::compute::{ComputeV2, ComputeMode, WorkBackend, RandomXBackend};
+
+/// Difficulty of vectors whose validity depends on their work.
+pub const VECTOR_DIFFICULTY: u64 = 16;
+
+/// A V2 seal with the inputs it is verified against, signed in the legacy
+/// domain.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestVector {
+	pub key_hash: H256,
+	pub pre_hash: H256,
+	/// Raw sr25519 public key of the author.
+	pub author: H256,
+	pub difficulty: Difficulty,
+	pub seal_bytes: Bytes,
+	/// Work of the seal, as computed by the generator. Verifiers can compare
+	/// their own work against it.
+	pub work: H256,
+	pub expected_valid: bool,
+}
+
+/// Generate `count` vectors with the RandomX machine. Cases cycle through a
+/// valid seal, a seal signed by another key, a seal for another difficulty
+/// and a seal whose work misses the difficulty, all at
+/// [`VECTOR_DIFFICULTY`]. Nonces are searched so that only the work
+/// separates the first and the last case.
+pub fn generate_test_vectors(count: usize) -> Vec<TestVector> {
+	generate_test_vectors_with(&RandomXBackend, count)
+}
+
+/// Like [`generate_test_vectors`], computing work with `backend`.
+pub fn generate_test_vectors_with<W: WorkBackend + ?Sized>(
+	backend: &W,
+	count: usize,
+) -> Vec<TestVector> {
+	(0..count).map(|index| {
+		let seed = (index as u64).to_le_bytes();
+		let pair = app::Pair::from_seed(&H256::from_low_u64_be(index as u64 + 1).0);
+		let author = pair.public();
+		let key_hash = H256::from(sp_core::blake2_256(&(b"key", seed).encode()[..]));
+		let pre_hash = H256::from(sp_core::blake2_256(&(b"pre", seed).encode()[..]));
+		let difficulty = U256::from(VECTOR_DIFFICULTY);
+
+		// First nonce whose work, signed with `signer` for `signed`, meets
+		// `difficulty` or not as `meets` requires.
+		let search = |signer: &app::Pair, signed: Difficulty, meets: bool| {
+			(0u64..).map(|attempt| {
+				let nonce = H256::from(sp_core::blake2_256(&(b"nonce", seed, attempt).encode()[..]));
+				let compute = ComputeV2 { key_hash, pre_hash, difficulty, nonce };
+				let signature = ComputeV2 { difficulty: signed, ..compute }.sign(signer);
+				compute.seal_and_work_with(backend, signature, ComputeMode::Sync)
+			}).find(|(_, work)| is_valid_hash(work, difficulty) == meets)
+				.expect("Nonces are unbounded; qed")
+		};
+
+		let ((seal, work), expected_valid) = match index % 4 {
+			0 => (search(&pair, difficulty, true), true),
+			1 => (search(&app::Pair::from_seed(&[0xff; 32]), difficulty, true), false),
+			2 => (search(&pair, difficulty * U256::from(2), true), false),
+			_ => (search(&pair, difficulty, false), false),
+		};
+
+		TestVector {
+			key_hash,
+			pre_hash,
+			author: H256::from_slice(&author.encode()[..]),
+			difficulty,
+			seal_bytes: Bytes(seal.encode()),
+			work,
+			expected_valid,
+		}
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Decode;
+	use crate::{verify_seal_v2_with, compute::{SealV2, SigningDomain}};
+	use crate::mock::CountingBackend;
+
+	fn verify<W: WorkBackend + ?Sized>(backend: &W, vector: &TestVector) -> bool {
+		let author = app::Public::decode(&mut vector.author.as_bytes()).unwrap();
+		verify_seal_v2_with(
+			backend,
+			&vector.key_hash,
+			&vector.pre_hash,
+			&author,
+			vector.difficulty,
+			SigningDomain::Legacy,
+			&vector.seal_bytes[..],
+		)
+	}
+
+	fn recompute<W: WorkBackend + ?Sized>(backend: &W, vector: &TestVector) -> H256 {
+		let seal = SealV2::decode(&mut &vector.seal_bytes[..]).unwrap();
+		let compute = ComputeV2 {
+			key_hash: vector.key_hash,
+			pre_hash: vector.pre_hash,
+			difficulty: vector.difficulty,
+			nonce: seal.nonce,
+		};
+		compute.seal_and_work_with(backend, seal.signature, ComputeMode::Sync).1
+	}
+
+	#[test]
+	fn vectors_round_trip_through_verification() {
+		let backend = CountingBackend::default();
+		let vectors = generate_test_vectors_with(&backend, 8);
+
+		assert!(vectors.iter().any(|vector| vector.expected_valid));
+		assert!(vectors.iter().any(|vector| !vector.expected_valid));
+		assert!(vectors.iter().all(|vector| vector.difficulty == U256::from(VECTOR_DIFFICULTY)));
+
+		let json = serde_json::to_string(&vectors).unwrap();
+		let decoded: Vec<TestVector> = serde_json::from_str(&json).unwrap();
+		assert_eq!(decoded, vectors);
+
+		for vector in decoded {
+			assert_eq!(recompute(&backend, &vector), vector.work);
+			assert_eq!(verify(&backend, &vector), vector.expected_valid);
+		}
+	}
+
+	#[test]
+	fn vectors_depend_on_the_work() {
+		struct SkipWork;
+
+		impl WorkBackend for SkipWork {
+			fn work(&self, _key_hash: &H256, _input: &[u8], _mode: ComputeMode) -> H256 {
+				H256::zero()
+			}
+		}
+
+		let vectors = generate_test_vectors_with(&CountingBackend::default(), 4);
+
+		// Valid and work-missing vectors differ only in their work.
+		assert!(vectors[0].expected_valid && is_valid_hash(&vectors[0].work, vectors[0].difficulty));
+		assert!(!vectors[3].expected_valid && !is_valid_hash(&vectors[3].work, vectors[3].difficulty));
+
+		// So a verifier skipping RandomX fails the suite.
+		assert!(verify(&SkipWork, &vectors[0]));
+		assert_ne!(verify(&SkipWork, &vectors[3]), vectors[3].expected_valid);
+	}
+
+	#[test]
+	#[cfg(feature = "randomx-regression")]
+	fn vectors_verify_with_randomx() {
+		let _guard = crate::mock::lock_shared_caches();
+
+		for vector in generate_test_vectors(4) {
+			assert_eq!(recompute(&RandomXBackend, &vector), vector.work);
+			assert_eq!(verify(&RandomXBackend, &vector), vector.expected_valid);
+		}
+	}
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Bounded pool of threads running seal verification, so that verifying during
+//! sync does not saturate every core.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use parking_lot::Mutex;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A verification job panicked before returning its result.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct JobPanicked;
+
+impl fmt::Display for JobPanicked {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Verification job panicked")
+	}
+}
+
+impl std::error::Error for JobPanicked { }
+
+/// Fixed number of threads verification jobs are queued on.
+pub struct VerifyPool {
+	sender: Mutex<Option<mpsc::Sender<Job>>>,
+	workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl VerifyPool {
+	/// Pool of `threads` threads, at least one.
+	pub fn new(threads: usize) -> Self {
+		let (sender, receiver) = mpsc::channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+
+		let workers = (0..threads.max(1)).map(|index| {
+			let receiver = receiver.clone();
+
+			thread::Builder::new()
+				.name(format!("pow-verify-{}", index))
+				.spawn(move || loop {
+					let job = match receiver.lock().recv() {
+						Ok(job) => job,
+						Err(_) => break,
+					};
+					// A panicking job only drops its result, and keeps the worker.
+					let _ = panic::catch_unwind(AssertUnwindSafe(job));
+				})
+				.expect("Spawning verification thread failed")
+		}).collect();
+
+		Self { sender: Mutex::new(Some(sender)), workers: Mutex::new(workers) }
+	}
+
+	/// Number of running threads, zero once closed.
+	pub fn threads(&self) -> usize {
+		self.workers.lock().len()
+	}
+
+	/// Queue `f`, returning a receiver of its result. The receiver errors if
+	/// `f` panicked, or if the pool is closed.
+	pub fn spawn<R, F>(&self, f: F) -> mpsc::Receiver<R> where
+		R: Send + 'static,
+		F: FnOnce() -> R + Send + 'static,
+	{
+		let (result_sender, result) = mpsc::channel();
+		let job: Job = Box::new(move || {
+			let _ = result_sender.send(f());
+		});
+
+		if let Some(sender) = self.sender.lock().as_ref() {
+			// Workers only stop once the sender is dropped.
+			let _ = sender.send(job);
+		}
+
+		result
+	}
+
+	/// Run `f` on the pool, waiting for its result. A panic of `f` is
+	/// returned as an error instead of being resumed on the calling thread.
+	pub fn run<R, F>(&self, f: F) -> Result<R, JobPanicked> where
+		R: Send + 'static,
+		F: FnOnce() -> R + Send + 'static,
+	{
+		self.spawn(f).recv().map_err(|_| JobPanicked)
+	}
+
+	/// Stop the threads once the queued jobs are done, dropping what they
+	/// hold, such as their machines. Jobs queued afterwards are dropped. A
+	/// worker closing its own pool is not waited for.
+	pub fn close(&self) {
+		// Closing the channel stops the workers once the queue is drained.
+		self.sender.lock().take();
+
+		let current = thread::current().id();
+		for worker in self.workers.lock().drain(..) {
+			if worker.thread().id() != current {
+				let _ = worker.join();
+			}
+		}
+	}
+}
+
+impl Drop for VerifyPool {
+	fn drop(&mut self) {
+		self.close();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn jobs_run_on_pool_threads() {
+		let pool = VerifyPool::new(2);
+		assert_eq!(pool.threads(), 2);
+
+		let name = pool.run(|| thread::current().name().map(|name| name.to_string()));
+		assert!(name.unwrap().unwrap().starts_with("pow-verify-"));
+
+		assert!(pool.spawn(|| panic!("Job panics")).recv().is_err());
+		assert_eq!(pool.run(|| -> u32 { panic!("Job panics") }), Err(JobPanicked));
+		assert_eq!(pool.run(|| 1 + 1), Ok(2));
+
+		pool.close();
+		assert_eq!(pool.threads(), 0);
+		assert_eq!(pool.run(|| 1 + 1), Err(JobPanicked));
+	}
+}
@@ -225,7 +225,7 @@ impl<B, I, C, S, Pow, Reorg> BlockImport<B> for WeakSubjectiveBlockImport<B, I,
 			match self.reorg_algorithm.weak_subjective_decide(params) {
 				WeakSubjectiveDecision::BlockReorg => {
 					warn!(
-						target: "kulupu-pow",
+						target: crate::LOG_TARGET,
 						"Weak subjectivity blocked a deep chain reorg. Retracted len: {}, current head total difficulty: {}, reorg total difficulty: {}",
 						retracted_len,
 						best_total_difficulty,
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Author pre-digest layouts, shared by the node and the rewards pallet so
+//! that both read the author of a block the same way.
+
+use codec::{Encode, Decode};
+use sp_core::RuntimeDebug;
+
+/// Version tag of the pre-digest carrying only the author.
+pub const AUTHOR_DIGEST_V1: u8 = 1;
+/// Version tag of the pre-digest carrying the author and the epoch key hash.
+pub const AUTHOR_DIGEST_V2: u8 = 2;
+
+/// Length of the epoch key hash committed by [`AUTHOR_DIGEST_V2`] pre-digests.
+pub const EPOCH_KEY_HASH_LEN: usize = 32;
+
+/// Layout of the author pre-digest, which is consensus critical.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, RuntimeDebug)]
+pub enum PreDigestFormat {
+	/// The encoded author alone, as in all blocks up to algorithm V2.
+	Raw,
+	/// A version tag, followed by the encoded author and, for
+	/// [`AUTHOR_DIGEST_V2`], the epoch key hash.
+	Versioned,
+}
+
+impl PreDigestFormat {
+	/// Format of pre-digests for children of blocks with a runtime reporting
+	/// the algorithm `identifier`. Versioned pre-digests start with algorithm V3.
+	pub fn for_identifier(identifier: &[u8; 8]) -> Self {
+		if *identifier == crate::ALGORITHM_IDENTIFIER_V3 {
+			PreDigestFormat::Versioned
+		} else {
+			PreDigestFormat::Raw
+		}
+	}
+}
+
+/// Split a pre-digest into the encoded author and the committed epoch key
+/// hash, if any. `None` if the pre-digest is not of `format`.
+pub fn split_pre_digest(
+	format: PreDigestFormat,
+	pre_digest: &[u8],
+) -> Option<(&[u8], Option<&[u8]>)> {
+	match format {
+		PreDigestFormat::Raw => Some((pre_digest, None)),
+		PreDigestFormat::Versioned => match pre_digest.split_first() {
+			Some((&AUTHOR_DIGEST_V1, author)) => Some((author, None)),
+			Some((&AUTHOR_DIGEST_V2, rest)) if rest.len() >= EPOCH_KEY_HASH_LEN => {
+				let (author, key_hash) = rest.split_at(rest.len() - EPOCH_KEY_HASH_LEN);
+				Some((author, Some(key_hash)))
+			},
+			_ => None,
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn format_follows_identifier() {
+		assert_eq!(
+			PreDigestFormat::for_identifier(&crate::ALGORITHM_IDENTIFIER_V2),
+			PreDigestFormat::Raw,
+		);
+		assert_eq!(
+			PreDigestFormat::for_identifier(&crate::ALGORITHM_IDENTIFIER_BLAKE2_TEST),
+			PreDigestFormat::Raw,
+		);
+		assert_eq!(
+			PreDigestFormat::for_identifier(&crate::ALGORITHM_IDENTIFIER_V3),
+			PreDigestFormat::Versioned,
+		);
+	}
+
+	#[test]
+	fn raw_pre_digest_is_taken_whole() {
+		let author = [AUTHOR_DIGEST_V1; 32];
+
+		assert_eq!(split_pre_digest(PreDigestFormat::Raw, &author[..]), Some((&author[..], None)));
+		assert_eq!(split_pre_digest(PreDigestFormat::Versioned, &author[..]), Some((&author[1..], None)));
+	}
+
+	#[test]
+	fn versioned_pre_digest_is_split() {
+		let mut committed = vec![AUTHOR_DIGEST_V2];
+		committed.extend_from_slice(&[1; 32]);
+		committed.extend_from_slice(&[2; 32]);
+
+		assert_eq!(
+			split_pre_digest(PreDigestFormat::Versioned, &committed[..]),
+			Some((&[1; 32][..], Some(&[2; 32][..]))),
+		);
+		assert_eq!(split_pre_digest(PreDigestFormat::Versioned, &committed[..16]), None);
+		assert_eq!(split_pre_digest(PreDigestFormat::Versioned, &[3; 33][..]), None);
+		assert_eq!(split_pre_digest(PreDigestFormat::Versioned, &[][..]), None);
+	}
+}
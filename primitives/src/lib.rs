@@ -20,7 +20,12 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod seal;
+pub mod digest;
+
+use codec::{Encode, Decode};
 use sp_api::decl_runtime_apis;
+use sp_core::RuntimeDebug;
 
 pub type Difficulty = sp_core::U256;
 
@@ -71,9 +76,40 @@ pub const DAYS: u32 = 24 * HOURS;
 
 pub const ALGORITHM_IDENTIFIER_V1: [u8; 8] = *b"randomx1";
 pub const ALGORITHM_IDENTIFIER_V2: [u8; 8] = *b"randomx2";
+pub const ALGORITHM_IDENTIFIER_V3: [u8; 8] = *b"randomx3";
+/// Identifier of test networks replacing RandomX with blake2. Never used on
+/// mainnet.
+pub const ALGORITHM_IDENTIFIER_BLAKE2_TEST: [u8; 8] = *b"blake2t1";
+
+/// Schedule of the blocks whose hash is used as RandomX key.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, RuntimeDebug)]
+pub struct KeyHashParams {
+	/// Blocks between two key blocks.
+	pub period: u64,
+	/// Blocks after a key block before it becomes the key.
+	pub offset: u64,
+}
+
+/// Key block schedule of Kulupu, every ~2.8 days with a delay of 2 hours.
+pub const DEFAULT_KEY_HASH_PARAMS: KeyHashParams = KeyHashParams {
+	period: 4096,
+	offset: 128,
+};
 
 decl_runtime_apis! {
 	pub trait AlgorithmApi {
 		fn identifier() -> [u8; 8];
 	}
+
+	/// Consensus defined key block schedule, for chains deviating from the
+	/// default one.
+	pub trait KeyHashApi {
+		fn key_hash_params() -> KeyHashParams;
+	}
+
+	/// Key hash recorded by the runtime for children of a block, for chains
+	/// whose runtime tracks it. `None` if not recorded.
+	pub trait ExpectedKeyHashApi {
+		fn expected_key_hash() -> Option<sp_core::H256>;
+	}
 }
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// This file is part of Kulupu.
+//
+// Copyright (c) 2020 Wei Tang.
+//
+// Kulupu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kulupu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kulupu. If not, see <http://www.gnu.org/licenses/>.
+
+//! Signature part of the V2 seal, usable in the runtime.
+//!
+//! Only the author signature over the calculation is checked here. Checking
+//! the RandomX work needs the `std` only `kulupu-pow` crate.
+
+use codec::{Encode, Decode};
+use sp_core::{H256, RuntimeDebug, sr25519};
+use sp_io::hashing::blake2_256;
+use sp_std::vec::Vec;
+use crate::Difficulty;
+
+/// Domain separator of the V2 signing payload, from algorithm V3 on.
+pub const SIGNING_DOMAIN_V2: &[u8] = b"kulupu-pow-v2";
+
+/// Domain separation applied to the signed calculation.
+#[derive(Clone, Copy, PartialEq, Eq, RuntimeDebug)]
+pub enum SigningDomain {
+	/// The bare calculation is signed, as in algorithm V2.
+	Legacy,
+	/// The calculation is prefixed with [`SIGNING_DOMAIN_V2`].
+	V2,
+}
+
+#[derive(Clone, PartialEq, Eq, Encode, Decode, RuntimeDebug)]
+pub struct Calculation {
+	pub pre_hash: H256,
+	pub difficulty: Difficulty,
+	pub nonce: H256,
+}
+
+/// Message signed by the author of a seal over `calculation`.
+pub fn signing_message(calculation: &Calculation, domain: SigningDomain) -> [u8; 32] {
+	let mut payload = match domain {
+		SigningDomain::Legacy => Vec::new(),
+		SigningDomain::V2 => SIGNING_DOMAIN_V2.to_vec(),
+	};
+	calculation.encode_to(&mut payload);
+
+	blake2_256(&payload[..])
+}
+
+/// Check the author signature of a seal over `calculation`.
+pub fn verify_signature(
+	calculation: &Calculation,
+	signature: &sr25519::Signature,
+	public: &sr25519::Public,
+	domain: SigningDomain,
+) -> bool {
+	let hash = signing_message(calculation, domain);
+	sp_io::crypto::sr25519_verify(signature, &hash[..], public)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Pair;
+
+	#[test]
+	fn signature_is_checked_without_randomx() {
+		let pair = sr25519::Pair::from_seed(&[1; 32]);
+		let calculation = Calculation {
+			pre_hash: H256::repeat_byte(2),
+			difficulty: Difficulty::from(1),
+			nonce: H256::repeat_byte(3),
+		};
+		let signature = pair.sign(&signing_message(&calculation, SigningDomain::V2)[..]);
+
+		assert!(verify_signature(&calculation, &signature, &pair.public(), SigningDomain::V2));
+		assert!(!verify_signature(&calculation, &signature, &pair.public(), SigningDomain::Legacy));
+
+		let other = Calculation { nonce: H256::repeat_byte(4), ..calculation };
+		assert!(!verify_signature(&other, &signature, &pair.public(), SigningDomain::V2));
+	}
+}
@@ -114,7 +114,7 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
 	spec_name: create_runtime_str!("kulupu"),
 	impl_name: create_runtime_str!("kulupu"),
 	authoring_version: 5,
-	spec_version: 12,
+	spec_version: 14,
 	impl_version: 0,
 	apis: RUNTIME_API_VERSIONS,
 	transaction_version: 6,
@@ -129,6 +129,10 @@ pub fn native_version() -> NativeVersion {
 	}
 }
 
+/// Algorithm identifier reported to the node. It also decides the layout of the
+/// author pre-digest, see [`kulupu_primitives::digest::PreDigestFormat`].
+pub const ALGORITHM_IDENTIFIER: [u8; 8] = kulupu_primitives::ALGORITHM_IDENTIFIER_V2;
+
 const AVERAGE_ON_INITIALIZE_WEIGHT: Perbill = Perbill::from_percent(10);
 parameter_types! {
 	pub const BlockHashCount: BlockNumber = 250;
@@ -365,6 +369,8 @@ impl rewards::GenerateRewardLocks<Runtime> for GenerateRewardLocks {
 
 parameter_types! {
 	pub DonationDestination: AccountId = Treasury::account_id();
+	pub PreDigestFormat: kulupu_primitives::digest::PreDigestFormat =
+		kulupu_primitives::digest::PreDigestFormat::for_identifier(&ALGORITHM_IDENTIFIER);
 }
 
 impl rewards::Trait for Runtime {
@@ -372,6 +378,7 @@ impl rewards::Trait for Runtime {
 	type Currency = Balances;
 	type DonationDestination = DonationDestination;
 	type GenerateRewardLocks = GenerateRewardLocks;
+	type PreDigestFormat = PreDigestFormat;
 	type WeightInfo = weights::rewards::WeightInfo<Runtime>;
 }
 
@@ -944,7 +951,19 @@ impl_runtime_apis! {
 
 	impl kulupu_primitives::AlgorithmApi<Block> for Runtime {
 		fn identifier() -> [u8; 8] {
-			kulupu_primitives::ALGORITHM_IDENTIFIER_V2
+			ALGORITHM_IDENTIFIER
+		}
+	}
+
+	impl kulupu_primitives::KeyHashApi<Block> for Runtime {
+		fn key_hash_params() -> kulupu_primitives::KeyHashParams {
+			kulupu_primitives::DEFAULT_KEY_HASH_PARAMS
+		}
+	}
+
+	impl kulupu_primitives::ExpectedKeyHashApi<Block> for Runtime {
+		fn expected_key_hash() -> Option<sp_core::H256> {
+			None
 		}
 	}
 
@@ -102,6 +102,9 @@ pub struct Cli {
 	pub check_inherents_after: Option<u32>,
 	#[structopt(long)]
 	pub randomx_flags: Vec<RandomxFlag>,
+	/// Pin mining threads to cores: `auto`, `none` or a comma separated core list.
+	#[structopt(long)]
+	pub mining_affinity: Option<kulupu_pow::affinity::WorkerAffinity>,
 }
 
 #[derive(Debug, StructOpt)]
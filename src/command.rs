@@ -90,6 +90,7 @@ pub fn run() -> sc_cli::Result<()> {
 		randomx_config.secure = true;
 	}
 	let _ = kulupu_pow::compute::set_global_config(randomx_config);
+	let _ = kulupu_pow::compute::configure(kulupu_pow::compute::CacheSettings::default());
 
 	match &cli.subcommand {
 		Some(Subcommand::BuildSpec(cmd)) => {
@@ -254,6 +255,7 @@ pub fn run() -> sc_cli::Result<()> {
 							cli.author.as_ref().map(|s| s.as_str()),
 							cli.threads.unwrap_or(1),
 							cli.round.unwrap_or(DEFAULT_ROUND),
+							cli.mining_affinity.clone().unwrap_or_default(),
 							cli.check_inherents_after.unwrap_or(DEFAULT_CHECK_INHERENTS_AFTER),
 							!cli.no_donate,
 							!cli.disable_weak_subjectivity,
@@ -22,7 +22,6 @@ use std::sync::Arc;
 use std::str::FromStr;
 use std::time::Duration;
 use std::thread;
-use parking_lot::Mutex;
 use codec::Encode;
 use sp_runtime::{Perbill, generic::BlockId, traits::Bounded};
 use sp_core::{H256, crypto::{UncheckedFrom, Ss58Codec, Ss58AddressFormat}};
@@ -175,10 +174,17 @@ pub fn new_full(
 	author: Option<&str>,
 	threads: usize,
 	round: u32,
+	affinity: kulupu_pow::affinity::WorkerAffinity,
 	check_inherents_after: u32,
 	donate: bool,
 	enable_weak_subjectivity: bool,
 ) -> Result<TaskManager, ServiceError> {
+	if !kulupu_pow::compute::library_matches_reference() {
+		return Err(ServiceError::Other(
+			"RandomX library does not reproduce its reference hash, refusing to validate blocks".into()
+		))
+	}
+
 	let sc_service::PartialComponents {
 		client, backend, mut task_manager, import_queue, keystore_container,
 		select_chain, transaction_pool, inherent_data_providers,
@@ -238,9 +244,9 @@ pub fn new_full(
 
 	if role.is_authority() {
 		let author = decode_author(author);
-		let algorithm = kulupu_pow::RandomXAlgorithm::new(
-			client.clone(),
-		);
+		let algorithm = kulupu_pow::RandomXAlgorithm::builder(client.clone())
+			.author(author.clone())
+			.build();
 
 		let proposer = sc_basic_authorship::ProposerFactory::new(
 			task_manager.spawn_handle(),
@@ -253,7 +259,7 @@ pub fn new_full(
 			Box::new(pow_block_import.clone()),
 			client.clone(),
 			select_chain.clone(),
-			algorithm,
+			algorithm.clone(),
 			proposer,
 			network.clone(),
 			author.clone().map(|a| a.encode()),
@@ -264,27 +270,26 @@ pub fn new_full(
 		);
 		task_manager.spawn_essential_handle().spawn_blocking("pow", worker_task);
 
-		let stats = Arc::new(Mutex::new(kulupu_pow::Stats::new()));
-
-		for _ in 0..threads {
+		for core in affinity.assign(threads) {
 			if let Some(keystore) = keystore_container.local_keystore() {
 				let worker = worker.clone();
-				let client = client.clone();
-				let stats = stats.clone();
+				let algorithm = algorithm.clone();
 
 				thread::spawn(move || {
+					if let Some(core) = core {
+						kulupu_pow::affinity::pin_current_thread(core);
+					}
+
 					loop {
 						let metadata = worker.lock().metadata();
 						if let Some(metadata) = metadata {
-							match kulupu_pow::mine(
-								client.as_ref(),
+							match algorithm.mine(
 								&keystore,
 								&BlockId::Hash(metadata.best_hash),
 								&metadata.pre_hash,
 								metadata.pre_runtime.as_ref().map(|v| &v[..]),
 								metadata.difficulty,
 								round,
-								&stats
 							) {
 								Ok(Some(seal)) => {
 									let mut worker = worker.lock();
@@ -294,6 +299,9 @@ pub fn new_full(
 									}
 								},
 								Ok(None) => (),
+								Err(kulupu_pow::MineError::Suspended) => {
+									thread::sleep(Duration::new(1, 0));
+								},
 								Err(err) => {
 									warn!("Mining failed: {:?}", err);
 								},